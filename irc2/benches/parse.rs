@@ -0,0 +1,57 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use irc2::{parse_line, MessageRef};
+
+const MOTD: &str = include_str!("../testdata/freenode_motd.txt");
+
+const CHANNEL: &[&str] = &[
+    "@time=2021-01-01T12:00:00.000Z;account=fritschy;msgid=a\\sb :fritschy!~fritschy@user/fritschy PRIVMSG #zebot-test :moep, see https://example.com/foo?bar=baz",
+    ":foo!~foo@2001:db8::1 JOIN #zebot-test",
+    ":bar!~bar@192.168.0.1 PRIVMSG #zebot-test :\x01ACTION waves\x01",
+    ":irc.example.com 353 ZeBot = #zebot-test :ZeBot @fritschy +foo bar baz qux quux corge grault garply waldo fred plugh",
+    "PING :irc.example.com",
+];
+
+fn bench(c: &mut Criterion, name: &str, lines: &[&str]) {
+    let bytes = lines.iter().map(|l| l.len() as u64).sum();
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(bytes));
+
+    group.bench_function("Message", |b| {
+        b.iter(|| {
+            for l in lines {
+                black_box(parse_line(black_box(l.as_bytes())).unwrap());
+            }
+        })
+    });
+
+    group.bench_function("MessageRef", |b| {
+        b.iter(|| {
+            for l in lines {
+                black_box(MessageRef::parse(black_box(l)).unwrap());
+            }
+        })
+    });
+
+    group.bench_function("MessageRef::to_message", |b| {
+        b.iter(|| {
+            for l in lines {
+                black_box(MessageRef::parse(black_box(l)).unwrap().to_message());
+            }
+        })
+    });
+
+    group.finish();
+}
+
+fn freenode_motd(c: &mut Criterion) {
+    let lines = MOTD.lines().collect::<Vec<_>>();
+    bench(c, "freenode_motd", &lines);
+}
+
+fn channel_traffic(c: &mut Criterion) {
+    bench(c, "channel_traffic", CHANNEL);
+}
+
+criterion_group!(benches, freenode_motd, channel_traffic);
+criterion_main!(benches);
@@ -0,0 +1,303 @@
+use crate::command::CommandCode;
+use crate::mode::ModeChange;
+use crate::*;
+
+// Typed constructors for outgoing messages. Their arguments are checked here already, so a
+// handler gets an error instead of a line that is cut short, split in two or rejected by the
+// server.
+
+// A single middle param, e.g. a nick, a channel or a key
+fn word(name: &'static str, v: &str) -> Result<String, EncodeError> {
+    if v.is_empty() || v.starts_with(':') || v.contains(['\0', '\r', '\n', ' ']) {
+        Err(EncodeError::InvalidArgument(name, v.to_string()))
+    } else {
+        Ok(v.to_string())
+    }
+}
+
+// A single nick or channel, commas would turn it into a list
+fn single(name: &'static str, v: &str) -> Result<String, EncodeError> {
+    if v.contains(',') {
+        Err(EncodeError::InvalidArgument(name, v.to_string()))
+    } else {
+        word(name, v)
+    }
+}
+
+// The trailing param, free text
+fn text(name: &'static str, v: &str) -> Result<String, EncodeError> {
+    if v.contains(['\0', '\r', '\n']) {
+        Err(EncodeError::InvalidArgument(name, v.to_string()))
+    } else {
+        Ok(v.to_string())
+    }
+}
+
+fn non_empty_text(name: &'static str, v: &str) -> Result<String, EncodeError> {
+    if v.is_empty() {
+        Err(EncodeError::InvalidArgument(name, v.to_string()))
+    } else {
+        text(name, v)
+    }
+}
+
+fn msg(command: CommandCode, params: Vec<String>) -> Result<Message, EncodeError> {
+    Ok(Message::new(command, params))
+}
+
+fn monitor(op: &str, nicks: &[&str]) -> Result<Message, EncodeError> {
+    let nicks = nicks.iter().map(|n| single("nick", n)).collect::<Result<Vec<_>, _>>()?;
+    if nicks.is_empty() {
+        return Err(EncodeError::InvalidArgument("nick", String::new()));
+    }
+    msg(CommandCode::Monitor, vec![op.to_string(), nicks.join(",")])
+}
+
+impl Message {
+    // rfc2812.txt:532
+    pub fn pass(password: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::Pass, vec![non_empty_text("password", password)?])
+    }
+
+    // rfc2812.txt:550
+    pub fn nick(nick: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::Nick, vec![single("nick", nick)?])
+    }
+
+    // rfc2812.txt:583, the mode is always 0 and the unused param '*'
+    pub fn user(user: &str, realname: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::User, vec![word("user", user)?, "0".to_string(), "*".to_string(), non_empty_text("realname", realname)?])
+    }
+
+    // rfc2812.txt:758
+    pub fn quit(reason: Option<&str>) -> Result<Message, EncodeError> {
+        msg(CommandCode::Quit, reason.map(|r| text("reason", r)).transpose()?.into_iter().collect())
+    }
+
+    pub fn ping(token: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::Ping, vec![non_empty_text("token", token)?])
+    }
+
+    /// Answer a PING, some servers want their name repeated as the second param
+    pub fn pong(token: &str, server: Option<&str>) -> Result<Message, EncodeError> {
+        let mut params = vec![word("token", token)?];
+        params.extend(server.map(|s| non_empty_text("server", s)).transpose()?);
+        msg(CommandCode::Pong, params)
+    }
+
+    pub fn privmsg(target: &str, message: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::PrivMsg, vec![word("target", target)?, non_empty_text("message", message)?])
+    }
+
+    pub fn notice(target: &str, message: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::Notice, vec![word("target", target)?, non_empty_text("message", message)?])
+    }
+
+    /// Join a single channel, with its key if it has one
+    pub fn join(channel: &str, key: Option<&str>) -> Result<Message, EncodeError> {
+        Message::join_all(&[(channel, key)])
+    }
+
+    /// Join several channels at once. The channels with keys are sent first, as keys are
+    /// matched to channels by their position (rfc2812.txt:855).
+    pub fn join_all(channels: &[(&str, Option<&str>)]) -> Result<Message, EncodeError> {
+        if channels.is_empty() {
+            return Err(EncodeError::InvalidArgument("channel", String::new()));
+        }
+
+        let (keyed, unkeyed): (Vec<_>, Vec<_>) = channels.iter().partition(|(_, k)| k.is_some());
+        let mut chans = Vec::with_capacity(channels.len());
+        let mut keys = Vec::with_capacity(keyed.len());
+        for (c, k) in keyed.iter().chain(unkeyed.iter()) {
+            // "JOIN 0" leaves all channels
+            if *c == "0" {
+                return Err(EncodeError::InvalidArgument("channel", c.to_string()));
+            }
+            chans.push(single("channel", c)?);
+            if let Some(k) = k {
+                keys.push(single("key", k)?);
+            }
+        }
+
+        let mut params = vec![chans.join(",")];
+        if !keys.is_empty() {
+            params.push(keys.join(","));
+        }
+        msg(CommandCode::Join, params)
+    }
+
+    pub fn part(channel: &str, reason: Option<&str>) -> Result<Message, EncodeError> {
+        let mut params = vec![single("channel", channel)?];
+        params.extend(reason.map(|r| text("reason", r)).transpose()?);
+        msg(CommandCode::Part, params)
+    }
+
+    pub fn kick(channel: &str, nick: &str, reason: Option<&str>) -> Result<Message, EncodeError> {
+        let mut params = vec![single("channel", channel)?, single("nick", nick)?];
+        params.extend(reason.map(|r| text("reason", r)).transpose()?);
+        msg(CommandCode::Kick, params)
+    }
+
+    /// Change modes, e.g. `+ov-b nick1 nick2 *!*@bad`. Without changes this queries the
+    /// modes of the target.
+    pub fn mode(target: &str, changes: &[ModeChange]) -> Result<Message, EncodeError> {
+        let mut params = vec![single("target", target)?];
+        if !changes.is_empty() {
+            let mut modes = String::with_capacity(changes.len() + 2);
+            let mut set = None;
+            for c in changes {
+                if set != Some(c.set) {
+                    modes.push(if c.set { '+' } else { '-' });
+                    set = Some(c.set);
+                }
+                modes.push(c.mode.mode());
+            }
+            params.push(word("modes", &modes)?);
+            for p in changes.iter().filter_map(|c| c.mode.param()) {
+                params.push(word("mode parameter", p)?);
+            }
+        }
+        msg(CommandCode::Mode, params)
+    }
+
+    /// Query a list mode of a channel, e.g. the ban list for 'b'
+    pub fn list_mode(channel: &str, mode: char) -> Result<Message, EncodeError> {
+        msg(CommandCode::Mode, vec![single("channel", channel)?, word("mode", &format!("+{}", mode))?])
+    }
+
+    /// Set the topic, an empty topic clears it, `None` queries it
+    pub fn topic(channel: &str, topic: Option<&str>) -> Result<Message, EncodeError> {
+        let mut params = vec![single("channel", channel)?];
+        params.extend(topic.map(|t| text("topic", t)).transpose()?);
+        msg(CommandCode::Topic, params)
+    }
+
+    pub fn invite(nick: &str, channel: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::Invite, vec![single("nick", nick)?, single("channel", channel)?])
+    }
+
+    pub fn who(mask: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::Who, vec![word("mask", mask)?])
+    }
+
+    pub fn whois(nick: &str) -> Result<Message, EncodeError> {
+        msg(CommandCode::WhoIs, vec![word("nick", nick)?])
+    }
+
+    /// Mark us as away with the given message, `None` marks us as back
+    pub fn away(message: Option<&str>) -> Result<Message, EncodeError> {
+        msg(CommandCode::Away, message.map(|m| non_empty_text("message", m)).transpose()?.into_iter().collect())
+    }
+
+    /// CAP LS, with the version of the CAP protocol we support, e.g. "302"
+    pub fn cap_ls(version: Option<&str>) -> Result<Message, EncodeError> {
+        let mut params = vec!["LS".to_string()];
+        params.extend(version.map(|v| word("version", v)).transpose()?);
+        msg(CommandCode::Cap, params)
+    }
+
+    /// CAP REQ, capabilities prefixed with '-' are disabled
+    pub fn cap_req(caps: &[&str]) -> Result<Message, EncodeError> {
+        let caps = caps.iter().map(|c| word("capability", c)).collect::<Result<Vec<_>, _>>()?;
+        if caps.is_empty() {
+            return Err(EncodeError::InvalidArgument("capability", String::new()));
+        }
+        msg(CommandCode::Cap, vec!["REQ".to_string(), caps.join(" ")])
+    }
+
+    pub fn cap_end() -> Result<Message, EncodeError> {
+        msg(CommandCode::Cap, vec!["END".to_string()])
+    }
+
+    /// Get notified when these nicks come online or go offline, see
+    /// https://ircv3.net/specs/extensions/monitor
+    pub fn monitor_add(nicks: &[&str]) -> Result<Message, EncodeError> {
+        monitor("+", nicks)
+    }
+
+    pub fn monitor_remove(nicks: &[&str]) -> Result<Message, EncodeError> {
+        monitor("-", nicks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mode::Mode;
+
+    fn line(m: Result<Message, EncodeError>) -> String {
+        m.unwrap().encode().unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(line(Message::privmsg("#zebot-test", "moep moep")), "PRIVMSG #zebot-test :moep moep\r\n");
+        assert_eq!(line(Message::notice("fritschy", ":-)")), "NOTICE fritschy ::-)\r\n");
+        assert_eq!(line(Message::user("zebot", "The Bot")), "USER zebot 0 * :The Bot\r\n");
+        assert_eq!(line(Message::nick("ZeBot")), "NICK ZeBot\r\n");
+        assert_eq!(line(Message::quit(None)), "QUIT\r\n");
+        assert_eq!(line(Message::quit(Some("bye bye"))), "QUIT :bye bye\r\n");
+        assert_eq!(line(Message::pong("irc.example.com", None)), "PONG irc.example.com\r\n");
+        assert_eq!(line(Message::pong("irc.example.com", Some("irc.example.com"))), "PONG irc.example.com irc.example.com\r\n");
+        assert_eq!(line(Message::part("#zebot-test", Some("cya"))), "PART #zebot-test cya\r\n");
+        assert_eq!(line(Message::kick("#zebot-test", "foo", Some("no spam"))), "KICK #zebot-test foo :no spam\r\n");
+        assert_eq!(line(Message::topic("#zebot-test", Some(""))), "TOPIC #zebot-test :\r\n");
+        assert_eq!(line(Message::topic("#zebot-test", None)), "TOPIC #zebot-test\r\n");
+        assert_eq!(line(Message::invite("foo", "#zebot-test")), "INVITE foo #zebot-test\r\n");
+        assert_eq!(line(Message::who("#zebot-test")), "WHO #zebot-test\r\n");
+        assert_eq!(line(Message::away(None)), "AWAY\r\n");
+        assert_eq!(line(Message::cap_ls(Some("302"))), "CAP LS 302\r\n");
+        assert_eq!(line(Message::cap_req(&["sasl", "-multi-prefix"])), "CAP REQ :sasl -multi-prefix\r\n");
+        assert_eq!(line(Message::cap_end()), "CAP END\r\n");
+        assert_eq!(line(Message::monitor_add(&["ZeBot", "fritschy"])), "MONITOR + ZeBot,fritschy\r\n");
+        assert_eq!(line(Message::monitor_remove(&["ZeBot"])), "MONITOR - ZeBot\r\n");
+    }
+
+    #[test]
+    fn join() {
+        assert_eq!(line(Message::join("#zebot-test", None)), "JOIN #zebot-test\r\n");
+        assert_eq!(line(Message::join("#zebot-test", Some("secret"))), "JOIN #zebot-test secret\r\n");
+        assert_eq!(
+            line(Message::join_all(&[("#a", None), ("#b", Some("kb")), ("#c", None), ("#d", Some("kd"))])),
+            "JOIN #b,#d,#a,#c kb,kd\r\n"
+        );
+        assert!(Message::join_all(&[]).is_err());
+        assert!(Message::join("#a,#b", None).is_err());
+        assert!(Message::join("0", None).is_err());
+        assert!(Message::join("#a", Some("k,x")).is_err());
+    }
+
+    #[test]
+    fn mode() {
+        let changes = [
+            ModeChange { set: true, mode: Mode::Prefix('o', "nick1".to_string()) },
+            ModeChange { set: true, mode: Mode::Prefix('v', "nick2".to_string()) },
+            ModeChange { set: false, mode: Mode::List('b', "*!*@bad".to_string()) },
+            ModeChange { set: false, mode: Mode::Param('l', None) },
+            ModeChange { set: true, mode: Mode::Flag('m') },
+        ];
+        assert_eq!(line(Message::mode("#zebot-test", &changes)), "MODE #zebot-test +ov-bl+m nick1 nick2 *!*@bad\r\n");
+        assert_eq!(line(Message::mode("#zebot-test", &[])), "MODE #zebot-test\r\n");
+        assert_eq!(line(Message::list_mode("#zebot-test", 'b')), "MODE #zebot-test +b\r\n");
+
+        let bad = [ModeChange { set: true, mode: Mode::List('b', "a b".to_string()) }];
+        assert_eq!(Message::mode("#zebot-test", &bad), Err(EncodeError::InvalidArgument("mode parameter", "a b".to_string())));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            Message::privmsg("#zebot-test", "moep\r\nQUIT"),
+            Err(EncodeError::InvalidArgument("message", "moep\r\nQUIT".to_string()))
+        );
+        assert!(Message::privmsg("#zebot-test", "").is_err());
+        assert!(Message::privmsg("#zebot test", "moep").is_err());
+        assert!(Message::privmsg(":foo", "moep").is_err());
+        assert!(Message::privmsg("", "moep").is_err());
+        assert!(Message::nick("foo\0").is_err());
+        assert!(Message::nick("a,b").is_err());
+        assert!(Message::kick("#zebot-test", "foo bar", None).is_err());
+        assert!(Message::part("#zebot-test", Some("a\nb")).is_err());
+        assert!(Message::cap_req(&[]).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use tracing::{info, warn};
+
+use crate::command::{CommandCode, Numeric};
+use crate::Message;
+
+// Keep CAP REQ lines well below 512 bytes, including the prefix the server adds to its ACK
+const MAX_REQ_LEN: usize = 400;
+
+/// IRCv3 capability negotiation, see https://ircv3.net/specs/extensions/capability-negotiation
+///
+/// Start with `start()` before sending NICK and USER, then feed every CAP message to
+/// `update()` and send what it returns. Registration is held off by the server until we
+/// send CAP END, which happens once everything we asked for was ACKed or NAKed.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    wanted: BTreeSet<String>,
+    /// Offered by the server, with their values, e.g. "sasl" -> "PLAIN,EXTERNAL"
+    available: BTreeMap<String, Option<String>>,
+    enabled: BTreeSet<String>,
+    requested: BTreeSet<String>,
+    /// Between CAP LS and CAP END
+    negotiating: bool,
+    /// The server finished listing its capabilities
+    listed: bool,
+    /// Things that need to happen before CAP END, e.g. SASL
+    holds: usize,
+}
+
+impl Capabilities {
+    pub fn new() -> Self {
+        Capabilities::default()
+    }
+
+    /// Ask for a capability if the server offers it, now or later with CAP NEW
+    pub fn want(&mut self, cap: &str) {
+        self.wanted.insert(cap.to_string());
+    }
+
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    pub fn enabled(&self) -> impl Iterator<Item = &str> {
+        self.enabled.iter().map(String::as_str)
+    }
+
+    pub fn is_available(&self, cap: &str) -> bool {
+        self.available.contains_key(cap)
+    }
+
+    /// The value the server announced for a capability, e.g. the mechanisms for "sasl"
+    pub fn value(&self, cap: &str) -> Option<&str> {
+        self.available.get(cap).and_then(|v| v.as_deref())
+    }
+
+    pub fn is_negotiating(&self) -> bool {
+        self.negotiating
+    }
+
+    /// Whether the server listed its capabilities and answered everything we asked for
+    pub fn is_settled(&self) -> bool {
+        self.listed && self.requested.is_empty()
+    }
+
+    /// Hold off CAP END until `release()`, e.g. for SASL which needs to happen in between
+    pub fn hold(&mut self) {
+        self.holds += 1;
+    }
+
+    /// Returns CAP END if this was the last hold and all requests were answered
+    pub fn release(&mut self) -> Option<Message> {
+        self.holds = self.holds.saturating_sub(1);
+        self.end()
+    }
+
+    /// Start negotiating, forgetting what a previous connection negotiated. Holds are kept.
+    pub fn start(&mut self) -> Message {
+        self.available.clear();
+        self.enabled.clear();
+        self.requested.clear();
+        self.negotiating = true;
+        self.listed = false;
+        Message::cap_ls(Some("302")).unwrap()
+    }
+
+    /// Handle a CAP message, returns the messages to send in response
+    pub fn update(&mut self, msg: &Message) -> Vec<Message> {
+        match msg.command {
+            CommandCode::Cap => (),
+            // The server does not know CAP or registration went through without it
+            CommandCode::Numeric(Numeric::RplWelcome) => {
+                self.negotiating = false;
+                return Vec::new();
+            }
+            _ => return Vec::new(),
+        }
+
+        // <nick> <subcommand> [*] :<caps>
+        let (sub, more, caps) = match &msg.params[..] {
+            [_, sub, star, caps] if star == "*" => (sub, true, caps),
+            [_, sub, caps] => (sub, false, caps),
+            _ => {
+                warn!("Unexpected CAP message {}", msg);
+                return Vec::new();
+            }
+        };
+        let caps = caps.split_ascii_whitespace();
+
+        match sub.to_ascii_uppercase().as_str() {
+            sub @ ("LS" | "NEW") => {
+                let mut offered = Vec::new();
+                for cap in caps {
+                    let (name, value) = match cap.split_once('=') {
+                        Some((n, v)) => (n, Some(v.to_string())),
+                        None => (cap, None),
+                    };
+                    self.available.insert(name.to_string(), value);
+                    offered.push(name.to_string());
+                }
+
+                if more {
+                    return Vec::new();
+                }
+                self.listed = true;
+
+                // Only ask for new ones with NEW, not again for those that were rejected
+                let offered = if sub == "LS" {
+                    self.available.keys().cloned().collect()
+                } else {
+                    offered
+                };
+                let mut r = self.request(&offered);
+                if r.is_empty() {
+                    r.extend(self.end());
+                }
+                r
+            }
+
+            "ACK" => {
+                for cap in caps {
+                    if let Some(cap) = cap.strip_prefix('-') {
+                        self.requested.remove(cap);
+                        self.enabled.remove(cap);
+                    } else {
+                        info!("Capability {} enabled", cap);
+                        self.requested.remove(cap);
+                        self.enabled.insert(cap.to_string());
+                    }
+                }
+                self.end().into_iter().collect()
+            }
+
+            "NAK" => {
+                for cap in caps {
+                    warn!("Capability {} was rejected", cap);
+                    self.requested.remove(cap.trim_start_matches('-'));
+                }
+                self.end().into_iter().collect()
+            }
+
+            "DEL" => {
+                for cap in caps {
+                    info!("Capability {} was removed", cap);
+                    self.available.remove(cap);
+                    self.enabled.remove(cap);
+                }
+                Vec::new()
+            }
+
+            _ => Vec::new(),
+        }
+    }
+
+    // REQ what we want of the offered caps, that is not enabled or requested yet
+    fn request(&mut self, offered: &[String]) -> Vec<Message> {
+        let caps = self
+            .wanted
+            .iter()
+            .filter(|c| offered.contains(*c) && !self.enabled.contains(*c) && !self.requested.contains(*c))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut r = Vec::new();
+        let mut line: Vec<&str> = Vec::new();
+        let mut len = 0;
+        for cap in &caps {
+            if len + cap.len() + 1 > MAX_REQ_LEN && !line.is_empty() {
+                r.extend(Message::cap_req(&line));
+                line.clear();
+                len = 0;
+            }
+            line.push(cap);
+            len += cap.len() + 1;
+        }
+        if !line.is_empty() {
+            r.extend(Message::cap_req(&line));
+        }
+
+        self.requested.extend(caps);
+        r
+    }
+
+    // CAP END once all requests were answered and nothing holds it, only during registration
+    fn end(&mut self) -> Option<Message> {
+        if self.negotiating && self.requested.is_empty() && self.holds == 0 {
+            self.negotiating = false;
+            Some(Message::cap_end().unwrap())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(caps: &mut Capabilities, line: &str) -> Vec<String> {
+        let (_, msg) = crate::parse(format!("{}\r\n", line).as_bytes()).unwrap();
+        caps.update(&msg).iter().map(|m| m.encode().unwrap().trim_end().to_string()).collect()
+    }
+
+    #[test]
+    fn negotiate() {
+        let mut caps = Capabilities::new();
+        caps.want("multi-prefix");
+        caps.want("sasl");
+        caps.want("away-notify");
+        caps.want("not-offered");
+
+        assert_eq!(caps.start().encode().unwrap(), "CAP LS 302\r\n");
+        assert!(feed(&mut caps, ":irc.example.com CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL").is_empty());
+        assert_eq!(
+            feed(&mut caps, ":irc.example.com CAP * LS :away-notify account-tag"),
+            ["CAP REQ :away-notify multi-prefix sasl"]
+        );
+        assert_eq!(caps.value("sasl"), Some("PLAIN,EXTERNAL"));
+        assert!(caps.is_available("account-tag"));
+
+        assert!(feed(&mut caps, ":irc.example.com CAP * ACK :multi-prefix sasl").is_empty());
+        assert_eq!(feed(&mut caps, ":irc.example.com CAP * NAK :away-notify"), ["CAP END"]);
+        assert!(caps.is_enabled("sasl") && caps.is_enabled("multi-prefix"));
+        assert!(!caps.is_enabled("away-notify"));
+        assert!(!caps.is_negotiating());
+
+        // After registration
+        caps.want("chghost");
+        assert_eq!(feed(&mut caps, ":irc.example.com CAP ZeBot NEW :chghost extended-join"), ["CAP REQ chghost"]);
+        assert!(feed(&mut caps, ":irc.example.com CAP ZeBot ACK :chghost").is_empty());
+        assert!(caps.is_enabled("chghost"));
+
+        assert!(feed(&mut caps, ":irc.example.com CAP ZeBot DEL :sasl").is_empty());
+        assert!(!caps.is_enabled("sasl") && !caps.is_available("sasl"));
+        assert_eq!(caps.enabled().collect::<Vec<_>>(), ["chghost", "multi-prefix"]);
+    }
+
+    #[test]
+    fn nothing_wanted() {
+        let mut caps = Capabilities::new();
+        caps.start();
+        assert_eq!(feed(&mut caps, ":irc.example.com CAP * LS :multi-prefix"), ["CAP END"]);
+    }
+
+    #[test]
+    fn no_cap_support() {
+        let mut caps = Capabilities::new();
+        caps.want("sasl");
+        caps.start();
+        assert!(feed(&mut caps, ":irc.example.com 001 ZeBot :Welcome").is_empty());
+        assert!(!caps.is_negotiating());
+    }
+
+    #[test]
+    fn hold() {
+        let mut caps = Capabilities::new();
+        caps.want("sasl");
+        caps.hold();
+        caps.start();
+        assert!(feed(&mut caps, ":irc.example.com CAP * LS * :multi-prefix").is_empty());
+        assert!(!caps.is_settled());
+        assert_eq!(feed(&mut caps, ":irc.example.com CAP * LS :sasl"), ["CAP REQ sasl"]);
+        assert!(!caps.is_settled());
+        assert!(feed(&mut caps, ":irc.example.com CAP * ACK :sasl").is_empty());
+        assert!(caps.is_negotiating() && caps.is_settled());
+        assert_eq!(caps.release().unwrap().encode().unwrap(), "CAP END\r\n");
+        assert!(caps.release().is_none());
+    }
+
+    #[test]
+    fn long_requests() {
+        let mut caps = Capabilities::new();
+        let names = (0..60).map(|i| format!("vendor.example.org/capability-{:02}", i)).collect::<Vec<_>>();
+        for n in &names {
+            caps.want(n);
+        }
+        caps.start();
+        let r = feed(&mut caps, &format!(":irc.example.com CAP * LS :{}", names.join(" ")));
+        assert!(r.len() > 1);
+        assert!(r.iter().all(|l| l.len() < 512));
+        assert_eq!(r.iter().map(|l| l.split(' ').count() - 2).sum::<usize>(), names.len());
+    }
+}
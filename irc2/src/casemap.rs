@@ -0,0 +1,163 @@
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+
+/// How nicknames and channel names are compared, announced by the server via CASEMAPPING
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub enum CaseMapping {
+    Ascii,
+    /// The default, rfc2812.txt:419 - {}|^ are the lower case equivalents of []\~
+    #[default]
+    Rfc1459,
+    /// Like Rfc1459, but without ~ and ^
+    StrictRfc1459,
+    /// Unicode aware, approximated by the Unicode lower case mapping
+    Rfc7613,
+}
+
+impl CaseMapping {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            "rfc7613" => Some(CaseMapping::Rfc7613),
+            _ => None,
+        }
+    }
+
+    pub fn fold_char(&self, c: char) -> char {
+        match (self, c) {
+            (CaseMapping::Rfc1459, '~') => '^',
+            (CaseMapping::Rfc1459, '[') | (CaseMapping::StrictRfc1459, '[') => '{',
+            (CaseMapping::Rfc1459, ']') | (CaseMapping::StrictRfc1459, ']') => '}',
+            (CaseMapping::Rfc1459, '\\') | (CaseMapping::StrictRfc1459, '\\') => '|',
+            // Multi-char lower case mappings are rare enough in nicks to not care
+            (CaseMapping::Rfc7613, c) => c.to_lowercase().next().unwrap_or(c),
+            (_, c) => c.to_ascii_lowercase(),
+        }
+    }
+
+    pub fn fold(&self, s: &str) -> String {
+        s.chars().map(|c| self.fold_char(c)).collect()
+    }
+
+    pub fn eq(&self, a: &str, b: &str) -> bool {
+        a.chars().count() == b.chars().count()
+            && a.chars().zip(b.chars()).all(|(a, b)| self.fold_char(a) == self.fold_char(b))
+    }
+}
+
+macro_rules! case_mapped_name {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        ///
+        /// Comparison and hashing use the case mapping it was created with, while the original
+        /// spelling is kept for display.
+        #[derive(Debug, Clone)]
+        pub struct $name {
+            name: String,
+            folded: String,
+        }
+
+        impl $name {
+            pub fn new(name: &str, casemapping: CaseMapping) -> Self {
+                $name {
+                    name: name.to_string(),
+                    folded: casemapping.fold(name),
+                }
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.name
+            }
+
+            /// The case folded name, i.e. what is used for comparisons
+            pub fn folded(&self) -> &str {
+                &self.folded
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.folded == other.folded
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl Hash for $name {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.folded.hash(state)
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", self.name)
+            }
+        }
+    };
+}
+
+case_mapped_name!(
+    /// A nickname
+    Nick
+);
+
+case_mapped_name!(
+    /// A channel name
+    Channel
+);
+
+/// The target of a message, i.e. where an answer would go to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Channel(Channel),
+    Nick(Nick),
+}
+
+impl Target {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Target::Channel(c) => c.as_str(),
+            Target::Nick(n) => n.as_str(),
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn fold() {
+        assert_eq!(CaseMapping::Ascii.fold("ZeBot[]\\~"), "zebot[]\\~");
+        assert_eq!(CaseMapping::Rfc1459.fold("ZeBot[]\\~"), "zebot{}|^");
+        assert_eq!(CaseMapping::StrictRfc1459.fold("ZeBot[]\\~"), "zebot{}|~");
+        assert_eq!(CaseMapping::Rfc7613.fold("ÄÖÜ"), "äöü");
+        assert!(CaseMapping::Rfc1459.eq("fritschy[m]", "FRITSCHY{M}"));
+        assert!(!CaseMapping::Ascii.eq("fritschy[m]", "FRITSCHY{M}"));
+        assert!(!CaseMapping::Rfc1459.eq("fritschy", "fritschy_"));
+    }
+
+    #[test]
+    fn hashing() {
+        let mut m = HashMap::new();
+        m.insert(Nick::new("Foo[m]", CaseMapping::Rfc1459), 1);
+        assert_eq!(m.get(&Nick::new("foo{M}", CaseMapping::Rfc1459)), Some(&1));
+        assert_eq!(m.get(&Nick::new("foo[M]", CaseMapping::Ascii)), None);
+
+        let c = Channel::new("#ZeBot-Test", CaseMapping::Rfc1459);
+        assert_eq!(c, Channel::new("#zebot-test", CaseMapping::Rfc1459));
+        assert_eq!(c.to_string(), "#ZeBot-Test");
+        assert_eq!(c.folded(), "#zebot-test");
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use tracing::warn;
+
+use crate::casemap::{Channel, Nick};
+use crate::command::{CommandCode, Numeric};
+use crate::isupport::ISupport;
+use crate::mode::Mode;
+use crate::Message;
+
+/// What we know about a channel we are in
+#[derive(Debug, Clone)]
+pub struct ChannelState {
+    pub name: Channel,
+    /// Members with their membership modes, e.g. "ov"
+    pub members: HashMap<Nick, String>,
+    /// Address lists by mode, e.g. the ban list for 'b'
+    pub lists: BTreeMap<char, BTreeSet<String>>,
+    /// Channel settings, with their parameter if any
+    pub modes: BTreeMap<char, Option<String>>,
+}
+
+impl ChannelState {
+    fn new(name: Channel) -> Self {
+        ChannelState {
+            name,
+            members: HashMap::new(),
+            lists: BTreeMap::new(),
+            modes: BTreeMap::new(),
+        }
+    }
+
+    pub fn has_mode(&self, nick: &Nick, mode: char) -> bool {
+        self.members.get(nick).map(|m| m.contains(mode)).unwrap_or(false)
+    }
+
+    pub fn is_op(&self, nick: &Nick) -> bool {
+        self.has_mode(nick, 'o')
+    }
+
+    pub fn is_voiced(&self, nick: &Nick) -> bool {
+        self.has_mode(nick, 'v')
+    }
+
+    pub fn bans(&self) -> impl Iterator<Item = &str> {
+        self.lists.get(&'b').into_iter().flatten().map(String::as_str)
+    }
+
+    fn apply_mode(&mut self, set: bool, mode: Mode, isupport: &ISupport) {
+        match mode {
+            Mode::Prefix(m, nick) => match self.members.get_mut(&isupport.nick(&nick)) {
+                Some(modes) if set => {
+                    if !modes.contains(m) {
+                        modes.push(m);
+                    }
+                }
+                Some(modes) => modes.retain(|x| x != m),
+                None => warn!("Mode {} for {} who is not in {}", m, nick, self.name),
+            },
+            Mode::List(m, mask) => {
+                let list = self.lists.entry(m).or_default();
+                if set {
+                    list.insert(mask);
+                } else {
+                    list.remove(&mask);
+                }
+            }
+            Mode::Param(m, p) if set => {
+                self.modes.insert(m, p);
+            }
+            Mode::Flag(m) if set => {
+                self.modes.insert(m, None);
+            }
+            Mode::Param(m, _) | Mode::Flag(m) => {
+                self.modes.remove(&m);
+            }
+        }
+    }
+}
+
+/// Tracks the channels we are in, their members, membership modes, lists and settings.
+///
+/// Feed it every message with `update()`, it picks the ones it needs: JOIN, PART, KICK, QUIT,
+/// NICK, MODE, RPL_NAMREPLY, RPL_BANLIST and RPL_CHANNELMODEIS.
+#[derive(Debug, Clone, Default)]
+pub struct Channels {
+    channels: HashMap<Channel, ChannelState>,
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Channels::default()
+    }
+
+    pub fn get(&self, name: &Channel) -> Option<&ChannelState> {
+        self.channels.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChannelState> {
+        self.channels.values()
+    }
+
+    pub fn clear(&mut self) {
+        self.channels.clear();
+    }
+
+    /// Whether `update()` does anything with messages of this command
+    pub fn tracks(command: &CommandCode) -> bool {
+        matches!(
+            command,
+            CommandCode::Join
+                | CommandCode::Part
+                | CommandCode::Kick
+                | CommandCode::Quit
+                | CommandCode::Nick
+                | CommandCode::Mode
+                | CommandCode::Numeric(Numeric::RplChannelModeIs | Numeric::RplNamReply | Numeric::RplBanList)
+        )
+    }
+
+    /// `me` is our own nick, a NICK change of our own still needs to be passed the old nick
+    pub fn update(&mut self, me: &str, msg: &Message, isupport: &ISupport) {
+        let me = isupport.nick(me);
+        let source = msg.nickname().map(|n| isupport.nick(n.nickname()));
+        let param = |i: usize| msg.params.get(i).map(String::as_str);
+
+        match &msg.command {
+            CommandCode::Join => {
+                if let (Some(nick), Some(chans)) = (source, param(0)) {
+                    for chan in chans.split(',').map(|c| isupport.channel(c)) {
+                        if nick == me {
+                            self.channels.insert(chan.clone(), ChannelState::new(chan.clone()));
+                        }
+                        if let Some(state) = self.channels.get_mut(&chan) {
+                            state.members.insert(nick.clone(), String::new());
+                        }
+                    }
+                }
+            }
+
+            CommandCode::Part => {
+                if let (Some(nick), Some(chans)) = (source, param(0)) {
+                    for chan in chans.split(',') {
+                        self.leave(&isupport.channel(chan), &nick, &me);
+                    }
+                }
+            }
+
+            CommandCode::Kick => {
+                if let (Some(chan), Some(nick)) = (param(0), param(1)) {
+                    self.leave(&isupport.channel(chan), &isupport.nick(nick), &me);
+                }
+            }
+
+            CommandCode::Quit => {
+                if let Some(nick) = source {
+                    for state in self.channels.values_mut() {
+                        state.members.remove(&nick);
+                    }
+                }
+            }
+
+            CommandCode::Nick => {
+                if let (Some(old), Some(new)) = (source, param(0)) {
+                    let new = isupport.nick(new);
+                    for state in self.channels.values_mut() {
+                        if let Some(modes) = state.members.remove(&old) {
+                            state.members.insert(new.clone(), modes);
+                        }
+                    }
+                }
+            }
+
+            CommandCode::Mode | CommandCode::Numeric(Numeric::RplChannelModeIs) => {
+                if let Some((target, changes)) = msg.mode_changes(isupport) {
+                    let state = match self.channels.get_mut(&isupport.channel(target)) {
+                        Some(state) => state,
+                        None => return,
+                    };
+                    match changes {
+                        Ok(changes) => {
+                            for c in changes {
+                                state.apply_mode(c.set, c.mode, isupport);
+                            }
+                        }
+                        Err(e) => warn!("Could not parse modes of {}: {}", msg, e),
+                    }
+                }
+            }
+
+            // <me> <symbol> <channel> :[prefix]<nick>{ [prefix]<nick>}
+            CommandCode::Numeric(Numeric::RplNamReply) => {
+                if let (Some(chan), Some(names)) = (param(2), param(3)) {
+                    if let Some(state) = self.channels.get_mut(&isupport.channel(chan)) {
+                        for name in names.split_ascii_whitespace() {
+                            // There may be several prefixes with multi-prefix
+                            let nick = name.trim_start_matches(|c| isupport.mode_for_prefix(c).is_some());
+                            let modes = name[..name.len() - nick.len()]
+                                .chars()
+                                .filter_map(|c| isupport.mode_for_prefix(c))
+                                .collect();
+                            state.members.insert(isupport.nick(nick), modes);
+                        }
+                    }
+                }
+            }
+
+            // <me> <channel> <mask> [<who> <when>]
+            CommandCode::Numeric(Numeric::RplBanList) => {
+                if let (Some(chan), Some(mask)) = (param(1), param(2)) {
+                    if let Some(state) = self.channels.get_mut(&isupport.channel(chan)) {
+                        state.lists.entry('b').or_default().insert(mask.to_string());
+                    }
+                }
+            }
+
+            _ => (),
+        }
+    }
+
+    fn leave(&mut self, chan: &Channel, nick: &Nick, me: &Nick) {
+        if nick == me {
+            self.channels.remove(chan);
+        } else if let Some(state) = self.channels.get_mut(chan) {
+            state.members.remove(nick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(channels: &mut Channels, isupport: &ISupport, lines: &[&str]) {
+        for l in lines {
+            let (_, msg) = crate::parse(format!("{}\r\n", l).as_bytes()).unwrap();
+            // As it is used, everything else is not looked at
+            if Channels::tracks(&msg.command) {
+                channels.update("ZeBot", &msg, isupport);
+            }
+        }
+    }
+
+    #[test]
+    fn track() {
+        let i = ISupport::default();
+        let mut c = Channels::new();
+        let chan = i.channel("#ZeBot-Test");
+        let nick = |n| i.nick(n);
+
+        feed(&mut c, &i, &[
+            ":foo!~foo@localhost JOIN #other",
+            ":ZeBot!~zebot@localhost JOIN #zebot-test",
+            ":irc.example.com 353 ZeBot = #zebot-test :ZeBot @fritschy +foo @+bar",
+            ":irc.example.com 367 ZeBot #zebot-test *!*@bad fritschy 1609502400",
+            ":baz!~baz@localhost JOIN :#zebot-test",
+            ":fritschy!~fritschy@localhost MODE #zebot-test +v-o+b baz bar *!*@worse",
+            ":fritschy!~fritschy@localhost MODE #zebot-test +ntk secret",
+        ]);
+
+        assert!(c.get(&i.channel("#other")).is_none());
+        let state = c.get(&chan).unwrap();
+        assert_eq!(state.members.len(), 5);
+        assert!(state.is_op(&nick("Fritschy")));
+        assert!(state.is_voiced(&nick("foo")));
+        assert!(state.is_voiced(&nick("bar")) && !state.is_op(&nick("bar")));
+        assert!(state.is_voiced(&nick("baz")));
+        assert!(!state.is_voiced(&nick("zebot")));
+        assert_eq!(state.bans().collect::<Vec<_>>(), ["*!*@bad", "*!*@worse"]);
+        assert_eq!(state.modes.get(&'k'), Some(&Some("secret".to_string())));
+
+        feed(&mut c, &i, &[
+            ":foo!~foo@localhost NICK :foo_",
+            ":bar!~bar@localhost QUIT :bye",
+            ":fritschy!~fritschy@localhost KICK #zebot-test baz :no",
+            ":fritschy!~fritschy@localhost MODE #zebot-test -b-k *!*@bad *",
+        ]);
+
+        let state = c.get(&chan).unwrap();
+        assert_eq!(state.members.len(), 3);
+        assert!(state.is_voiced(&nick("foo_")));
+        assert_eq!(state.bans().collect::<Vec<_>>(), ["*!*@worse"]);
+        assert!(!state.modes.contains_key(&'k'));
+
+        feed(&mut c, &i, &[":ZeBot!~zebot@localhost PART #zebot-test"]);
+        assert!(c.get(&chan).is_none());
+    }
+}
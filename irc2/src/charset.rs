@@ -0,0 +1,174 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use encoding_rs::{Encoding, UTF_8};
+
+use crate::parser::parsers;
+use crate::CaseMapping;
+
+/// Which legacy charsets to use for lines that are not UTF-8.
+///
+/// Incoming lines are always tried as UTF-8 first. If that fails, they are decoded with the
+/// charset of the channel they target if it has one configured, or with the network charset.
+/// Outgoing lines are encoded with the same charset, lines without a configured charset are
+/// sent as UTF-8.
+///
+/// Charsets are looked up by their WHATWG labels, e.g. 'latin1' or 'cp1252', both of which
+/// result in windows-1252.
+#[derive(Debug, Clone, Default)]
+pub struct Charsets {
+    network: Option<&'static Encoding>,
+    // Keyed by the rfc1459 folded channel name, the server's CASEMAPPING is not known yet
+    // when the charsets are configured.
+    channels: HashMap<String, &'static Encoding>,
+}
+
+/// A charset label that is unknown to encoding_rs
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownCharset(pub String);
+
+impl std::fmt::Display for UnknownCharset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Unknown charset '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCharset {}
+
+fn lookup(label: &str) -> Result<&'static Encoding, UnknownCharset> {
+    Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| UnknownCharset(label.to_string()))
+}
+
+fn fold(channel: &str) -> String {
+    CaseMapping::Rfc1459.fold(channel)
+}
+
+impl Charsets {
+    pub fn new() -> Self {
+        Charsets::default()
+    }
+
+    /// Set the fallback charset for the whole network
+    pub fn set_network(&mut self, label: &str) -> Result<(), UnknownCharset> {
+        self.network = Some(lookup(label)?);
+        Ok(())
+    }
+
+    /// Set the fallback charset for a single channel, this takes precedence over the network
+    pub fn set_channel(&mut self, channel: &str, label: &str) -> Result<(), UnknownCharset> {
+        self.channels.insert(fold(channel), lookup(label)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.network.is_none() && self.channels.is_empty()
+    }
+
+    /// The charset to use for the given line, as it appears on the wire
+    fn for_line(&self, line: &[u8]) -> Option<&'static Encoding> {
+        if !self.channels.is_empty() {
+            if let Ok((_, raw)) = parsers::raw_message(line) {
+                let channel = target(raw.command)
+                    .and_then(|i| raw.params.as_slice().get(i))
+                    .and_then(|p| self.channels.get(&fold(&String::from_utf8_lossy(p))));
+                if channel.is_some() {
+                    return channel.copied();
+                }
+            }
+        }
+        self.network
+    }
+
+    /// Decode a line, falling back to the configured charset if it is not valid UTF-8
+    pub fn decode<'a>(&self, line: &'a [u8]) -> Cow<'a, str> {
+        match std::str::from_utf8(line) {
+            Ok(s) => Cow::Borrowed(s),
+            Err(_) => match self.for_line(line) {
+                Some(enc) => enc.decode_without_bom_handling(line).0,
+                None => String::from_utf8_lossy(line),
+            },
+        }
+    }
+
+    /// Encode a line with the configured charset, characters that cannot be encoded are
+    /// replaced by HTML character references (that is what encoding_rs does).
+    pub fn encode<'a>(&self, line: &'a str) -> Cow<'a, [u8]> {
+        match self.for_line(line.as_bytes()) {
+            Some(enc) if enc != UTF_8 => enc.encode(line).0,
+            _ => Cow::Borrowed(line.as_bytes()),
+        }
+    }
+}
+
+/// The index of the param naming the channel, the text of a message never selects the charset
+fn target(command: &[u8]) -> Option<usize> {
+    const FIRST: [&[u8]; 7] = [b"PRIVMSG", b"NOTICE", b"JOIN", b"PART", b"KICK", b"TOPIC", b"MODE"];
+    match command {
+        // RPL_CHANNELMODEIS, RPL_CREATIONTIME, RPL_TOPIC, RPL_TOPICWHOTIME, RPL_ENDOFNAMES
+        b"324" | b"329" | b"332" | b"333" | b"366" => Some(1),
+        // RPL_NAMREPLY has the channel type in between
+        b"353" => Some(2),
+        _ if FIRST.iter().any(|c| c.eq_ignore_ascii_case(command)) => Some(0),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_first() {
+        let mut cs = Charsets::new();
+        cs.set_network("latin1").unwrap();
+        let line = ":foo!~foo@localhost PRIVMSG #zebot-test :Grüße".as_bytes();
+        assert_eq!(cs.decode(line), ":foo!~foo@localhost PRIVMSG #zebot-test :Grüße");
+        assert!(matches!(cs.decode(line), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn fallback() {
+        let line = b":foo!~foo@localhost PRIVMSG #Zebot-Test :Gr\xfc\xdfe \x80";
+
+        // Nothing configured
+        let mut cs = Charsets::new();
+        assert_eq!(cs.decode(line), ":foo!~foo@localhost PRIVMSG #Zebot-Test :Gr\u{fffd}\u{fffd}e \u{fffd}");
+
+        cs.set_channel("#zebot-test", "cp1252").unwrap();
+        assert_eq!(cs.decode(line), ":foo!~foo@localhost PRIVMSG #Zebot-Test :Grüße €");
+
+        // Other channels use the network charset, if any
+        let other = b":foo!~foo@localhost PRIVMSG #other :Gr\xfc\xdfe";
+        assert_eq!(cs.decode(other), ":foo!~foo@localhost PRIVMSG #other :Gr\u{fffd}\u{fffd}e");
+        cs.set_network("iso-8859-15").unwrap();
+        assert_eq!(cs.decode(other), ":foo!~foo@localhost PRIVMSG #other :Grüße");
+
+        // The channel is not always the first param
+        let topic = b":irc.example.com 332 ZeBot #zebot-test :Gr\x80\xfc\xdfe";
+        assert_eq!(cs.decode(topic), ":irc.example.com 332 ZeBot #zebot-test :Gr€üße");
+
+        let names = b":irc.example.com 353 ZeBot = #zebot-test :\xfcber";
+        assert_eq!(cs.decode(names), ":irc.example.com 353 ZeBot = #zebot-test :über");
+
+        // Channels named in the text do not count
+        let other = b":foo!~foo@localhost PRIVMSG #other :Gr\xfc\xdfe auf #zebot-test \xa4";
+        assert_eq!(cs.decode(other), ":foo!~foo@localhost PRIVMSG #other :Grüße auf #zebot-test €");
+        let kick = b":foo!~foo@localhost KICK #other b\xa4r :#zebot-test";
+        assert_eq!(cs.decode(kick), ":foo!~foo@localhost KICK #other b€r :#zebot-test");
+        let motd = b":irc.example.com 372 ZeBot :- #zebot-test \xa4";
+        assert_eq!(cs.decode(motd), ":irc.example.com 372 ZeBot :- #zebot-test €");
+
+        assert_eq!(lookup("klingon"), Err(UnknownCharset("klingon".to_string())));
+    }
+
+    #[test]
+    fn encode() {
+        let mut cs = Charsets::new();
+        assert_eq!(&*cs.encode("PRIVMSG #zebot-test :Grüße\r\n"), "PRIVMSG #zebot-test :Grüße\r\n".as_bytes());
+
+        cs.set_channel("#zebot-test", "latin1").unwrap();
+        assert_eq!(&*cs.encode("PRIVMSG #zebot-test :Grüße\r\n"), b"PRIVMSG #zebot-test :Gr\xfc\xdfe\r\n");
+        assert_eq!(&*cs.encode("PRIVMSG #zebot-test :\u{263a}\r\n"), b"PRIVMSG #zebot-test :&#9786;\r\n");
+        assert_eq!(&*cs.encode("PRIVMSG #other :Grüße\r\n"), "PRIVMSG #other :Grüße\r\n".as_bytes());
+    }
+}
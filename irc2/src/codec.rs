@@ -0,0 +1,336 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::{error as log_error, warn};
+
+use crate::charset::Charsets;
+use crate::parser::parse_line;
+use crate::Message;
+
+/// 512 bytes for the message itself (rfc2812.txt:220) plus 8191 bytes of IRCv3 tags
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 512 + 8191;
+
+/// Frames lines ending in CRLF or a bare LF, without parsing them.
+///
+/// Lines longer than the maximum line length are dropped and logged, this does not end the
+/// stream. Lines that are not UTF-8 are decoded according to the configured `Charsets`. Use
+/// `MessageRef::parse()` on the lines to look at them without allocating.
+#[derive(Debug)]
+pub struct LineCodec {
+    max_line_length: usize,
+    // Where to continue looking for a LF, everything before it has been searched already
+    next_index: usize,
+    // Whether we are skipping the rest of an overlong line
+    discarding: bool,
+    charsets: Charsets,
+}
+
+impl LineCodec {
+    pub fn new() -> Self {
+        LineCodec::with_max_line_length(DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    pub fn with_max_line_length(max_line_length: usize) -> Self {
+        LineCodec {
+            max_line_length,
+            next_index: 0,
+            discarding: false,
+            charsets: Charsets::default(),
+        }
+    }
+
+    pub fn max_line_length(&self) -> usize {
+        self.max_line_length
+    }
+
+    pub fn charsets(&self) -> &Charsets {
+        &self.charsets
+    }
+
+    pub fn set_charsets(&mut self, charsets: Charsets) {
+        self.charsets = charsets;
+    }
+}
+
+impl Default for LineCodec {
+    fn default() -> Self {
+        LineCodec::new()
+    }
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+impl Decoder for LineCodec {
+    type Item = String;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, std::io::Error> {
+        loop {
+            match buf[self.next_index..].iter().position(|&c| c == b'\n') {
+                Some(pos) => {
+                    let line = buf.split_to(self.next_index + pos + 1);
+                    self.next_index = 0;
+
+                    if self.discarding {
+                        self.discarding = false;
+                        continue;
+                    }
+
+                    let line = trim_line_ending(&line);
+                    if line.is_empty() {
+                        continue;
+                    }
+
+                    if line.len() > self.max_line_length {
+                        log_error!("Skipping line of {} bytes, longer than {}", line.len(), self.max_line_length);
+                        continue;
+                    }
+
+                    return Ok(Some(self.charsets.decode(line).into_owned()));
+                }
+
+                None if buf.len() > self.max_line_length => {
+                    if !self.discarding {
+                        log_error!("Skipping line longer than {} bytes", self.max_line_length);
+                    }
+                    // Drop what we have of it and skip ahead until the next LF
+                    buf.clear();
+                    self.next_index = 0;
+                    self.discarding = true;
+                    return Ok(None);
+                }
+
+                None => {
+                    self.next_index = buf.len();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<String>, std::io::Error> {
+        match self.decode(buf)? {
+            Some(line) => Ok(Some(line)),
+            None => {
+                if !buf.is_empty() && !self.discarding {
+                    warn!("Dropping incomplete line at end of stream '{}'", String::from_utf8_lossy(buf));
+                }
+                buf.clear();
+                self.next_index = 0;
+                self.discarding = false;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Frames lines like `LineCodec` and parses them into messages.
+///
+/// Lines that cannot be parsed are logged and dropped, this does not end the stream. Outgoing
+/// lines are encoded according to the configured `Charsets`.
+#[derive(Debug, Default)]
+pub struct IrcCodec {
+    lines: LineCodec,
+}
+
+impl IrcCodec {
+    pub fn new() -> Self {
+        IrcCodec::default()
+    }
+
+    pub fn with_max_line_length(max_line_length: usize) -> Self {
+        IrcCodec {
+            lines: LineCodec::with_max_line_length(max_line_length),
+        }
+    }
+
+    pub fn max_line_length(&self) -> usize {
+        self.lines.max_line_length()
+    }
+
+    pub fn charsets(&self) -> &Charsets {
+        self.lines.charsets()
+    }
+
+    pub fn set_charsets(&mut self, charsets: Charsets) {
+        self.lines.set_charsets(charsets);
+    }
+}
+
+// Malformed lines are skipped
+fn parse(line: String) -> Option<Message> {
+    match parse_line(line.as_bytes()) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            log_error!("Skipping malformed line '{}': {:?}", line, e);
+            None
+        }
+    }
+}
+
+impl Decoder for IrcCodec {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Message>, std::io::Error> {
+        while let Some(line) = self.lines.decode(buf)? {
+            if let Some(msg) = parse(line) {
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Message>, std::io::Error> {
+        while let Some(line) = self.lines.decode_eof(buf)? {
+            if let Some(msg) = parse(line) {
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<Message> for IrcCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: Message, buf: &mut BytesMut) -> Result<(), std::io::Error> {
+        self.encode(&msg, buf)
+    }
+}
+
+impl Encoder<&Message> for IrcCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: &Message, buf: &mut BytesMut) -> Result<(), std::io::Error> {
+        let line = msg.encode()?;
+        let line = self.lines.charsets.encode(&line);
+        buf.reserve(line.len());
+        buf.put(&*line);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::CommandCode;
+
+    fn decode_all(codec: &mut IrcCodec, buf: &mut BytesMut) -> Vec<Message> {
+        let mut r = Vec::new();
+        while let Some(msg) = codec.decode(buf).unwrap() {
+            r.push(msg);
+        }
+        r
+    }
+
+    #[test]
+    fn line_endings() {
+        let mut codec = IrcCodec::new();
+        let mut buf = BytesMut::from(&b"PING :a\r\nPING :b\n\r\n\nPING :c\r\nPING :d"[..]);
+
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs.iter().map(|m| m.params[0].as_str()).collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(&buf[..], b"PING :d");
+
+        buf.extend_from_slice(b"\r\n");
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs[0].params, ["d"]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn partial_reads() {
+        let mut codec = IrcCodec::new();
+        let mut buf = BytesMut::new();
+        let line = b":fritschy!~fritschy@localhost PRIVMSG #zebot-test :moep\r\n";
+
+        for chunk in line.chunks(7) {
+            buf.extend_from_slice(chunk);
+            let msgs = decode_all(&mut codec, &mut buf);
+            if !buf.is_empty() {
+                assert!(msgs.is_empty());
+            } else {
+                assert_eq!(msgs[0].command, CommandCode::PrivMsg);
+                assert_eq!(msgs[0].params, ["#zebot-test", "moep"]);
+            }
+        }
+    }
+
+    #[test]
+    fn skip_malformed() {
+        let mut codec = IrcCodec::new();
+        let mut buf = BytesMut::from(&b":bad prefix! ! !\r\n:@ :-(\r\nPING :ok\r\n"[..]);
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].params, ["ok"]);
+    }
+
+    #[test]
+    fn skip_overlong() {
+        let mut codec = IrcCodec::with_max_line_length(16);
+        let mut buf = BytesMut::from(&b"PING :0123456789abcdef\r\nPING :ok\r\n"[..]);
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].params, ["ok"]);
+
+        // Without a LF in sight
+        let mut buf = BytesMut::from(&b"PING :0123456789abcdef"[..]);
+        assert!(decode_all(&mut codec, &mut buf).is_empty());
+        assert!(buf.is_empty());
+        buf.extend_from_slice(b"0123456789\r\nPING :ok\r\n");
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].params, ["ok"]);
+    }
+
+    #[test]
+    fn eof() {
+        let mut codec = IrcCodec::new();
+        let mut buf = BytesMut::from(&b"PING :a\r\nPING :b"[..]);
+        assert!(codec.decode_eof(&mut buf).unwrap().is_some());
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn lines() {
+        let mut codec = LineCodec::new();
+        let mut buf = BytesMut::from(&b":bad prefix! ! !\r\nPING :ok\r\n:foo PRIVMSG #zebot-test :Gr\xfc\xdfe\r\n"[..]);
+        let mut lines = Vec::new();
+        while let Some(line) = codec.decode(&mut buf).unwrap() {
+            lines.push(line);
+        }
+        assert_eq!(lines, [":bad prefix! ! !", "PING :ok", ":foo PRIVMSG #zebot-test :Gr\u{fffd}\u{fffd}e"]);
+    }
+
+    #[test]
+    fn encode() {
+        let mut codec = IrcCodec::new();
+        let mut buf = BytesMut::new();
+        let msg = Message::new(CommandCode::PrivMsg, vec!["#zebot-test".to_string(), "moep moep".to_string()]);
+        codec.encode(&msg, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"PRIVMSG #zebot-test :moep moep\r\n");
+
+        let msg = Message::new(CommandCode::PrivMsg, vec!["#zebot-test".to_string(), "a\r\nQUIT".to_string()]);
+        assert!(codec.encode(msg, &mut buf).is_err());
+    }
+
+    #[test]
+    fn charsets() {
+        let mut charsets = Charsets::new();
+        charsets.set_channel("#zebot-test", "latin1").unwrap();
+        let mut codec = IrcCodec::new();
+        codec.set_charsets(charsets);
+
+        let mut buf = BytesMut::from(&b":foo!~foo@localhost PRIVMSG #zebot-test :Gr\xfc\xdfe\r\n"[..]);
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs[0].params, ["#zebot-test", "Grüße"]);
+
+        let msg = Message::new(CommandCode::PrivMsg, vec!["#zebot-test".to_string(), "Grüße".to_string()]);
+        codec.encode(msg, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"PRIVMSG #zebot-test Gr\xfc\xdfe\r\n");
+    }
+}
@@ -1,68 +1,441 @@
 use std::fmt::{Display, Formatter};
 
-use tracing::{warn, error as log_error};
-
-#[derive(Eq, PartialEq, Hash, Debug, Clone)]
-pub enum CommandCode {
-    Numeric(u32),
-    Generic(String),
-    // Yeah ...
-    PrivMsg,
-    Notice,
-    Nick,
-    Join,
-    Part,
-    Quit,
-    Mode,
-    Ping,
-    Error,
-    Unknown,
+use tracing::warn;
+
+macro_rules! commands {
+    ($($variant:ident => $name:literal,)*) => {
+        #[derive(Eq, PartialEq, Hash, Debug, Clone)]
+        pub enum CommandCode {
+            Numeric(Numeric),
+            Generic(String),
+            $($variant,)*
+            Unknown,
+        }
+
+        impl CommandCode {
+            fn from_name(c: &[u8]) -> Option<Self> {
+                match c {
+                    $($name => Some(CommandCode::$variant),)*
+                    b"UNKNOWN" => Some(CommandCode::Unknown),
+                    _ => None,
+                }
+            }
+
+            fn name(&self) -> Option<&'static str> {
+                match self {
+                    // The names are byte strings to match against, they are ASCII only
+                    $(CommandCode::$variant => std::str::from_utf8($name).ok(),)*
+                    CommandCode::Unknown => Some("UNKNOWN"),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+// rfc2812.txt:532 (section 3) and IRCv3 extensions
+commands! {
+    Pass => b"PASS",
+    Nick => b"NICK",
+    User => b"USER",
+    Oper => b"OPER",
+    Mode => b"MODE",
+    Service => b"SERVICE",
+    Quit => b"QUIT",
+    SQuit => b"SQUIT",
+    Join => b"JOIN",
+    Part => b"PART",
+    Topic => b"TOPIC",
+    Names => b"NAMES",
+    List => b"LIST",
+    Invite => b"INVITE",
+    Kick => b"KICK",
+    PrivMsg => b"PRIVMSG",
+    Notice => b"NOTICE",
+    Motd => b"MOTD",
+    LUsers => b"LUSERS",
+    Version => b"VERSION",
+    Stats => b"STATS",
+    Links => b"LINKS",
+    Time => b"TIME",
+    Connect => b"CONNECT",
+    Trace => b"TRACE",
+    Admin => b"ADMIN",
+    Info => b"INFO",
+    ServList => b"SERVLIST",
+    SQuery => b"SQUERY",
+    Who => b"WHO",
+    WhoIs => b"WHOIS",
+    WhoWas => b"WHOWAS",
+    Kill => b"KILL",
+    Ping => b"PING",
+    Pong => b"PONG",
+    Error => b"ERROR",
+    Away => b"AWAY",
+    Rehash => b"REHASH",
+    Die => b"DIE",
+    Restart => b"RESTART",
+    Summon => b"SUMMON",
+    Users => b"USERS",
+    Wallops => b"WALLOPS",
+    UserHost => b"USERHOST",
+    IsOn => b"ISON",
+    Cap => b"CAP",
+    Authenticate => b"AUTHENTICATE",
+    Account => b"ACCOUNT",
+    ChgHost => b"CHGHOST",
+    SetName => b"SETNAME",
+    Batch => b"BATCH",
+    TagMsg => b"TAGMSG",
+    Monitor => b"MONITOR",
+}
+
+macro_rules! numerics {
+    ($($variant:ident = $code:literal, $name:literal;)*) => {
+        /// Numeric replies and errors, rfc2812.txt:2380 (section 5)
+        ///
+        /// Numerics compare and hash by their code, `Other(1)` is the same as `RplWelcome`.
+        #[derive(Debug, Clone, Copy)]
+        pub enum Numeric {
+            $($variant,)*
+            Other(u16),
+        }
+
+        impl Numeric {
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(Numeric::$variant => $code,)*
+                    Numeric::Other(n) => *n,
+                }
+            }
+
+            pub fn name(&self) -> Option<&'static str> {
+                match self {
+                    $(Numeric::$variant => Some($name),)*
+                    Numeric::Other(_) => None,
+                }
+            }
+        }
+
+        impl PartialEq for Numeric {
+            fn eq(&self, other: &Self) -> bool {
+                self.code() == other.code()
+            }
+        }
+
+        impl Eq for Numeric {}
+
+        impl std::hash::Hash for Numeric {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                self.code().hash(state);
+            }
+        }
+
+        impl From<u16> for Numeric {
+            fn from(n: u16) -> Self {
+                match n {
+                    $($code => Numeric::$variant,)*
+                    n => Numeric::Other(n),
+                }
+            }
+        }
+    };
+}
+
+numerics! {
+    RplWelcome = 1, "RPL_WELCOME";
+    RplYourHost = 2, "RPL_YOURHOST";
+    RplCreated = 3, "RPL_CREATED";
+    RplMyInfo = 4, "RPL_MYINFO";
+    // rfc2812 calls this RPL_BOUNCE, all current servers use it for RPL_ISUPPORT instead
+    RplISupport = 5, "RPL_ISUPPORT";
+    RplTraceLink = 200, "RPL_TRACELINK";
+    RplTraceConnecting = 201, "RPL_TRACECONNECTING";
+    RplTraceHandshake = 202, "RPL_TRACEHANDSHAKE";
+    RplTraceUnknown = 203, "RPL_TRACEUNKNOWN";
+    RplTraceOperator = 204, "RPL_TRACEOPERATOR";
+    RplTraceUser = 205, "RPL_TRACEUSER";
+    RplTraceServer = 206, "RPL_TRACESERVER";
+    RplTraceService = 207, "RPL_TRACESERVICE";
+    RplTraceNewType = 208, "RPL_TRACENEWTYPE";
+    RplTraceClass = 209, "RPL_TRACECLASS";
+    RplTraceReconnect = 210, "RPL_TRACERECONNECT";
+    RplStatsLinkInfo = 211, "RPL_STATSLINKINFO";
+    RplStatsCommands = 212, "RPL_STATSCOMMANDS";
+    RplStatsCLine = 213, "RPL_STATSCLINE";
+    RplStatsNLine = 214, "RPL_STATSNLINE";
+    RplStatsILine = 215, "RPL_STATSILINE";
+    RplStatsKLine = 216, "RPL_STATSKLINE";
+    RplStatsQLine = 217, "RPL_STATSQLINE";
+    RplStatsYLine = 218, "RPL_STATSYLINE";
+    RplEndOfStats = 219, "RPL_ENDOFSTATS";
+    RplUModeIs = 221, "RPL_UMODEIS";
+    RplServiceInfo = 231, "RPL_SERVICEINFO";
+    RplEndOfServices = 232, "RPL_ENDOFSERVICES";
+    RplService = 233, "RPL_SERVICE";
+    RplServList = 234, "RPL_SERVLIST";
+    RplServListEnd = 235, "RPL_SERVLISTEND";
+    RplStatsVLine = 240, "RPL_STATSVLINE";
+    RplStatsLLine = 241, "RPL_STATSLLINE";
+    RplStatsUptime = 242, "RPL_STATSUPTIME";
+    RplStatsOLine = 243, "RPL_STATSOLINE";
+    RplStatsHLine = 244, "RPL_STATSHLINE";
+    RplStatsPing = 246, "RPL_STATSPING";
+    RplStatsBLine = 247, "RPL_STATSBLINE";
+    RplStatsDLine = 250, "RPL_STATSDLINE";
+    RplLUserClient = 251, "RPL_LUSERCLIENT";
+    RplLUserOp = 252, "RPL_LUSEROP";
+    RplLUserUnknown = 253, "RPL_LUSERUNKNOWN";
+    RplLUserChannels = 254, "RPL_LUSERCHANNELS";
+    RplLUserMe = 255, "RPL_LUSERME";
+    RplAdminMe = 256, "RPL_ADMINME";
+    RplAdminLoc1 = 257, "RPL_ADMINLOC1";
+    RplAdminLoc2 = 258, "RPL_ADMINLOC2";
+    RplAdminEmail = 259, "RPL_ADMINEMAIL";
+    RplTraceLog = 261, "RPL_TRACELOG";
+    RplTraceEnd = 262, "RPL_TRACEEND";
+    RplTryAgain = 263, "RPL_TRYAGAIN";
+    RplNone = 300, "RPL_NONE";
+    RplAway = 301, "RPL_AWAY";
+    RplUserHost = 302, "RPL_USERHOST";
+    RplIsOn = 303, "RPL_ISON";
+    RplUnAway = 305, "RPL_UNAWAY";
+    RplNowAway = 306, "RPL_NOWAWAY";
+    RplWhoIsUser = 311, "RPL_WHOISUSER";
+    RplWhoIsServer = 312, "RPL_WHOISSERVER";
+    RplWhoIsOperator = 313, "RPL_WHOISOPERATOR";
+    RplWhoWasUser = 314, "RPL_WHOWASUSER";
+    RplEndOfWho = 315, "RPL_ENDOFWHO";
+    RplWhoIsChanOp = 316, "RPL_WHOISCHANOP";
+    RplWhoIsIdle = 317, "RPL_WHOISIDLE";
+    RplEndOfWhoIs = 318, "RPL_ENDOFWHOIS";
+    RplWhoIsChannels = 319, "RPL_WHOISCHANNELS";
+    RplListStart = 321, "RPL_LISTSTART";
+    RplList = 322, "RPL_LIST";
+    RplListEnd = 323, "RPL_LISTEND";
+    RplChannelModeIs = 324, "RPL_CHANNELMODEIS";
+    RplUniqOpIs = 325, "RPL_UNIQOPIS";
+    RplNoTopic = 331, "RPL_NOTOPIC";
+    RplTopic = 332, "RPL_TOPIC";
+    RplInviting = 341, "RPL_INVITING";
+    RplSummoning = 342, "RPL_SUMMONING";
+    RplInviteList = 346, "RPL_INVITELIST";
+    RplEndOfInviteList = 347, "RPL_ENDOFINVITELIST";
+    RplExceptList = 348, "RPL_EXCEPTLIST";
+    RplEndOfExceptList = 349, "RPL_ENDOFEXCEPTLIST";
+    RplVersion = 351, "RPL_VERSION";
+    RplWhoReply = 352, "RPL_WHOREPLY";
+    RplNamReply = 353, "RPL_NAMREPLY";
+    RplKillDone = 361, "RPL_KILLDONE";
+    RplClosing = 362, "RPL_CLOSING";
+    RplCloseEnd = 363, "RPL_CLOSEEND";
+    RplLinks = 364, "RPL_LINKS";
+    RplEndOfLinks = 365, "RPL_ENDOFLINKS";
+    RplEndOfNames = 366, "RPL_ENDOFNAMES";
+    RplBanList = 367, "RPL_BANLIST";
+    RplEndOfBanList = 368, "RPL_ENDOFBANLIST";
+    RplEndOfWhoWas = 369, "RPL_ENDOFWHOWAS";
+    RplInfo = 371, "RPL_INFO";
+    RplMotd = 372, "RPL_MOTD";
+    RplInfoStart = 373, "RPL_INFOSTART";
+    RplEndOfInfo = 374, "RPL_ENDOFINFO";
+    RplMotdStart = 375, "RPL_MOTDSTART";
+    RplEndOfMotd = 376, "RPL_ENDOFMOTD";
+    RplYoureOper = 381, "RPL_YOUREOPER";
+    RplRehashing = 382, "RPL_REHASHING";
+    RplYoureService = 383, "RPL_YOURESERVICE";
+    RplMyPortIs = 384, "RPL_MYPORTIS";
+    RplTime = 391, "RPL_TIME";
+    RplUsersStart = 392, "RPL_USERSSTART";
+    RplUsers = 393, "RPL_USERS";
+    RplEndOfUsers = 394, "RPL_ENDOFUSERS";
+    RplNoUsers = 395, "RPL_NOUSERS";
+    ErrNoSuchNick = 401, "ERR_NOSUCHNICK";
+    ErrNoSuchServer = 402, "ERR_NOSUCHSERVER";
+    ErrNoSuchChannel = 403, "ERR_NOSUCHCHANNEL";
+    ErrCannotSendToChan = 404, "ERR_CANNOTSENDTOCHAN";
+    ErrTooManyChannels = 405, "ERR_TOOMANYCHANNELS";
+    ErrWasNoSuchNick = 406, "ERR_WASNOSUCHNICK";
+    ErrTooManyTargets = 407, "ERR_TOOMANYTARGETS";
+    ErrNoSuchService = 408, "ERR_NOSUCHSERVICE";
+    ErrNoOrigin = 409, "ERR_NOORIGIN";
+    ErrNoRecipient = 411, "ERR_NORECIPIENT";
+    ErrNoTextToSend = 412, "ERR_NOTEXTTOSEND";
+    ErrNoTopLevel = 413, "ERR_NOTOPLEVEL";
+    ErrWildTopLevel = 414, "ERR_WILDTOPLEVEL";
+    ErrBadMask = 415, "ERR_BADMASK";
+    ErrUnknownCommand = 421, "ERR_UNKNOWNCOMMAND";
+    ErrNoMotd = 422, "ERR_NOMOTD";
+    ErrNoAdminInfo = 423, "ERR_NOADMININFO";
+    ErrFileError = 424, "ERR_FILEERROR";
+    ErrNoNicknameGiven = 431, "ERR_NONICKNAMEGIVEN";
+    ErrErroneousNickname = 432, "ERR_ERRONEUSNICKNAME";
+    ErrNicknameInUse = 433, "ERR_NICKNAMEINUSE";
+    ErrNickCollision = 436, "ERR_NICKCOLLISION";
+    ErrUnavailResource = 437, "ERR_UNAVAILRESOURCE";
+    ErrUserNotInChannel = 441, "ERR_USERNOTINCHANNEL";
+    ErrNotOnChannel = 442, "ERR_NOTONCHANNEL";
+    ErrUserOnChannel = 443, "ERR_USERONCHANNEL";
+    ErrNoLogin = 444, "ERR_NOLOGIN";
+    ErrSummonDisabled = 445, "ERR_SUMMONDISABLED";
+    ErrUsersDisabled = 446, "ERR_USERSDISABLED";
+    ErrNotRegistered = 451, "ERR_NOTREGISTERED";
+    ErrNeedMoreParams = 461, "ERR_NEEDMOREPARAMS";
+    ErrAlreadyRegistered = 462, "ERR_ALREADYREGISTRED";
+    ErrNoPermForHost = 463, "ERR_NOPERMFORHOST";
+    ErrPasswdMismatch = 464, "ERR_PASSWDMISMATCH";
+    ErrYoureBannedCreep = 465, "ERR_YOUREBANNEDCREEP";
+    ErrYouWillBeBanned = 466, "ERR_YOUWILLBEBANNED";
+    ErrKeySet = 467, "ERR_KEYSET";
+    ErrChannelIsFull = 471, "ERR_CHANNELISFULL";
+    ErrUnknownMode = 472, "ERR_UNKNOWNMODE";
+    ErrInviteOnlyChan = 473, "ERR_INVITEONLYCHAN";
+    ErrBannedFromChan = 474, "ERR_BANNEDFROMCHAN";
+    ErrBadChannelKey = 475, "ERR_BADCHANNELKEY";
+    ErrBadChanMask = 476, "ERR_BADCHANMASK";
+    ErrNoChanModes = 477, "ERR_NOCHANMODES";
+    ErrBanListFull = 478, "ERR_BANLISTFULL";
+    ErrNoPrivileges = 481, "ERR_NOPRIVILEGES";
+    ErrChanOPrivsNeeded = 482, "ERR_CHANOPRIVSNEEDED";
+    ErrCantKillServer = 483, "ERR_CANTKILLSERVER";
+    ErrRestricted = 484, "ERR_RESTRICTED";
+    ErrUniqOpPrivsNeeded = 485, "ERR_UNIQOPPRIVSNEEDED";
+    ErrNoOperHost = 491, "ERR_NOOPERHOST";
+    ErrNoServiceHost = 492, "ERR_NOSERVICEHOST";
+    ErrUModeUnknownFlag = 501, "ERR_UMODEUNKNOWNFLAG";
+    ErrUsersDontMatch = 502, "ERR_USERSDONTMATCH";
+
+    // https://ircv3.net/specs/extensions/monitor
+    RplMonOnline = 730, "RPL_MONONLINE";
+    RplMonOffline = 731, "RPL_MONOFFLINE";
+    RplMonList = 732, "RPL_MONLIST";
+    RplEndOfMonList = 733, "RPL_ENDOFMONLIST";
+    ErrMonListFull = 734, "ERR_MONLISTFULL";
+
+    // https://ircv3.net/specs/extensions/sasl-3.1
+    RplLoggedIn = 900, "RPL_LOGGEDIN";
+    RplLoggedOut = 901, "RPL_LOGGEDOUT";
+    ErrNickLocked = 902, "ERR_NICKLOCKED";
+    RplSaslSuccess = 903, "RPL_SASLSUCCESS";
+    ErrSaslFail = 904, "ERR_SASLFAIL";
+    ErrSaslTooLong = 905, "ERR_SASLTOOLONG";
+    ErrSaslAborted = 906, "ERR_SASLABORTED";
+    ErrSaslAlready = 907, "ERR_SASLALREADY";
+    RplSaslMechs = 908, "RPL_SASLMECHS";
+}
+
+impl Numeric {
+    // rfc2812.txt:2388
+    pub fn is_error(&self) -> bool {
+        (400..600).contains(&self.code())
+            || matches!(
+                self,
+                Numeric::ErrNickLocked
+                    | Numeric::ErrSaslFail
+                    | Numeric::ErrSaslTooLong
+                    | Numeric::ErrSaslAborted
+                    | Numeric::ErrSaslAlready
+            )
+    }
+}
+
+impl From<Numeric> for u16 {
+    fn from(n: Numeric) -> Self {
+        n.code()
+    }
+}
+
+impl Display for Numeric {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{:03}", self.code()),
+        }
+    }
+}
+
+impl From<Numeric> for CommandCode {
+    fn from(n: Numeric) -> Self {
+        CommandCode::Numeric(n)
+    }
 }
 
 impl From<&[u8]> for CommandCode {
     fn from(c: &[u8]) -> Self {
         if c.len() == 3 && c.iter().all(|x| x.is_ascii_digit()) {
-            CommandCode::Numeric(c.iter().rev().enumerate().fold(0u32, |acc, x| {
-                acc + (*x.1 - b'0') as u32 * 10u32.pow(x.0 as u32)
-            }))
+            CommandCode::Numeric(Numeric::from(c.iter().fold(0u16, |acc, x| acc * 10 + (*x - b'0') as u16)))
+        } else if let Some(c) = CommandCode::from_name(c) {
+            c
         } else {
-            match c {
-                b"PRIVMSG" => CommandCode::PrivMsg,
-                b"NOTICE" => CommandCode::Notice,
-                b"NICK" => CommandCode::Nick,
-                b"JOIN" => CommandCode::Join,
-                b"PART" => CommandCode::Part,
-                b"QUIT" => CommandCode::Quit,
-                b"MODE" => CommandCode::Mode,
-                b"PING" => CommandCode::Ping,
-                b"ERROR" => CommandCode::Error,
-                b"UNKNOWN" => CommandCode::Unknown,
-                _ => {
-                    let c = String::from_utf8_lossy(c);
-                    warn!("WARNING: Fallback to generic CommandCode for {}", c);
-                    CommandCode::Generic(c.to_string())
-                }
-            }
+            let c = String::from_utf8_lossy(c);
+            warn!("WARNING: Fallback to generic CommandCode for {}", c);
+            CommandCode::Generic(c.to_string())
         }
     }
 }
 
+impl From<&str> for CommandCode {
+    fn from(c: &str) -> Self {
+        c.as_bytes().into()
+    }
+}
+
 impl Display for CommandCode {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            CommandCode::PrivMsg => write!(f, "PRIVMSG")?,
-            CommandCode::Notice => write!(f, "NOTICE")?,
-            CommandCode::Nick => write!(f, "NICK")?,
-            CommandCode::Join => write!(f, "JOIN")?,
-            CommandCode::Part => write!(f, "PART")?,
-            CommandCode::Quit => write!(f, "QUIT")?,
-            CommandCode::Mode => write!(f, "MODE")?,
-            CommandCode::Ping => write!(f, "PING")?,
-            CommandCode::Error => write!(f, "ERROR")?,
-            CommandCode::Unknown => write!(f, "UNKNOWN")?,
-            CommandCode::Numeric(n) => write!(f, "{:03}", n)?,
-            CommandCode::Generic(n) => write!(f, "'{}'", n)?,
+            CommandCode::Numeric(n) => write!(f, "{:03}", n.code()),
+            CommandCode::Generic(n) => write!(f, "{}", n),
+            c => write!(f, "{}", c.name().unwrap_or_default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numerics() {
+        assert_eq!(CommandCode::from(&b"001"[..]), CommandCode::Numeric(Numeric::RplWelcome));
+        assert_eq!(CommandCode::from(&b"433"[..]), CommandCode::Numeric(Numeric::ErrNicknameInUse));
+        assert_eq!(CommandCode::from(&b"999"[..]), CommandCode::Numeric(Numeric::Other(999)));
+
+        assert_eq!(Numeric::from(353), Numeric::RplNamReply);
+        assert_eq!(u16::from(Numeric::RplEndOfMotd), 376);
+        assert_eq!(Numeric::RplISupport.code(), 5);
+
+        assert_eq!(Numeric::ErrNicknameInUse.to_string(), "ERR_NICKNAMEINUSE");
+        assert_eq!(Numeric::Other(999).to_string(), "999");
+        assert_eq!(CommandCode::Numeric(Numeric::RplWelcome).to_string(), "001");
+
+        // No aliases for known codes
+        assert_eq!(Numeric::Other(1), Numeric::RplWelcome);
+        assert_eq!(CommandCode::Numeric(Numeric::Other(433)), CommandCode::from(&b"433"[..]));
+        let handlers: std::collections::HashSet<_> = [CommandCode::Numeric(Numeric::RplWelcome)].into();
+        assert!(handlers.contains(&CommandCode::Numeric(Numeric::Other(1))));
+
+        assert!(Numeric::ErrBannedFromChan.is_error());
+        assert!(!Numeric::RplTopic.is_error());
+        assert!(Numeric::from(904).is_error());
+        assert!(!Numeric::RplSaslSuccess.is_error());
+    }
+
+    #[test]
+    fn numerics_roundtrip() {
+        for n in 0..1000 {
+            assert_eq!(Numeric::from(n).code(), n);
+        }
+    }
+
+    #[test]
+    fn commands() {
+        for c in ["KICK", "INVITE", "TOPIC", "NAMES", "WHO", "CAP", "AUTHENTICATE", "PRIVMSG"] {
+            let code = CommandCode::from(c);
+            assert!(!matches!(code, CommandCode::Generic(_)), "{} is generic", c);
+            assert_eq!(code.to_string(), c);
         }
-        Ok(())
+        assert_eq!(CommandCode::from("KICK"), CommandCode::Kick);
+        assert_eq!(CommandCode::from("FOO"), CommandCode::Generic("FOO".to_string()));
+        assert_eq!(CommandCode::from("FOO").to_string(), "FOO");
     }
 }
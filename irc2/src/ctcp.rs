@@ -0,0 +1,214 @@
+use std::fmt::{Display, Formatter};
+
+use crate::command::CommandCode;
+use crate::Message;
+
+const DELIM: char = '\x01';
+
+/// A CTCP message, see https://modern.ircdocs.horse/ctcp.html
+#[derive(Debug, PartialEq, Clone)]
+pub enum Ctcp {
+    Action(String),
+    Version(Option<String>),
+    Ping(Option<String>),
+    Time(Option<String>),
+    ClientInfo(Option<String>),
+    Dcc(String),
+    Other(String, Option<String>),
+}
+
+/// Part of a PRIVMSG or NOTICE text, either plain text or a CTCP message
+#[derive(Debug, PartialEq, Clone)]
+pub enum Part<'a> {
+    Text(&'a str),
+    Ctcp(Ctcp),
+}
+
+impl Ctcp {
+    /// Parse the contents between the delimiters, e.g. `VERSION` or `ACTION waves`
+    pub fn parse(s: &str) -> Self {
+        let (command, params) = match s.split_once(' ') {
+            Some((c, p)) => (c, Some(p.to_string())),
+            None => (s, None),
+        };
+
+        match command.to_ascii_uppercase().as_str() {
+            "ACTION" => Ctcp::Action(params.unwrap_or_default()),
+            "VERSION" => Ctcp::Version(params),
+            "PING" => Ctcp::Ping(params),
+            "TIME" => Ctcp::Time(params),
+            "CLIENTINFO" => Ctcp::ClientInfo(params),
+            "DCC" => Ctcp::Dcc(params.unwrap_or_default()),
+            _ => Ctcp::Other(command.to_string(), params),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        match self {
+            Ctcp::Action(_) => "ACTION",
+            Ctcp::Version(_) => "VERSION",
+            Ctcp::Ping(_) => "PING",
+            Ctcp::Time(_) => "TIME",
+            Ctcp::ClientInfo(_) => "CLIENTINFO",
+            Ctcp::Dcc(_) => "DCC",
+            Ctcp::Other(c, _) => c,
+        }
+    }
+
+    pub fn params(&self) -> Option<&str> {
+        match self {
+            Ctcp::Action(p) | Ctcp::Dcc(p) => Some(p),
+            Ctcp::Version(p) | Ctcp::Ping(p) | Ctcp::Time(p) | Ctcp::ClientInfo(p) | Ctcp::Other(_, p) => {
+                p.as_deref()
+            }
+        }
+    }
+
+    /// Encode this including the delimiters, ready to be used as PRIVMSG or NOTICE text
+    pub fn encode(&self) -> String {
+        format!("{}", self)
+    }
+
+    /// Build the NOTICE carrying this as a reply to a query from `dst`
+    pub fn reply(&self, dst: &str) -> Message {
+        Message::new(CommandCode::Notice, vec![dst.to_string(), self.encode()])
+    }
+
+    /// Build the PRIVMSG carrying this as a query to `dst`
+    pub fn query(&self, dst: &str) -> Message {
+        Message::new(CommandCode::PrivMsg, vec![dst.to_string(), self.encode()])
+    }
+}
+
+impl Display for Ctcp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // The delimiter and line breaks cannot be quoted, just drop them
+        let clean = |s: &str| s.replace([DELIM, '\r', '\n', '\0'], "");
+        write!(f, "{}{}", DELIM, clean(self.command()))?;
+        if let Some(p) = self.params() {
+            write!(f, " {}", clean(p))?;
+        }
+        write!(f, "{}", DELIM)
+    }
+}
+
+/// Split a text into plain text and CTCP parts. The closing delimiter of the last CTCP message
+/// is optional, as some clients do not send it.
+pub fn split(text: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        match rest.find(DELIM) {
+            Some(start) => {
+                if start > 0 {
+                    parts.push(Part::Text(&rest[..start]));
+                }
+                let tail = &rest[start + 1..];
+                let (ctcp, next) = match tail.find(DELIM) {
+                    Some(end) => (&tail[..end], &tail[end + 1..]),
+                    None => (tail, ""),
+                };
+                if !ctcp.is_empty() {
+                    parts.push(Part::Ctcp(Ctcp::parse(ctcp)));
+                }
+                rest = next;
+            }
+            None => {
+                parts.push(Part::Text(rest));
+                break;
+            }
+        }
+    }
+
+    parts
+}
+
+impl Message {
+    /// All CTCP messages contained in a PRIVMSG or NOTICE
+    pub fn ctcp(&self) -> Vec<Ctcp> {
+        match self.command {
+            CommandCode::PrivMsg | CommandCode::Notice if self.params.len() > 1 => split(&self.params[1])
+                .into_iter()
+                .filter_map(|p| match p {
+                    Part::Ctcp(c) => Some(c),
+                    Part::Text(_) => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether this is a CTCP ACTION, i.e. /me
+    pub fn is_action(&self) -> bool {
+        self.ctcp().iter().any(|c| matches!(c, Ctcp::Action(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ctcp::parse("VERSION"), Ctcp::Version(None));
+        assert_eq!(Ctcp::parse("version"), Ctcp::Version(None));
+        assert_eq!(Ctcp::parse("ACTION waves at you"), Ctcp::Action("waves at you".to_string()));
+        assert_eq!(Ctcp::parse("PING 1234567890"), Ctcp::Ping(Some("1234567890".to_string())));
+        assert_eq!(Ctcp::parse("TIME"), Ctcp::Time(None));
+        assert_eq!(Ctcp::parse("CLIENTINFO"), Ctcp::ClientInfo(None));
+        assert_eq!(
+            Ctcp::parse("DCC SEND file.txt 3232235521 1234 42"),
+            Ctcp::Dcc("SEND file.txt 3232235521 1234 42".to_string())
+        );
+        assert_eq!(Ctcp::parse("FINGER"), Ctcp::Other("FINGER".to_string(), None));
+    }
+
+    #[test]
+    fn split_text() {
+        assert_eq!(split("moep"), vec![Part::Text("moep")]);
+        assert_eq!(split("\x01VERSION\x01"), vec![Part::Ctcp(Ctcp::Version(None))]);
+        assert_eq!(split("\x01ACTION waves"), vec![Part::Ctcp(Ctcp::Action("waves".to_string()))]);
+        assert_eq!(
+            split("hi \x01PING 1\x01 there\x01TIME\x01"),
+            vec![
+                Part::Text("hi "),
+                Part::Ctcp(Ctcp::Ping(Some("1".to_string()))),
+                Part::Text(" there"),
+                Part::Ctcp(Ctcp::Time(None)),
+            ]
+        );
+        assert_eq!(split("\x01\x01"), vec![]);
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(Ctcp::Version(Some("ZeBot 0.5.0".to_string())).encode(), "\x01VERSION ZeBot 0.5.0\x01");
+        assert_eq!(Ctcp::Ping(None).encode(), "\x01PING\x01");
+        assert_eq!(Ctcp::Action("a\x01b\r\nc".to_string()).encode(), "\x01ACTION abc\x01");
+
+        let m = Ctcp::Time(Some("now".to_string())).reply("fritschy");
+        assert_eq!(m.encode().unwrap(), "NOTICE fritschy :\x01TIME now\x01\r\n");
+
+        for c in [
+            Ctcp::Action("waves".to_string()),
+            Ctcp::Version(None),
+            Ctcp::Ping(Some("123".to_string())),
+            Ctcp::Other("FINGER".to_string(), Some("x y".to_string())),
+        ] {
+            assert_eq!(split(&c.encode()), vec![Part::Ctcp(c.clone())]);
+        }
+    }
+
+    #[test]
+    fn freenode_bot_frigg() {
+        let i = &b":freenode-connect!frigg@freenode/utility-bot/frigg PRIVMSG ZeBot :\x01VERSION\x01\r\n"[..];
+        let (_, msg) = crate::parse(i).unwrap();
+        assert_eq!(msg.ctcp(), vec![Ctcp::Version(None)]);
+        assert!(!msg.is_action());
+
+        let i = &b":fritschy!~fritschy@localhost PRIVMSG #zebot-test :\x01ACTION waves\x01\r\n"[..];
+        let (_, msg) = crate::parse(i).unwrap();
+        assert!(msg.is_action());
+    }
+}
@@ -0,0 +1,386 @@
+use std::fmt::{Display, Formatter};
+
+use crate::command::CommandCode;
+use crate::*;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EncodeError {
+    InvalidTagKey(String),
+    InvalidTagValue(String),
+    InvalidPrefix(String),
+    InvalidCommand(String),
+    InvalidParam(String),
+    TooManyParams(usize),
+    /// An argument to one of the typed constructors, e.g. `Message::privmsg()`, by its name
+    InvalidArgument(&'static str, String),
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::InvalidTagKey(k) => write!(f, "Invalid tag key '{}'", k),
+            EncodeError::InvalidTagValue(v) => write!(f, "Invalid tag value '{}'", v.escape_debug()),
+            EncodeError::InvalidPrefix(p) => write!(f, "Invalid prefix '{}'", p.escape_debug()),
+            EncodeError::InvalidCommand(c) => write!(f, "Invalid command '{}'", c.escape_debug()),
+            EncodeError::InvalidParam(p) => write!(f, "Invalid parameter '{}'", p.escape_debug()),
+            EncodeError::TooManyParams(n) => write!(f, "Too many parameters: {} (max. 15)", n),
+            EncodeError::InvalidArgument(n, v) => write!(f, "Invalid {} '{}'", n, v.escape_debug()),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl From<EncodeError> for std::io::Error {
+    fn from(e: EncodeError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+// rfc2812.txt:324 - at most 14 middle params plus the trailing one
+const MAX_PARAMS: usize = 15;
+
+impl Message {
+    /// Encode this message to a line as it is sent over the wire, including the trailing CRLF.
+    ///
+    /// The last parameter only gets a leading `:` if it needs one, i.e. if it is empty,
+    /// contains a space or starts with a `:` itself.
+    pub fn encode(&self) -> Result<String, EncodeError> {
+        let mut line = String::with_capacity(64);
+
+        if !self.tags.is_empty() {
+            line.push('@');
+            for (n, (k, v)) in self.tags.iter().enumerate() {
+                if n > 0 {
+                    line.push(';');
+                }
+                encode_tag(&mut line, &k, v)?;
+            }
+            line.push(' ');
+        }
+
+        if let Some(prefix) = &self.prefix {
+            line.push(':');
+            encode_prefix(&mut line, prefix)?;
+            line.push(' ');
+        }
+
+        encode_command(&mut line, &self.command)?;
+
+        if self.params.len() > MAX_PARAMS {
+            return Err(EncodeError::TooManyParams(self.params.len()));
+        }
+
+        if let Some((last, middle)) = self.params.split_last() {
+            for p in middle {
+                if !is_middle(p) {
+                    return Err(EncodeError::InvalidParam(p.clone()));
+                }
+                line.push(' ');
+                line.push_str(p);
+            }
+
+            if last.contains(['\0', '\r', '\n']) {
+                return Err(EncodeError::InvalidParam(last.clone()));
+            }
+            line.push(' ');
+            if !is_middle(last) {
+                line.push(':');
+            }
+            line.push_str(last);
+        }
+
+        line.push_str("\r\n");
+
+        Ok(line)
+    }
+}
+
+// rfc2812.txt:327
+fn is_nospcrlfcl(c: char) -> bool {
+    c != '\0' && c != '\r' && c != '\n' && c != ' ' && c != ':'
+}
+
+// rfc2812.txt:329
+fn is_middle(p: &str) -> bool {
+    let mut chars = p.chars();
+    match chars.next() {
+        Some(c) if is_nospcrlfcl(c) => chars.all(|c| c == ':' || is_nospcrlfcl(c)),
+        _ => false,
+    }
+}
+
+// <key> ::= [ <client_prefix> ] [ <vendor> '/' ] <key_name>
+fn is_tag_key(k: &str) -> bool {
+    fn is_key_name(k: &str) -> bool {
+        !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    }
+    let k = k.strip_prefix('+').unwrap_or(k);
+    match k.split_once('/') {
+        Some((vendor, name)) => {
+            !vendor.is_empty()
+                && vendor.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                && is_key_name(name)
+        }
+        None => is_key_name(k),
+    }
+}
+
+fn encode_tag(line: &mut String, key: &str, value: &str) -> Result<(), EncodeError> {
+    if !is_tag_key(key) {
+        return Err(EncodeError::InvalidTagKey(key.to_string()));
+    }
+
+    line.push_str(key);
+
+    // key and key= are equivalent, prefer the shorter one
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    line.push('=');
+    for c in value.chars() {
+        match c {
+            ';' => line.push_str("\\:"),
+            ' ' => line.push_str("\\s"),
+            '\\' => line.push_str("\\\\"),
+            '\r' => line.push_str("\\r"),
+            '\n' => line.push_str("\\n"),
+            '\0' => return Err(EncodeError::InvalidTagValue(value.to_string())),
+            c => line.push(c),
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_prefix(line: &mut String, prefix: &Prefix) -> Result<(), EncodeError> {
+    fn is_valid(s: &str, extra: &[char]) -> bool {
+        !s.is_empty() && !s.contains(|c| "\0\r\n ".contains(c) || extra.contains(&c))
+    }
+
+    let valid = match prefix {
+        Prefix::Server(s) => is_valid(s, &['!', '@']),
+        Prefix::Nickname(Nickname { nickname, user, host }) => {
+            is_valid(nickname, &['!', '@', ':'])
+                && user.as_ref().map(|u| is_valid(u, &['@'])).unwrap_or(true)
+                && host.as_ref().map(|h| is_valid(&h.to_string(), &[])).unwrap_or(true)
+                // The user part is only valid together with the host part
+                && (user.is_none() || host.is_some())
+        }
+    };
+
+    if !valid {
+        return Err(EncodeError::InvalidPrefix(format!("{:?}", prefix)));
+    }
+
+    line.push_str(&prefix.to_string());
+
+    Ok(())
+}
+
+// rfc2812.txt:323
+fn encode_command(line: &mut String, command: &CommandCode) -> Result<(), EncodeError> {
+    match command {
+        CommandCode::Numeric(n) if n.code() > 999 => {
+            return Err(EncodeError::InvalidCommand(n.code().to_string()));
+        }
+        CommandCode::Generic(c) if c.is_empty() || !c.chars().all(|c| c.is_ascii_alphabetic()) => {
+            return Err(EncodeError::InvalidCommand(c.clone()));
+        }
+        _ => (),
+    }
+
+    line.push_str(&command.to_string());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::command::{CommandCode, Numeric};
+    use crate::*;
+
+    use super::EncodeError;
+
+    fn msg(prefix: Option<Prefix>, command: CommandCode, params: &[&str]) -> Message {
+        Message {
+            tags: Tags::default(),
+            prefix,
+            command,
+            params: params.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn trailing_only_when_needed() {
+        let m = msg(None, CommandCode::Join, &["#zebot-test"]);
+        assert_eq!(m.encode().unwrap(), "JOIN #zebot-test\r\n");
+
+        let m = msg(None, CommandCode::PrivMsg, &["#zebot-test", "moep"]);
+        assert_eq!(m.encode().unwrap(), "PRIVMSG #zebot-test moep\r\n");
+
+        let m = msg(None, CommandCode::PrivMsg, &["#zebot-test", "moep moep"]);
+        assert_eq!(m.encode().unwrap(), "PRIVMSG #zebot-test :moep moep\r\n");
+
+        let m = msg(None, CommandCode::PrivMsg, &["#zebot-test", ":)"]);
+        assert_eq!(m.encode().unwrap(), "PRIVMSG #zebot-test ::)\r\n");
+
+        let m = msg(None, CommandCode::Mode, &["ZeBot", ""]);
+        assert_eq!(m.encode().unwrap(), "MODE ZeBot :\r\n");
+
+        let m = msg(None, CommandCode::Quit, &[]);
+        assert_eq!(m.encode().unwrap(), "QUIT\r\n");
+    }
+
+    #[test]
+    fn prefix_and_tags() {
+        let mut m = msg(
+            Some(Prefix::Nickname(Nickname {
+                nickname: "fritschy".to_string(),
+                user: Some("~fritschy".to_string()),
+                host: Some(Host::Hostname("localhost".to_string())),
+            })),
+            CommandCode::Numeric(Numeric::RplWelcome),
+            &["ZeBot", "Welcome"],
+        );
+        m.tags.insert("time", "2021-01-01T12:00:00.000Z");
+        m.tags.insert("+draft/reply", "a b;c\\");
+        m.tags.insert("novalue", "");
+        assert_eq!(
+            m.encode().unwrap(),
+            "@novalue;time=2021-01-01T12:00:00.000Z;+draft/reply=a\\sb\\:c\\\\ :fritschy!~fritschy@localhost 001 ZeBot Welcome\r\n"
+        );
+    }
+
+    #[test]
+    fn reject_unencodable() {
+        let m = msg(None, CommandCode::PrivMsg, &["#zebot-test", "moep\r\nQUIT"]);
+        assert_eq!(m.encode(), Err(EncodeError::InvalidParam("moep\r\nQUIT".to_string())));
+
+        let m = msg(None, CommandCode::PrivMsg, &["#zebot test", "moep"]);
+        assert!(matches!(m.encode(), Err(EncodeError::InvalidParam(_))));
+
+        let m = msg(None, CommandCode::PrivMsg, &[":zebot", "moep"]);
+        assert!(matches!(m.encode(), Err(EncodeError::InvalidParam(_))));
+
+        let m = msg(None, CommandCode::PrivMsg, &["", "moep"]);
+        assert!(matches!(m.encode(), Err(EncodeError::InvalidParam(_))));
+
+        let m = msg(None, CommandCode::Generic("PRIV MSG".to_string()), &[]);
+        assert!(matches!(m.encode(), Err(EncodeError::InvalidCommand(_))));
+
+        let m = msg(None, CommandCode::Numeric(Numeric::Other(1000)), &[]);
+        assert!(matches!(m.encode(), Err(EncodeError::InvalidCommand(_))));
+
+        let m = msg(None, CommandCode::Ping, &["x"; 16]);
+        assert_eq!(m.encode(), Err(EncodeError::TooManyParams(16)));
+
+        let m = msg(Some(Prefix::Server("irc example".to_string())), CommandCode::Ping, &[]);
+        assert!(matches!(m.encode(), Err(EncodeError::InvalidPrefix(_))));
+
+        let mut m = msg(None, CommandCode::Ping, &[]);
+        m.tags.insert("in valid", "");
+        assert!(matches!(m.encode(), Err(EncodeError::InvalidTagKey(_))));
+    }
+
+    fn arb_tags() -> impl Strategy<Value = Tags> {
+        let key = "\\+?([a-z0-9.-]{1,8}/)?[a-zA-Z0-9-]{1,10}";
+        let value = "[^\0]{0,16}";
+        prop::collection::vec((key, value), 0..4).prop_map(|tags| {
+            let mut t = Tags::default();
+            for (k, v) in tags {
+                t.insert(&k, &v);
+            }
+            t
+        })
+    }
+
+    fn arb_host() -> impl Strategy<Value = Host> {
+        // The first label starts with a letter, so this cannot be mistaken for an IPv4 address
+        let hostname = "[a-z]([a-z0-9-]{0,8}[a-z0-9])?(\\.[a-z0-9]([a-z0-9-]{0,8}[a-z0-9])?){0,3}";
+        prop_oneof![
+            hostname.prop_map(Host::Hostname),
+            any::<[u8; 4]>().prop_map(|a| Host::Ip4(a.into())),
+            any::<[u16; 8]>().prop_map(|a| Host::Ip6(a.into())),
+            "[a-z]{1,8}(/[a-z0-9.-]{1,8}){1,3}".prop_map(Host::Cloak),
+        ]
+    }
+
+    fn arb_prefix() -> impl Strategy<Value = Option<Prefix>> {
+        let hostname = "[a-z0-9]([a-z0-9-]{0,8}[a-z0-9])?(\\.[a-z0-9]([a-z0-9-]{0,8}[a-z0-9])?){1,3}";
+        let nickname = "[a-zA-Z\\[\\]\\\\`_^{|}][a-zA-Z0-9\\[\\]\\\\`_^{|}-]{0,15}";
+        let user = "~?[a-zA-Z0-9_.-]{1,10}";
+        prop_oneof![
+            Just(None),
+            hostname.prop_map(|s| Some(Prefix::Server(s))),
+            (nickname, prop::option::of((prop::option::of(user), arb_host()))).prop_map(|(n, uh)| {
+                let (user, host) = match uh {
+                    Some((u, h)) => (u, Some(h)),
+                    None => (None, None),
+                };
+                Some(Prefix::Nickname(Nickname { nickname: n, user, host }))
+            }),
+        ]
+    }
+
+    fn arb_command() -> impl Strategy<Value = CommandCode> {
+        prop_oneof![
+            (0u16..1000).prop_map(|n| CommandCode::Numeric(n.into())),
+            "[a-z]{1,10}".prop_map(CommandCode::Generic),
+            prop::sample::select(vec![
+                CommandCode::PrivMsg,
+                CommandCode::Notice,
+                CommandCode::Nick,
+                CommandCode::Join,
+                CommandCode::Part,
+                CommandCode::Quit,
+                CommandCode::Mode,
+                CommandCode::Ping,
+                CommandCode::Error,
+                CommandCode::Kick,
+                CommandCode::Cap,
+                CommandCode::Authenticate,
+            ]),
+        ]
+    }
+
+    fn arb_params() -> impl Strategy<Value = Vec<String>> {
+        let middle = "[^\0\r\n :][^\0\r\n ]{0,12}";
+        let trailing = "[^\0\r\n]{0,32}";
+        (prop::collection::vec(middle, 0..14), prop::option::of(trailing)).prop_map(|(mut m, t)| {
+            m.extend(t);
+            m
+        })
+    }
+
+    fn arb_message() -> impl Strategy<Value = Message> {
+        (arb_tags(), arb_prefix(), arb_command(), arb_params()).prop_map(|(tags, prefix, command, params)| {
+            Message {
+                tags,
+                prefix,
+                command,
+                params,
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(m in arb_message()) {
+            let line = m.encode().unwrap();
+            let (rest, parsed) = crate::parse(line.as_bytes()).unwrap();
+            prop_assert!(rest.is_empty());
+            prop_assert_eq!(parsed, m);
+        }
+
+        #[test]
+        fn parsed_lines_reencode(m in arb_message()) {
+            let line = m.encode().unwrap();
+            let (_, parsed) = crate::parse(line.as_bytes()).unwrap();
+            prop_assert_eq!(parsed.encode().unwrap(), line);
+        }
+    }
+}
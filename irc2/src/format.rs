@@ -0,0 +1,478 @@
+//! mIRC style text formatting, see https://modern.ircdocs.horse/formatting.html
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const RESET: char = '\x0f';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1d';
+const STRIKETHROUGH: char = '\x1e';
+const UNDERLINE: char = '\x1f';
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Color {
+    White,
+    Black,
+    Blue,
+    Green,
+    Red,
+    Brown,
+    Magenta,
+    Orange,
+    Yellow,
+    LightGreen,
+    Cyan,
+    LightCyan,
+    LightBlue,
+    Pink,
+    Grey,
+    LightGrey,
+    /// The extended colours 16 to 98
+    Extended(u8),
+    /// Colour 99, the client's default colour
+    Default,
+    Rgb(u8, u8, u8),
+}
+
+const PALETTE: [Color; 16] = [
+    Color::White,
+    Color::Black,
+    Color::Blue,
+    Color::Green,
+    Color::Red,
+    Color::Brown,
+    Color::Magenta,
+    Color::Orange,
+    Color::Yellow,
+    Color::LightGreen,
+    Color::Cyan,
+    Color::LightCyan,
+    Color::LightBlue,
+    Color::Pink,
+    Color::Grey,
+    Color::LightGrey,
+];
+
+impl Color {
+    fn from_code(n: u8) -> Self {
+        match n {
+            0..=15 => PALETTE[n as usize],
+            16..=98 => Color::Extended(n),
+            _ => Color::Default,
+        }
+    }
+
+    /// The colour code as used after `\x03`, `None` for RGB colours
+    fn code(&self) -> Option<u8> {
+        match self {
+            Color::Extended(n) => Some(*n),
+            Color::Default => Some(99),
+            Color::Rgb(..) => None,
+            c => PALETTE.iter().position(|x| x == c).map(|x| x as u8),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub monospace: bool,
+    pub reverse: bool,
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+}
+
+impl Style {
+    pub fn bold() -> Self {
+        Style {
+            bold: true,
+            ..Style::default()
+        }
+    }
+
+    pub fn color(fg: Color) -> Self {
+        Style {
+            fg: Some(fg),
+            ..Style::default()
+        }
+    }
+}
+
+/// A piece of text with a single style
+#[derive(Debug, PartialEq, Clone)]
+pub struct Span<'a> {
+    pub style: Style,
+    pub text: &'a str,
+}
+
+impl<'a> Span<'a> {
+    pub fn new(style: Style, text: &'a str) -> Self {
+        Span { style, text }
+    }
+
+    pub fn plain(text: &'a str) -> Self {
+        Span::new(Style::default(), text)
+    }
+}
+
+// Up to two digits of a colour code
+fn color_code(s: &str) -> Option<(u8, &str)> {
+    let len = s.bytes().take(2).take_while(|c| c.is_ascii_digit()).count();
+    if len == 0 {
+        return None;
+    }
+    Some((s[..len].parse().ok()?, &s[len..]))
+}
+
+fn hex_color(s: &str) -> Option<(Color, &str)> {
+    let hex = s.get(..6)?;
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let c = |i| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((Color::Rgb(c(0)?, c(2)?, c(4)?), &s[6..]))
+}
+
+// Parse the arguments of a colour code, returns the new fg/bg and the remaining text. Without
+// any valid foreground, both colours are reset.
+fn colors<'a>(
+    s: &'a str,
+    parse: impl Fn(&'a str) -> Option<(Color, &'a str)>,
+    style: &mut Style,
+) -> &'a str {
+    match parse(s) {
+        Some((fg, rest)) => {
+            style.fg = Some(fg);
+            // A comma is only part of the code if a valid background follows it
+            match rest.strip_prefix(',').and_then(&parse) {
+                Some((bg, rest)) => {
+                    style.bg = Some(bg);
+                    rest
+                }
+                None => rest,
+            }
+        }
+        None => {
+            style.fg = None;
+            style.bg = None;
+            s
+        }
+    }
+}
+
+/// Split a text into its styled spans, dropping all formatting codes
+pub fn parse(text: &str) -> Vec<Span<'_>> {
+    let mut spans = Vec::new();
+    let mut style = Style::default();
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c| {
+                [BOLD, COLOR, HEX_COLOR, RESET, MONOSPACE, REVERSE, ITALIC, STRIKETHROUGH, UNDERLINE].contains(&c)
+            })
+            .unwrap_or(rest.len());
+
+        if end > 0 {
+            spans.push(Span::new(style, &rest[..end]));
+        }
+
+        let mut chars = rest[end..].chars();
+        rest = match chars.next() {
+            Some(BOLD) => {
+                style.bold = !style.bold;
+                chars.as_str()
+            }
+            Some(ITALIC) => {
+                style.italic = !style.italic;
+                chars.as_str()
+            }
+            Some(UNDERLINE) => {
+                style.underline = !style.underline;
+                chars.as_str()
+            }
+            Some(STRIKETHROUGH) => {
+                style.strikethrough = !style.strikethrough;
+                chars.as_str()
+            }
+            Some(MONOSPACE) => {
+                style.monospace = !style.monospace;
+                chars.as_str()
+            }
+            Some(REVERSE) => {
+                style.reverse = !style.reverse;
+                chars.as_str()
+            }
+            Some(RESET) => {
+                style = Style::default();
+                chars.as_str()
+            }
+            Some(COLOR) => colors(
+                chars.as_str(),
+                |s| color_code(s).map(|(c, r)| (Color::from_code(c), r)),
+                &mut style,
+            ),
+            Some(HEX_COLOR) => colors(chars.as_str(), hex_color, &mut style),
+            _ => "",
+        };
+    }
+
+    spans
+}
+
+/// Remove all formatting codes from a text
+pub fn strip(text: &str) -> String {
+    parse(text).iter().map(|s| s.text).collect()
+}
+
+// A single code for both colours, which have to be both RGB or both from the palette
+fn push_code(r: &mut String, fg: Color, bg: Option<Color>) {
+    match (fg, bg) {
+        (Color::Rgb(fr, fg, fb), bg) => {
+            r.push(HEX_COLOR);
+            r.push_str(&format!("{:02X}{:02X}{:02X}", fr, fg, fb));
+            if let Some(Color::Rgb(br, bg, bb)) = bg {
+                r.push_str(&format!(",{:02X}{:02X}{:02X}", br, bg, bb));
+            }
+        }
+        (fg, bg) => {
+            // Always use two digits, the text might start with a digit itself
+            r.push(COLOR);
+            r.push_str(&format!("{:02}", fg.code().unwrap_or(99)));
+            if let Some(bg) = bg.and_then(|c| c.code()) {
+                r.push_str(&format!(",{:02}", bg));
+            }
+        }
+    }
+}
+
+// A background can only be set together with a foreground, the default colour is used if there
+// is none. An RGB background with a palette foreground, or the other way around, goes first with
+// a stand-in foreground, which the second code replaces.
+fn push_colors(r: &mut String, style: &Style) {
+    let fg = style.fg.unwrap_or(Color::Default);
+    match (fg, style.bg) {
+        (Color::Rgb(..), Some(bg)) if bg.code().is_some() => {
+            push_code(r, Color::Default, Some(bg));
+            push_code(r, fg, None);
+        }
+        (fg, Some(bg @ Color::Rgb(..))) if fg.code().is_some() => {
+            push_code(r, Color::Rgb(0, 0, 0), Some(bg));
+            push_code(r, fg, None);
+        }
+        (fg, bg) => push_code(r, fg, bg),
+    }
+}
+
+/// Render styled spans to text with formatting codes
+pub fn render(spans: &[Span]) -> String {
+    let mut r = String::new();
+    let mut current = Style::default();
+
+    for span in spans.iter().filter(|s| !s.text.is_empty()) {
+        let style = span.style;
+
+        if style != current {
+            // Colours can be changed but not removed individually, start over in that case. A bare
+            // \x03 would reset them, but not if the text starts with a digit.
+            if (style.fg.is_none() && current.fg.is_some()) || (style.bg.is_none() && current.bg.is_some()) {
+                r.push(RESET);
+                current = Style::default();
+            }
+
+            for (on, was, code) in [
+                (style.bold, current.bold, BOLD),
+                (style.italic, current.italic, ITALIC),
+                (style.underline, current.underline, UNDERLINE),
+                (style.strikethrough, current.strikethrough, STRIKETHROUGH),
+                (style.monospace, current.monospace, MONOSPACE),
+                (style.reverse, current.reverse, REVERSE),
+            ] {
+                if on != was {
+                    r.push(code);
+                }
+            }
+
+            if (style.fg.is_some() || style.bg.is_some()) && (style.fg != current.fg || style.bg != current.bg) {
+                push_colors(&mut r, &style);
+            }
+
+            current = style;
+        }
+
+        r.push_str(span.text);
+    }
+
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(spans: Vec<Span>) -> Vec<(Style, String)> {
+        let mut r: Vec<(Style, String)> = Vec::new();
+        for s in spans.into_iter().filter(|s| !s.text.is_empty()) {
+            match r.last_mut() {
+                Some((style, text)) if *style == s.style => text.push_str(s.text),
+                _ => r.push((s.style, s.text.to_string())),
+            }
+        }
+        r
+    }
+
+    #[test]
+    fn nickserv() {
+        let text = "identify via \x02/msg NickServ identify <password>\x02.";
+        assert_eq!(
+            parse(text),
+            vec![
+                Span::plain("identify via "),
+                Span::new(Style::bold(), "/msg NickServ identify <password>"),
+                Span::plain("."),
+            ]
+        );
+        assert_eq!(strip(text), "identify via /msg NickServ identify <password>.");
+    }
+
+    #[test]
+    fn colors() {
+        let spans = parse("\x034red\x03,5comma\x0312,01blue on black\x03\x0f plain");
+        assert_eq!(
+            spans,
+            vec![
+                Span::new(Style::color(Color::Red), "red"),
+                Span::plain(",5comma"),
+                Span::new(
+                    Style {
+                        fg: Some(Color::LightBlue),
+                        bg: Some(Color::Black),
+                        ..Style::default()
+                    },
+                    "blue on black"
+                ),
+                Span::plain(" plain"),
+            ]
+        );
+
+        assert_eq!(parse("\x03123")[0], Span::new(Style::color(Color::LightBlue), "3"));
+        assert_eq!(parse("\x0342x")[0], Span::new(Style::color(Color::Extended(42)), "x"));
+        assert_eq!(parse("\x0399x")[0], Span::new(Style::color(Color::Default), "x"));
+        assert_eq!(
+            parse("\x04FF8000,000000x")[0],
+            Span::new(
+                Style {
+                    fg: Some(Color::Rgb(255, 128, 0)),
+                    bg: Some(Color::Rgb(0, 0, 0)),
+                    ..Style::default()
+                },
+                "x"
+            )
+        );
+        assert_eq!(strip("\x04FF8000,00000x"), ",00000x");
+    }
+
+    #[test]
+    fn strip_all() {
+        assert_eq!(strip("\x02\x1d\x1f\x1e\x11\x16\x0f"), "");
+        assert_eq!(strip("https://example.org/\x02"), "https://example.org/");
+        assert_eq!(strip("\x0304,08warn\x03ing"), "warning");
+        assert_eq!(strip("ümläüte"), "ümläüte");
+    }
+
+    #[test]
+    fn render_roundtrip() {
+        let spans = vec![
+            Span::plain("plain "),
+            Span::new(Style::bold(), "bold "),
+            Span::new(
+                Style {
+                    bold: true,
+                    italic: true,
+                    fg: Some(Color::Green),
+                    ..Style::default()
+                },
+                "1 green"
+            ),
+            Span::new(
+                Style {
+                    italic: true,
+                    fg: Some(Color::Default),
+                    bg: Some(Color::Yellow),
+                    ..Style::default()
+                },
+                "on yellow"
+            ),
+            Span::new(Style::color(Color::Rgb(1, 2, 3)), "rgb"),
+            Span::new(Style::default(), ", plain"),
+            Span::new(Style::default(), " again"),
+            Span::new(
+                Style {
+                    underline: true,
+                    strikethrough: true,
+                    monospace: true,
+                    reverse: true,
+                    ..Style::default()
+                },
+                "all"
+            ),
+        ];
+
+        let text = render(&spans);
+        assert_eq!(normalized(parse(&text)), normalized(spans));
+        assert_eq!(render(&[Span::new(Style::bold(), "x")]), "\x02x");
+        assert_eq!(render(&[Span::new(Style::color(Color::Red), "1")]), "\x03041");
+        assert_eq!(
+            render(&[
+                Span::new(Style::color(Color::Red), "red"),
+                Span::plain("1"),
+            ]),
+            "\x0304red\x0f1"
+        );
+
+        let bg_only = Style {
+            bg: Some(Color::Yellow),
+            ..Style::default()
+        };
+        assert_eq!(render(&[Span::new(bg_only, "x")]), "\x0399,08x");
+    }
+
+    #[test]
+    fn render_roundtrip_mixed_colors() {
+        let rgb_on_palette = Style {
+            fg: Some(Color::Rgb(255, 128, 0)),
+            bg: Some(Color::Blue),
+            ..Style::default()
+        };
+        let palette_on_rgb = Style {
+            fg: Some(Color::Red),
+            bg: Some(Color::Rgb(0, 0, 128)),
+            ..Style::default()
+        };
+        let default_on_rgb = Style {
+            fg: Some(Color::Default),
+            bg: Some(Color::Rgb(1, 2, 3)),
+            ..Style::default()
+        };
+
+        for style in [rgb_on_palette, palette_on_rgb, default_on_rgb] {
+            let spans = vec![Span::new(style, "12 mixed"), Span::plain(" plain")];
+            assert_eq!(normalized(parse(&render(&spans))), normalized(spans));
+        }
+
+        let spans = vec![
+            Span::new(rgb_on_palette, "a"),
+            Span::new(palette_on_rgb, "b"),
+            Span::new(Style::color(Color::Rgb(0, 0, 128)), "c"),
+            Span::new(rgb_on_palette, "d"),
+        ];
+        assert_eq!(normalized(parse(&render(&spans))), normalized(spans));
+
+        assert_eq!(render(&[Span::new(rgb_on_palette, "x")]), "\x0399,02\x04FF8000x");
+        assert_eq!(render(&[Span::new(palette_on_rgb, "x")]), "\x04000000,000080\x0304x");
+    }
+}
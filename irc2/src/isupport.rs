@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+
+use tracing::warn;
+
+use crate::casemap::{CaseMapping, Channel, Nick, Target};
+use crate::command::{CommandCode, Numeric};
+use crate::Message;
+
+/// Channel mode classes as announced by CHANMODES
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChanModes {
+    /// Modes that add or remove an address to or from a list, always take a parameter
+    pub a: String,
+    /// Modes that change a setting, always take a parameter
+    pub b: String,
+    /// Modes that change a setting, only take a parameter when set
+    pub c: String,
+    /// Modes that change a setting, never take a parameter
+    pub d: String,
+}
+
+impl Default for ChanModes {
+    // rfc2811.txt:219
+    fn default() -> Self {
+        ChanModes {
+            a: "beI".to_string(),
+            b: "k".to_string(),
+            c: "l".to_string(),
+            d: "aimnqpsrt".to_string(),
+        }
+    }
+}
+
+/// The server's RPL_ISUPPORT (005) tokens, see https://modern.ircdocs.horse/#rplisupport-005
+///
+/// Well known tokens are parsed, all tokens are kept in `tokens` as they were sent.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ISupport {
+    pub casemapping: CaseMapping,
+    pub chantypes: String,
+    /// Channel membership prefixes as (mode, prefix), highest first
+    pub prefix: Vec<(char, char)>,
+    pub chanmodes: ChanModes,
+    pub nicklen: Option<usize>,
+    /// Maximum number of targets per command, `None` for no limit
+    pub targmax: BTreeMap<String, Option<usize>>,
+    pub linelen: usize,
+    pub network: Option<String>,
+    pub tokens: BTreeMap<String, Option<String>>,
+}
+
+impl Default for ISupport {
+    fn default() -> Self {
+        ISupport {
+            casemapping: CaseMapping::default(),
+            chantypes: "#&".to_string(),
+            prefix: vec![('o', '@'), ('v', '+')],
+            chanmodes: ChanModes::default(),
+            // rfc2812.txt:372
+            nicklen: Some(9),
+            targmax: BTreeMap::new(),
+            // rfc2812.txt:220
+            linelen: 512,
+            network: None,
+            tokens: BTreeMap::new(),
+        }
+    }
+}
+
+// Values may contain \xHH escapes
+fn unescape(v: &str) -> String {
+    let mut r = Vec::with_capacity(v.len());
+    let b = v.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'\\' && b.get(i + 1) == Some(&b'x') {
+            if let Some(c) = v.get(i + 2..i + 4).and_then(|x| u8::from_str_radix(x, 16).ok()) {
+                r.push(c);
+                i += 4;
+                continue;
+            }
+        }
+        r.push(b[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&r).to_string()
+}
+
+impl ISupport {
+    /// Update from a RPL_ISUPPORT message, other messages are ignored
+    pub fn update(&mut self, msg: &Message) {
+        if msg.command != CommandCode::Numeric(Numeric::RplISupport) || msg.params.len() < 2 {
+            return;
+        }
+
+        // The first param is our nick, the last one is the human readable "are supported by ..."
+        for token in &msg.params[1..msg.params.len() - 1] {
+            if let Some(name) = token.strip_prefix('-') {
+                self.tokens.remove(name);
+                self.reset(name);
+                continue;
+            }
+
+            let (name, value) = match token.split_once('=') {
+                Some((n, v)) => (n, Some(unescape(v))),
+                None => (token.as_str(), None),
+            };
+
+            self.set(name, value.as_deref());
+            self.tokens.insert(name.to_string(), value);
+        }
+    }
+
+    fn reset(&mut self, name: &str) {
+        let default = ISupport::default();
+        match name {
+            "CASEMAPPING" => self.casemapping = default.casemapping,
+            "CHANTYPES" => self.chantypes = default.chantypes,
+            "PREFIX" => self.prefix = default.prefix,
+            "CHANMODES" => self.chanmodes = default.chanmodes,
+            "NICKLEN" => self.nicklen = default.nicklen,
+            "TARGMAX" => self.targmax = default.targmax,
+            "LINELEN" => self.linelen = default.linelen,
+            "NETWORK" => self.network = default.network,
+            _ => (),
+        }
+    }
+
+    fn set(&mut self, name: &str, value: Option<&str>) {
+        let value = value.unwrap_or_default();
+        match name {
+            "CASEMAPPING" => match CaseMapping::from_name(value) {
+                Some(c) => self.casemapping = c,
+                None => warn!("Unknown CASEMAPPING {}, keeping {:?}", value, self.casemapping),
+            },
+            "CHANTYPES" => self.chantypes = value.to_string(),
+            // PREFIX=(ov)@+
+            "PREFIX" => {
+                self.prefix = match value.strip_prefix('(').and_then(|v| v.split_once(')')) {
+                    Some((modes, prefixes)) if modes.chars().count() == prefixes.chars().count() => {
+                        modes.chars().zip(prefixes.chars()).collect()
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            // CHANMODES=A,B,C,D[,...]
+            "CHANMODES" => {
+                let mut classes = value.split(',').map(String::from);
+                self.chanmodes = ChanModes {
+                    a: classes.next().unwrap_or_default(),
+                    b: classes.next().unwrap_or_default(),
+                    c: classes.next().unwrap_or_default(),
+                    d: classes.next().unwrap_or_default(),
+                };
+            }
+            "NICKLEN" => self.nicklen = value.parse().ok(),
+            // TARGMAX=PRIVMSG:4,NOTICE:4,JOIN:
+            "TARGMAX" => {
+                self.targmax = value
+                    .split(',')
+                    .filter_map(|t| t.split_once(':'))
+                    .map(|(cmd, n)| (cmd.to_ascii_uppercase(), n.parse().ok()))
+                    .collect()
+            }
+            "LINELEN" => self.linelen = value.parse().unwrap_or(self.linelen),
+            "NETWORK" => self.network = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    pub fn is_channel(&self, name: &str) -> bool {
+        name.chars().next().map(|c| self.chantypes.contains(c)).unwrap_or(false)
+    }
+
+    /// The maximum number of targets for a command, `None` if there is no known limit
+    pub fn max_targets(&self, command: &str) -> Option<usize> {
+        self.targmax.get(&command.to_ascii_uppercase()).copied().flatten()
+    }
+
+    /// The membership prefix symbol for a channel mode, e.g. '@' for 'o'
+    pub fn prefix_for_mode(&self, mode: char) -> Option<char> {
+        self.prefix.iter().find(|(m, _)| *m == mode).map(|(_, p)| *p)
+    }
+
+    /// The channel mode for a membership prefix symbol, e.g. 'o' for '@'
+    pub fn mode_for_prefix(&self, prefix: char) -> Option<char> {
+        self.prefix.iter().find(|(_, p)| *p == prefix).map(|(m, _)| *m)
+    }
+
+    pub fn nick(&self, name: &str) -> Nick {
+        Nick::new(name, self.casemapping)
+    }
+
+    pub fn channel(&self, name: &str) -> Channel {
+        Channel::new(name, self.casemapping)
+    }
+
+    pub fn target(&self, name: &str) -> Target {
+        if self.is_channel(name) {
+            Target::Channel(self.channel(name))
+        } else {
+            Target::Nick(self.nick(name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn isupport(lines: &[&str]) -> ISupport {
+        let mut i = ISupport::default();
+        for l in lines {
+            let (_, msg) = crate::parse(format!("{}\r\n", l).as_bytes()).unwrap();
+            i.update(&msg);
+        }
+        i
+    }
+
+    #[test]
+    fn libera() {
+        let i = isupport(&[
+            ":zinc.libera.chat 005 ZeBot CALLERID=g WHOX ETRACE FNC SAFELIST ELIST=CMNTU KNOCK MONITOR=100 CHANTYPES=# EXCEPTS INVEX CHANMODES=eIbq,k,flj,CFLMPQRSTcgimnprstuz :are supported by this server",
+            ":zinc.libera.chat 005 ZeBot CHANLIMIT=#:250 PREFIX=(ov)@+ MAXLIST=bqeI:100 MODES=4 NETWORK=Libera.Chat STATUSMSG=@+ CASEMAPPING=rfc1459 NICKLEN=16 MAXNICKLEN=16 CHANNELLEN=50 TOPICLEN=390 DEAF=D :are supported by this server",
+            ":zinc.libera.chat 005 ZeBot TARGMAX=NAMES:1,LIST:1,KICK:1,WHOIS:1,PRIVMSG:4,NOTICE:4,ACCEPT:,MONITOR: EXTBAN=$,ajrxz :are supported by this server",
+        ]);
+
+        assert_eq!(i.casemapping, CaseMapping::Rfc1459);
+        assert_eq!(i.chantypes, "#");
+        assert_eq!(i.prefix, vec![('o', '@'), ('v', '+')]);
+        assert_eq!(i.chanmodes.a, "eIbq");
+        assert_eq!(i.chanmodes.b, "k");
+        assert_eq!(i.chanmodes.c, "flj");
+        assert_eq!(i.chanmodes.d, "CFLMPQRSTcgimnprstuz");
+        assert_eq!(i.nicklen, Some(16));
+        assert_eq!(i.network.as_deref(), Some("Libera.Chat"));
+        assert_eq!(i.max_targets("privmsg"), Some(4));
+        assert_eq!(i.max_targets("MONITOR"), None);
+        assert_eq!(i.linelen, 512);
+        assert_eq!(i.tokens.get("WHOX"), Some(&None));
+        assert_eq!(i.tokens.get("MONITOR"), Some(&Some("100".to_string())));
+
+        assert!(i.is_channel("#zebot-test"));
+        assert!(!i.is_channel("&local"));
+        assert_eq!(i.target("#ZeBot[]"), Target::Channel(Channel::new("#zebot{}", CaseMapping::Rfc1459)));
+        assert_eq!(i.target("ZeBot"), Target::Nick(Nick::new("zebot", CaseMapping::Rfc1459)));
+    }
+
+    #[test]
+    fn escapes_and_negation() {
+        let i = isupport(&[
+            ":irc.example.com 005 ZeBot NETWORK=Example\\x20Net CASEMAPPING=ascii LINELEN=2048 PREFIX=(qaohv)~&@%+ :are supported by this server",
+            ":irc.example.com 005 ZeBot -CASEMAPPING -NETWORK :are supported by this server",
+        ]);
+
+        assert_eq!(i.casemapping, CaseMapping::Rfc1459);
+        assert_eq!(i.network, None);
+        assert!(!i.tokens.contains_key("NETWORK"));
+        assert_eq!(i.linelen, 2048);
+        assert_eq!(i.prefix_for_mode('h'), Some('%'));
+        assert_eq!(i.mode_for_prefix('~'), Some('q'));
+    }
+
+    #[test]
+    fn response_destination() {
+        let i = ISupport::default();
+        let joined = vec!["#zebot-test[m]".to_string()];
+
+        let (_, msg) = crate::parse(b":fritschy!~fritschy@localhost PRIVMSG #ZeBot-Test{M} :moep\r\n").unwrap();
+        assert_eq!(msg.get_reponse_destination(&joined, &i), "#ZeBot-Test{M}");
+
+        let (_, msg) = crate::parse(b":fritschy!~fritschy@localhost PRIVMSG ZeBot :moep\r\n").unwrap();
+        assert_eq!(msg.get_reponse_destination(&joined, &i), "fritschy");
+    }
+}
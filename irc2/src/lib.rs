@@ -1,11 +1,39 @@
-use nom::lib::std::fmt::Display;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
-use tracing::error as log_error;
+use nom::lib::std::fmt::Display;
 
 mod parser;
+mod encoder;
+mod builder;
+pub mod cap;
+pub mod casemap;
+pub mod channel;
+pub mod charset;
+pub mod codec;
 pub mod command;
+pub mod ctcp;
+pub mod format;
+pub mod isupport;
+pub mod mask;
+pub mod mode;
+pub mod nick;
+pub mod sasl;
+mod message_ref;
 
-pub use parser::parse;
+pub use parser::{parse, parse_line};
+pub use encoder::EncodeError;
+pub use mask::HostMask;
+pub use casemap::{CaseMapping, Channel, Nick, Target};
+pub use isupport::ISupport;
+pub use cap::Capabilities;
+pub use charset::Charsets;
+pub use codec::{IrcCodec, LineCodec};
+pub use channel::{ChannelState, Channels};
+pub use mode::{Mode, ModeChange};
+pub use message_ref::{MessageRef, PrefixRef};
+pub use sasl::{Mechanism, Sasl, SaslStatus};
+pub use nick::{Nicks, NoNickLeft};
 
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
 pub enum Prefix {
@@ -22,13 +50,55 @@ impl Display for Prefix {
     }
 }
 
+// rfc2812.txt:367
+#[derive(Debug, PartialEq, Clone, Eq, Hash)]
+pub enum Host {
+    Hostname(String),
+    Ip4(Ipv4Addr),
+    Ip6(Ipv6Addr),
+    /// Anything that is neither a hostname nor an address, e.g. 'user/foo'
+    Cloak(String),
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            Host::Hostname(h) | Host::Cloak(h) => write!(f, "{}", h),
+            Host::Ip4(a) => write!(f, "{}", a),
+            Host::Ip6(a) => write!(f, "{}", a),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
 pub struct Nickname {
     nickname: String,
     // XXX: in rfc2812 this should actually be an host: Option<(Option<user>, host)>
     //      but I really dont want to be it this way...
     user: Option<String>,
-    host: Option<String>,
+    host: Option<Host>,
+}
+
+impl Nickname {
+    pub fn new(nickname: &str, user: Option<&str>, host: Option<Host>) -> Self {
+        Nickname {
+            nickname: nickname.to_string(),
+            user: user.map(String::from),
+            host,
+        }
+    }
+
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn host(&self) -> Option<&Host> {
+        self.host.as_ref()
+    }
 }
 
 impl Display for Nickname {
@@ -44,19 +114,72 @@ impl Display for Nickname {
     }
 }
 
+/// IRCv3 message tags, see https://ircv3.net/specs/extensions/message-tags
+///
+/// Values are stored unescaped, a tag without a value is stored with an empty value, as the
+/// spec considers `key` and `key=` to be equivalent. Client-only tags (those with a leading `+`)
+/// are kept in their own map, their keys are stored without the `+`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Tags {
+    pub server: BTreeMap<String, String>,
+    pub client: BTreeMap<String, String>,
+}
+
+impl Tags {
+    pub fn is_empty(&self) -> bool {
+        self.server.is_empty() && self.client.is_empty()
+    }
+
+    /// Get a tag by its key as it appears on the wire, i.e. client-only tags need the `+`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        if let Some(key) = key.strip_prefix('+') {
+            self.client.get(key)
+        } else {
+            self.server.get(key)
+        }
+        .map(String::as_str)
+    }
+
+    /// Insert a tag by its key as it appears on the wire, later tags replace earlier ones.
+    pub fn insert(&mut self, key: &str, value: &str) {
+        if let Some(key) = key.strip_prefix('+') {
+            self.client.insert(key.to_string(), value.to_string());
+        } else {
+            self.server.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    /// Iterate over all tags with their wire keys, server tags first.
+    pub fn iter(&self) -> impl Iterator<Item = (String, &str)> {
+        self.server
+            .iter()
+            .map(|(k, v)| (k.clone(), v.as_str()))
+            .chain(self.client.iter().map(|(k, v)| (format!("+{}", k), v.as_str())))
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Message {
+    pub tags: Tags,
     pub prefix: Option<Prefix>,
     pub command: command::CommandCode,
     pub params: Vec<String>,
 }
 
+/// This is meant for logging, use `Message::encode()` to get the wire format.
 impl Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        if !self.tags.is_empty() {
+            write!(f, "T:")?;
+            for (k, v) in self.tags.iter() {
+                write!(f, "{}={};", k, v)?;
+            }
+            write!(f, " ")?;
+        }
         if let Some(p) = &self.prefix {
             write!(f, "P:{} ", p)?;
         }
-        write!(f, "C:{} ", self.command.to_string())?;
+        write!(f, "C:{} ", self.command)?;
         if !self.params.is_empty() {
             for p in &self.params {
                 write!(f, "'{}' ", p)?;
@@ -67,14 +190,33 @@ impl Display for Message {
 }
 
 impl Message {
-    pub fn get_reponse_destination(&self, channels: &[String]) -> String {
-        if channels.iter().any(|x| x == &self.params[0]) {
+    pub fn new(command: command::CommandCode, params: Vec<String>) -> Self {
+        Message {
+            tags: Tags::default(),
+            prefix: None,
+            command,
+            params,
+        }
+    }
+
+    pub fn get_reponse_destination(&self, channels: &[String], isupport: &ISupport) -> String {
+        if isupport.is_channel(&self.params[0])
+            && channels.iter().any(|x| isupport.casemapping.eq(x, &self.params[0]))
+        {
             self.params[0].clone()
         } else {
             self.get_nick()
         }
     }
 
+    /// The nickname this message originates from, if it was sent by a user
+    pub fn nickname(&self) -> Option<&Nickname> {
+        match &self.prefix {
+            Some(Prefix::Nickname(n)) => Some(n),
+            _ => None,
+        }
+    }
+
     pub fn get_nick(&self) -> String {
         if let Some(Prefix::Nickname(Nickname{nickname, ..})) = &self.prefix {
             nickname.clone()
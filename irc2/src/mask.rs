@@ -0,0 +1,227 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::casemap::CaseMapping;
+use crate::Nickname;
+
+/// A hostmask like `*!*@*.example.org`, matched according to rfc2812.txt:456 (section 2.5)
+///
+/// Masks are matched case insensitively, using the rfc1459 case mapping unless told otherwise.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct HostMask {
+    mask: String,
+}
+
+impl HostMask {
+    /// Create a mask, missing parts are filled in like servers do for bans, i.e. `foo` becomes
+    /// `foo!*@*`, `foo@bar` becomes `*!foo@bar` and `foo!bar` becomes `foo!bar@*`.
+    pub fn new(mask: &str) -> Self {
+        let (nick_user, host) = match mask.rsplit_once('@') {
+            Some((nu, h)) => (nu, Some(h)),
+            None => (mask, None),
+        };
+
+        let (nick, user) = match nick_user.split_once('!') {
+            Some((n, u)) => (n, Some(u)),
+            None if host.is_some() => ("*", Some(nick_user)),
+            None => (nick_user, None),
+        };
+
+        let or_any = |x: &str| if x.is_empty() { "*".to_string() } else { x.to_string() };
+
+        HostMask {
+            mask: format!(
+                "{}!{}@{}",
+                or_any(nick),
+                or_any(user.unwrap_or_default()),
+                or_any(host.unwrap_or_default())
+            ),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.mask
+    }
+
+    pub fn matches(&self, n: &Nickname) -> bool {
+        self.matches_with(n, CaseMapping::default())
+    }
+
+    pub fn matches_with(&self, n: &Nickname, casemapping: CaseMapping) -> bool {
+        let full = format!(
+            "{}!{}@{}",
+            n.nickname(),
+            n.user().unwrap_or_default(),
+            n.host().map(|h| h.to_string()).unwrap_or_default()
+        );
+        wildcard_match_with(&self.mask, &full, casemapping)
+    }
+}
+
+impl FromStr for HostMask {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(HostMask::new(s))
+    }
+}
+
+impl Display for HostMask {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.mask)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Token {
+    Char(char),
+    One,
+    Many,
+}
+
+// rfc2812.txt:466
+fn tokenize(mask: &str, fold: impl Fn(char) -> char) -> Vec<Token> {
+    let mut tokens = Vec::with_capacity(mask.len());
+    let mut chars = mask.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                // Only the wildcards can be escaped, any other backslash is taken literally
+                Some(w @ '*') | Some(w @ '?') => tokens.push(Token::Char(w)),
+                Some(c) => {
+                    tokens.push(Token::Char(fold('\\')));
+                    tokens.push(Token::Char(fold(c)));
+                }
+                None => tokens.push(Token::Char(fold('\\'))),
+            },
+            '?' => tokens.push(Token::One),
+            '*' => {
+                // Consecutive '*' are equivalent to a single one
+                if tokens.last() != Some(&Token::Many) {
+                    tokens.push(Token::Many);
+                }
+            }
+            c => tokens.push(Token::Char(fold(c))),
+        }
+    }
+    tokens
+}
+
+/// Match a string against an IRC wildcard mask, `?` matches exactly one character, `*` any
+/// number of characters. Both can be escaped with a backslash.
+pub fn wildcard_match(mask: &str, s: &str) -> bool {
+    wildcard_match_with(mask, s, CaseMapping::default())
+}
+
+pub fn wildcard_match_with(mask: &str, s: &str, casemapping: CaseMapping) -> bool {
+    let fold = |c| casemapping.fold_char(c);
+    let mask = tokenize(mask, fold);
+    let s = s.chars().map(fold).collect::<Vec<_>>();
+
+    let (mut m, mut i) = (0, 0);
+    // Position of the last '*' in the mask and the position in s it is matched up to
+    let mut backtrack = None;
+
+    while i < s.len() {
+        match mask.get(m) {
+            Some(Token::Many) => {
+                backtrack = Some((m, i));
+                m += 1;
+            }
+            Some(Token::One) => {
+                m += 1;
+                i += 1;
+            }
+            Some(Token::Char(c)) if *c == s[i] => {
+                m += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                // Let the last '*' eat one more character and try again
+                Some((bm, bi)) => {
+                    backtrack = Some((bm, bi + 1));
+                    m = bm + 1;
+                    i = bi + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    mask[m..].iter().all(|t| *t == Token::Many)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Host;
+
+    fn nick(n: &str, u: &str, h: Host) -> Nickname {
+        Nickname::new(n, Some(u), Some(h))
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("a?c", "abc"));
+        assert!(!wildcard_match("a?c", "ac"));
+        assert!(wildcard_match("a*c", "ac"));
+        assert!(wildcard_match("a*c", "abbbc"));
+        assert!(!wildcard_match("a*c", "abbbcd"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("**a**", "bab"));
+        assert!(wildcard_match("*a*b*c", "xxaxxbxxbxxc"));
+        assert!(!wildcard_match("", "a"));
+        assert!(wildcard_match("a\\*c", "a*c"));
+        assert!(!wildcard_match("a\\*c", "abc"));
+        assert!(wildcard_match("a\\?c", "a?c"));
+        assert!(!wildcard_match("a\\?c", "abc"));
+    }
+
+    #[test]
+    fn case_mapping() {
+        assert!(wildcard_match("ZeBot", "zebot"));
+        assert!(wildcard_match("foo[m]", "FOO{m}"));
+        assert!(wildcard_match("foo|bar", "foo\\bar"));
+        assert!(wildcard_match("foo~", "FOO^"));
+        assert!(!wildcard_match_with("foo[m]", "FOO{m}", CaseMapping::Ascii));
+        assert!(wildcard_match_with("foo[m]", "FOO[m]", CaseMapping::Ascii));
+        assert!(!wildcard_match_with("foo~", "FOO^", CaseMapping::StrictRfc1459));
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(HostMask::new("foo").as_str(), "foo!*@*");
+        assert_eq!(HostMask::new("foo@bar").as_str(), "*!foo@bar");
+        assert_eq!(HostMask::new("foo!bar").as_str(), "foo!bar@*");
+        assert_eq!(HostMask::new("*!*@*.example.org").as_str(), "*!*@*.example.org");
+        assert_eq!(HostMask::new("!@").as_str(), "*!*@*");
+    }
+
+    #[test]
+    fn hostmasks() {
+        let fritschy = nick("fritschy", "~fritschy", Host::Hostname("a.b.example.org".to_string()));
+        let cloaked = nick("foo", "~foo", Host::Cloak("user/foo".to_string()));
+        let ip6 = nick("bar", "bar", Host::Ip6("2001:db8::1".parse().unwrap()));
+
+        let m = HostMask::new("*!*@*.example.org");
+        assert!(m.matches(&fritschy));
+        assert!(!m.matches(&cloaked));
+
+        let m = HostMask::new("*!*@user/foo");
+        assert!(m.matches(&cloaked));
+        assert!(!m.matches(&fritschy));
+
+        let m = HostMask::new("*!*@2001:db8::*");
+        assert!(m.matches(&ip6));
+
+        let m = HostMask::new("FRITSCHY");
+        assert!(m.matches(&fritschy));
+
+        let m = HostMask::new("*!~*@*");
+        assert!(m.matches(&fritschy));
+        assert!(m.matches(&cloaked));
+        assert!(!m.matches(&ip6));
+
+        assert!(HostMask::new("ZeBot").matches(&Nickname::new("zebot", None, None)));
+    }
+}
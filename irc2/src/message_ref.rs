@@ -0,0 +1,221 @@
+use std::borrow::Cow;
+
+use nom::combinator::all_consuming;
+
+use crate::command::CommandCode;
+use crate::parser::parsers::{self, RawPrefix};
+use crate::*;
+
+/// A message borrowing from the line it was parsed from.
+///
+/// Parsing does not allocate, tag values are only unescaped when they are looked at and the
+/// prefix host is only classified when converting to a `Message`. Use `to_message()` to get
+/// an owned `Message` when the message needs to outlive the line.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageRef<'a> {
+    line: &'a str,
+    tags: Option<&'a str>,
+    prefix: Option<PrefixRef<'a>>,
+    command: &'a str,
+    params: [&'a str; 15],
+    nparams: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PrefixRef<'a> {
+    Server(&'a str),
+    Nickname {
+        nickname: &'a str,
+        user: Option<&'a str>,
+        host: Option<&'a str>,
+    },
+}
+
+impl PrefixRef<'_> {
+    pub fn to_prefix(&self) -> Prefix {
+        match *self {
+            PrefixRef::Server(s) => Prefix::Server(s.to_string()),
+            PrefixRef::Nickname { nickname, user, host } => Prefix::Nickname(Nickname {
+                nickname: nickname.to_string(),
+                user: user.map(String::from),
+                host: host.map(|h| parsers::classify_host(h.as_bytes())),
+            }),
+        }
+    }
+}
+
+// All parsers stop at ASCII delimiters, so every piece they return starts and ends on a
+// char boundary of the line.
+fn sub<'a>(line: &'a str, part: &[u8]) -> &'a str {
+    let start = part.as_ptr() as usize - line.as_ptr() as usize;
+    &line[start..start + part.len()]
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parse a single line without its line ending
+    pub fn parse(line: &'a str) -> Result<Self, nom::Err<nom::error::Error<&'a [u8]>>> {
+        let (_, raw) = all_consuming(parsers::raw_message)(line.as_bytes())?;
+
+        let mut params = [""; 15];
+        for (dst, p) in params.iter_mut().zip(raw.params.as_slice()) {
+            *dst = sub(line, p);
+        }
+
+        Ok(MessageRef {
+            line,
+            tags: raw.tags.map(|t| sub(line, t)),
+            prefix: raw.prefix.map(|p| match p {
+                RawPrefix::Server(s) => PrefixRef::Server(sub(line, s)),
+                RawPrefix::Nickname { nickname, user, host } => PrefixRef::Nickname {
+                    nickname: sub(line, nickname),
+                    user: user.map(|u| sub(line, u)),
+                    host: host.map(|h| sub(line, h)),
+                },
+            }),
+            command: sub(line, raw.command),
+            params,
+            nparams: raw.params.len,
+        })
+    }
+
+    /// The line this message was parsed from
+    pub fn as_str(&self) -> &'a str {
+        self.line
+    }
+
+    /// Iterate over all tags with their wire keys and unescaped values, in the order they
+    /// appear on the wire. Duplicate keys are not merged.
+    pub fn tags(&self) -> impl Iterator<Item = (&'a str, Cow<'a, str>)> {
+        let line = self.line;
+        self.tags
+            .into_iter()
+            .flat_map(|t| parsers::split_tags(t.as_bytes()))
+            .map(move |(k, v)| (sub(line, k), unescape(sub(line, v))))
+    }
+
+    /// Get a tag by its key as it appears on the wire, the last one wins if there are several
+    pub fn tag(&self, key: &str) -> Option<Cow<'a, str>> {
+        self.tags().filter(|(k, _)| *k == key).last().map(|(_, v)| v)
+    }
+
+    pub fn prefix(&self) -> Option<&PrefixRef<'a>> {
+        self.prefix.as_ref()
+    }
+
+    /// The nickname this message originates from, if it was sent by a user
+    pub fn nickname(&self) -> Option<&'a str> {
+        match self.prefix {
+            Some(PrefixRef::Nickname { nickname, .. }) => Some(nickname),
+            _ => None,
+        }
+    }
+
+    /// The command as it appears on the wire
+    pub fn command_str(&self) -> &'a str {
+        self.command
+    }
+
+    /// Only allocates for commands unknown to `CommandCode`
+    pub fn command(&self) -> CommandCode {
+        self.command.into()
+    }
+
+    pub fn params(&self) -> &[&'a str] {
+        &self.params[..self.nparams]
+    }
+
+    pub fn param(&self, i: usize) -> Option<&'a str> {
+        self.params().get(i).copied()
+    }
+
+    pub fn to_message(&self) -> Message {
+        let mut tags = Tags::default();
+        for (k, v) in self.tags() {
+            tags.insert(k, &v);
+        }
+
+        Message {
+            tags,
+            prefix: self.prefix.map(|p| p.to_prefix()),
+            command: self.command(),
+            params: self.params().iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+fn unescape(v: &str) -> Cow<'_, str> {
+    if v.contains('\\') {
+        Cow::Owned(parsers::unescape_tag_value(v))
+    } else {
+        Cow::Borrowed(v)
+    }
+}
+
+impl From<&MessageRef<'_>> for Message {
+    fn from(m: &MessageRef<'_>) -> Self {
+        m.to_message()
+    }
+}
+
+impl From<MessageRef<'_>> for Message {
+    fn from(m: MessageRef<'_>) -> Self {
+        m.to_message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privmsg() {
+        let msg = MessageRef::parse(":fritschy!~fritschy@localhost PRIVMSG #zebot-test :moep moep").unwrap();
+        assert_eq!(
+            msg.prefix(),
+            Some(&PrefixRef::Nickname { nickname: "fritschy", user: Some("~fritschy"), host: Some("localhost") })
+        );
+        assert_eq!(msg.nickname(), Some("fritschy"));
+        assert_eq!(msg.command(), CommandCode::PrivMsg);
+        assert_eq!(msg.command_str(), "PRIVMSG");
+        assert_eq!(msg.params(), ["#zebot-test", "moep moep"]);
+        assert_eq!(msg.param(2), None);
+    }
+
+    #[test]
+    fn tags() {
+        let msg = MessageRef::parse("@a=1;+b=x\\sy;a=2;c :irc.example.com NOTICE * :hi").unwrap();
+        assert!(matches!(msg.tag("a"), Some(Cow::Borrowed("2"))));
+        assert!(matches!(msg.tag("+b"), Some(Cow::Owned(ref v)) if v == "x y"));
+        assert_eq!(msg.tag("c").as_deref(), Some(""));
+        assert_eq!(msg.tag("b"), None);
+        assert_eq!(msg.tags().count(), 4);
+        assert_eq!(msg.prefix(), Some(&PrefixRef::Server("irc.example.com")));
+    }
+
+    #[test]
+    fn non_ascii() {
+        let msg = MessageRef::parse(":fößchen!~f@localhost PRIVMSG #zebot-test :äöü ß").unwrap_err();
+        assert!(matches!(msg, nom::Err::Error(_)));
+
+        let msg = MessageRef::parse(":fritschy!~f@localhost PRIVMSG #zebot-täst :äöü ß").unwrap();
+        assert_eq!(msg.params(), ["#zebot-täst", "äöü ß"]);
+    }
+
+    #[test]
+    fn same_as_owned() {
+        let lines = include_str!("../testdata/freenode_motd.txt");
+        let extra = [
+            "@time=2021-01-01T12:00:00.000Z;msgid=a\\sb :fritschy!~fritschy@2001:db8::1 PRIVMSG #zebot-test :moep",
+            ":foo!~foo@user/foo JOIN #zebot-test",
+            "PING :irc.example.com",
+            ":irc.example.com 005 ZeBot CHANTYPES=# PREFIX=(ov)@+ :are supported by this server",
+        ];
+
+        for line in lines.lines().chain(extra.iter().copied()) {
+            let owned = parse_line(line.as_bytes()).unwrap();
+            let msg = MessageRef::parse(line).unwrap();
+            assert_eq!(msg.to_message(), owned);
+            assert_eq!(msg.as_str(), line);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt::{Display, Formatter};
+
+use crate::command::{CommandCode, Numeric};
+use crate::isupport::ISupport;
+use crate::Message;
+
+/// A single mode, classified by CHANMODES and PREFIX
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Mode {
+    /// Channel membership, e.g. 'o' or 'v', with the nick it applies to
+    Prefix(char, String),
+    /// Address lists, e.g. 'b', with the mask that is added or removed (CHANMODES type A)
+    List(char, String),
+    /// Settings with a parameter, e.g. 'k' or 'l', the parameter is `None` when a
+    /// CHANMODES type C mode is unset (CHANMODES types B and C)
+    Param(char, Option<String>),
+    /// Settings without a parameter, e.g. 'm', also all user modes (CHANMODES type D)
+    Flag(char),
+}
+
+impl Mode {
+    pub fn mode(&self) -> char {
+        match self {
+            Mode::Prefix(m, _) | Mode::List(m, _) | Mode::Param(m, _) => *m,
+            Mode::Flag(m) => *m,
+        }
+    }
+
+    pub fn param(&self) -> Option<&str> {
+        match self {
+            Mode::Prefix(_, p) | Mode::List(_, p) => Some(p),
+            Mode::Param(_, p) => p.as_deref(),
+            Mode::Flag(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ModeChange {
+    /// Whether the mode is set (`+`) or unset (`-`)
+    pub set: bool,
+    pub mode: Mode,
+}
+
+impl Display for ModeChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", if self.set { '+' } else { '-' }, self.mode.mode())?;
+        if let Some(p) = self.mode.param() {
+            write!(f, " {}", p)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ModeError {
+    /// A mode that needs a parameter was the last one without
+    MissingParam(char),
+    /// The mode string does not start with '+' or '-'
+    NoDirection(String),
+}
+
+impl Display for ModeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModeError::MissingParam(m) => write!(f, "Mode {} is missing its parameter", m),
+            ModeError::NoDirection(s) => write!(f, "Mode string '{}' does not start with + or -", s),
+        }
+    }
+}
+
+impl std::error::Error for ModeError {}
+
+/// Parse a channel mode string and its parameters, e.g. `["+ov-b", "nick1", "nick2", "*!*@bad"]`.
+///
+/// Whether a mode takes a parameter is looked up in PREFIX and CHANMODES, unknown modes are
+/// treated as flags. Extra parameters are ignored.
+pub fn parse_channel_modes<S: AsRef<str>>(params: &[S], isupport: &ISupport) -> Result<Vec<ModeChange>, ModeError> {
+    let mut args = params.iter().skip(1).map(|p| p.as_ref().to_string());
+    parse_modes(params.first().map(AsRef::as_ref).unwrap_or_default(), |set, m| {
+        let cm = &isupport.chanmodes;
+        let mut arg = || args.next().ok_or(ModeError::MissingParam(m));
+        Ok(if isupport.prefix_for_mode(m).is_some() {
+            Mode::Prefix(m, arg()?)
+        } else if cm.a.contains(m) {
+            Mode::List(m, arg()?)
+        } else if cm.b.contains(m) {
+            Mode::Param(m, Some(arg()?))
+        } else if cm.c.contains(m) {
+            Mode::Param(m, if set { Some(arg()?) } else { None })
+        } else {
+            Mode::Flag(m)
+        })
+    })
+}
+
+/// Parse a user mode string, e.g. `+iw`. User modes never take parameters.
+pub fn parse_user_modes(modes: &str) -> Result<Vec<ModeChange>, ModeError> {
+    parse_modes(modes, |_, m| Ok(Mode::Flag(m)))
+}
+
+fn parse_modes(
+    modes: &str,
+    mut mode: impl FnMut(bool, char) -> Result<Mode, ModeError>,
+) -> Result<Vec<ModeChange>, ModeError> {
+    let mut set = match modes.chars().next() {
+        Some('+') => true,
+        Some('-') => false,
+        _ => return Err(ModeError::NoDirection(modes.to_string())),
+    };
+
+    let mut changes = Vec::new();
+    for c in modes.chars() {
+        match c {
+            '+' => set = true,
+            '-' => set = false,
+            m => changes.push(ModeChange { set, mode: mode(set, m)? }),
+        }
+    }
+
+    Ok(changes)
+}
+
+impl Message {
+    /// The target and mode changes of a MODE or RPL_CHANNELMODEIS message, `None` for other
+    /// messages. Targets that are not channels are parsed as user modes.
+    pub fn mode_changes(&self, isupport: &ISupport) -> Option<(&str, Result<Vec<ModeChange>, ModeError>)> {
+        let params = match self.command {
+            CommandCode::Mode => &self.params[..],
+            // The first param is our nick
+            CommandCode::Numeric(Numeric::RplChannelModeIs) => self.params.get(1..)?,
+            _ => return None,
+        };
+
+        let (target, modes) = params.split_first()?;
+        if modes.is_empty() {
+            return None;
+        }
+
+        if isupport.is_channel(target) {
+            Some((target, parse_channel_modes(modes, isupport)))
+        } else {
+            Some((target, parse_user_modes(&modes[0])))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(set: bool, mode: Mode) -> ModeChange {
+        ModeChange { set, mode }
+    }
+
+    #[test]
+    fn channel_modes() {
+        let i = ISupport::default();
+
+        assert_eq!(
+            parse_channel_modes(&["+ov-b", "nick1", "nick2", "*!*@bad"], &i),
+            Ok(vec![
+                change(true, Mode::Prefix('o', "nick1".to_string())),
+                change(true, Mode::Prefix('v', "nick2".to_string())),
+                change(false, Mode::List('b', "*!*@bad".to_string())),
+            ])
+        );
+
+        assert_eq!(
+            parse_channel_modes(&["+kl-l+m", "secret", "42"], &i),
+            Ok(vec![
+                change(true, Mode::Param('k', Some("secret".to_string()))),
+                change(true, Mode::Param('l', Some("42".to_string()))),
+                change(false, Mode::Param('l', None)),
+                change(true, Mode::Flag('m')),
+            ])
+        );
+
+        assert_eq!(parse_channel_modes(&["+o"], &i), Err(ModeError::MissingParam('o')));
+        assert_eq!(parse_channel_modes(&["o", "nick"], &i), Err(ModeError::NoDirection("o".to_string())));
+        assert_eq!(parse_channel_modes::<&str>(&[], &i), Err(ModeError::NoDirection("".to_string())));
+
+        // Unknown modes do not take parameters
+        assert_eq!(
+            parse_channel_modes(&["+Xb", "*!*@bad"], &i),
+            Ok(vec![change(true, Mode::Flag('X')), change(true, Mode::List('b', "*!*@bad".to_string()))])
+        );
+    }
+
+    #[test]
+    fn isupport_modes() {
+        let mut i = ISupport::default();
+        let (_, msg) = crate::parse(b":irc.example.com 005 ZeBot CHANMODES=eIbq,k,flj,CFLMPQRSTcgimnprstuz PREFIX=(qaohv)~&@%+ :are supported by this server\r\n").unwrap();
+        i.update(&msg);
+
+        let (_, msg) = crate::parse(b":ChanServ!ChanServ@services.libera.chat MODE #zebot-test +hq-f+j foo bar 3:5\r\n").unwrap();
+        let (target, changes) = msg.mode_changes(&i).unwrap();
+        assert_eq!(target, "#zebot-test");
+        let changes = changes.unwrap();
+        assert_eq!(changes.iter().map(ToString::to_string).collect::<Vec<_>>(), ["+h foo", "+q bar", "-f", "+j 3:5"]);
+        assert_eq!(changes[1].mode, Mode::Prefix('q', "bar".to_string()));
+    }
+
+    #[test]
+    fn messages() {
+        let i = ISupport::default();
+
+        let (_, msg) = crate::parse(b":ZeBot MODE ZeBot :+iw-x\r\n").unwrap();
+        let (target, changes) = msg.mode_changes(&i).unwrap();
+        assert_eq!(target, "ZeBot");
+        assert_eq!(changes, Ok(vec![change(true, Mode::Flag('i')), change(true, Mode::Flag('w')), change(false, Mode::Flag('x'))]));
+
+        let (_, msg) = crate::parse(b":irc.example.com 324 ZeBot #zebot-test +ntk secret\r\n").unwrap();
+        let (target, changes) = msg.mode_changes(&i).unwrap();
+        assert_eq!(target, "#zebot-test");
+        assert_eq!(changes.unwrap()[2], change(true, Mode::Param('k', Some("secret".to_string()))));
+
+        // A request for the channel modes
+        let (_, msg) = crate::parse(b"MODE #zebot-test\r\n").unwrap();
+        assert!(msg.mode_changes(&i).is_none());
+
+        let (_, msg) = crate::parse(b"PRIVMSG #zebot-test :+o foo\r\n").unwrap();
+        assert!(msg.mode_changes(&i).is_none());
+    }
+}
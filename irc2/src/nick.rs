@@ -0,0 +1,272 @@
+use tracing::{info, warn};
+
+use crate::command::{CommandCode, Numeric};
+use crate::isupport::ISupport;
+use crate::Message;
+
+// How many underscore suffixed nicks to try after the alternates
+const MAX_GENERATED: usize = 8;
+
+/// Every nickname we tried during registration was taken or rejected
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoNickLeft(pub String);
+
+impl std::fmt::Display for NoNickLeft {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "No usable nickname left, the last one tried was '{}'", self.0)
+    }
+}
+
+impl std::error::Error for NoNickLeft {}
+
+/// Keeps track of our own nickname.
+///
+/// While registering it tries the alternates, then the primary nick with '_' appended, when
+/// the server says a nick is taken. Afterwards it follows our NICK changes and takes the
+/// primary nick back when its holder quits or changes nick, watched with MONITOR if the
+/// server supports it. Feed it every message with `update()` and send what it returns.
+#[derive(Debug, Clone)]
+pub struct Nicks {
+    primary: String,
+    alternates: Vec<String>,
+    current: String,
+    /// Nicks rejected during registration
+    attempts: usize,
+    registered: bool,
+    /// The primary nick is on our MONITOR list
+    monitoring: bool,
+}
+
+impl Nicks {
+    pub fn new(primary: &str, alternates: &[&str]) -> Self {
+        Nicks {
+            primary: primary.to_string(),
+            alternates: alternates.iter().map(|a| a.to_string()).collect(),
+            current: primary.to_string(),
+            attempts: 0,
+            registered: false,
+            monitoring: false,
+        }
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    /// The nick we have, or while registering the one we are trying
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    pub fn has_primary(&self, isupport: &ISupport) -> bool {
+        isupport.casemapping.eq(&self.current, &self.primary)
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+
+    /// Start registering with the primary nick, forgetting the previous connection
+    pub fn start(&mut self) -> Message {
+        self.current = self.primary.clone();
+        self.attempts = 0;
+        self.registered = false;
+        self.monitoring = false;
+        Message::nick(&self.primary).unwrap()
+    }
+
+    /// Whether `update()` does anything with messages of this command
+    pub fn tracks(command: &CommandCode) -> bool {
+        matches!(
+            command,
+            CommandCode::Nick
+                | CommandCode::Quit
+                | CommandCode::Numeric(
+                    Numeric::ErrNicknameInUse
+                        | Numeric::ErrNickCollision
+                        | Numeric::ErrUnavailResource
+                        | Numeric::ErrErroneousNickname
+                        | Numeric::RplWelcome
+                        | Numeric::RplISupport
+                        | Numeric::RplMonOffline
+                )
+        )
+    }
+
+    pub fn update(&mut self, msg: &Message, isupport: &ISupport) -> Result<Vec<Message>, NoNickLeft> {
+        let cm = isupport.casemapping;
+        let source = msg.nickname().map(|n| n.nickname());
+        let param = |i: usize| msg.params.get(i).map(String::as_str);
+        let primary = self.primary.clone();
+        let is_primary = |n: &str| cm.eq(n, &primary);
+
+        match &msg.command {
+            // <me> <nick> :<reason>
+            CommandCode::Numeric(
+                Numeric::ErrNicknameInUse
+                | Numeric::ErrNickCollision
+                | Numeric::ErrUnavailResource
+                | Numeric::ErrErroneousNickname,
+            ) if param(1).map(|n| cm.eq(n, &self.current)).unwrap_or(false) => {
+                if self.registered {
+                    // We tried to take the primary nick back
+                    warn!("Can not change nick to {}: {}", param(1).unwrap(), param(2).unwrap_or_default());
+                    return Ok(Vec::new());
+                }
+
+                self.attempts += 1;
+                let next = self.next_nick(isupport).ok_or_else(|| NoNickLeft(self.current.clone()))?;
+                warn!("Nick {} is not available, trying {}", self.current, next);
+                self.current = next;
+                Ok(Message::nick(&self.current).into_iter().collect())
+            }
+
+            // The server tells us which nick we ended up with
+            CommandCode::Numeric(Numeric::RplWelcome) => {
+                if let Some(me) = param(0) {
+                    self.current = me.to_string();
+                }
+                self.registered = true;
+                Ok(Vec::new())
+            }
+
+            // Watch the primary nick, if we did not get it and the server supports MONITOR
+            CommandCode::Numeric(Numeric::RplISupport) => {
+                let monitor = msg.params.iter().any(|p| p == "MONITOR" || p.starts_with("MONITOR="));
+                if monitor && self.registered && !self.monitoring && !self.has_primary(isupport) {
+                    self.monitoring = true;
+                    Ok(Message::monitor_add(&[&self.primary]).into_iter().collect())
+                } else {
+                    Ok(Vec::new())
+                }
+            }
+
+            CommandCode::Nick => match (source, param(0)) {
+                (Some(old), Some(new)) if cm.eq(old, &self.current) => {
+                    info!("Our nick changed from {} to {}", old, new);
+                    self.current = new.to_string();
+                    if self.monitoring && is_primary(new) {
+                        self.monitoring = false;
+                        return Ok(Message::monitor_remove(&[&self.primary]).into_iter().collect());
+                    }
+                    Ok(Vec::new())
+                }
+                (Some(old), Some(new)) if is_primary(old) && !is_primary(new) => Ok(self.regain()),
+                _ => Ok(Vec::new()),
+            },
+
+            CommandCode::Quit => match source {
+                Some(nick) if is_primary(nick) && !self.has_primary(isupport) => Ok(self.regain()),
+                _ => Ok(Vec::new()),
+            },
+
+            // <me> :<nick>[!<user>@<host>][,...]
+            CommandCode::Numeric(Numeric::RplMonOffline) => {
+                let offline = param(1)
+                    .unwrap_or_default()
+                    .split(',')
+                    .any(|t| is_primary(t.split('!').next().unwrap_or_default()));
+                if offline && !self.has_primary(isupport) {
+                    Ok(self.regain())
+                } else {
+                    Ok(Vec::new())
+                }
+            }
+
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    // The primary nick is free now
+    fn regain(&self) -> Vec<Message> {
+        if !self.registered {
+            return Vec::new();
+        }
+        info!("Taking back nick {}", self.primary);
+        Message::nick(&self.primary).into_iter().collect()
+    }
+
+    // The alternates first, then the primary nick with more and more underscores, cut to NICKLEN
+    fn next_nick(&self, isupport: &ISupport) -> Option<String> {
+        if let Some(alt) = self.alternates.get(self.attempts - 1) {
+            return Some(alt.clone());
+        }
+
+        let n = self.attempts - self.alternates.len();
+        if n > MAX_GENERATED {
+            return None;
+        }
+
+        let mut nick = self.primary.clone();
+        if let Some(len) = isupport.nicklen {
+            while nick.chars().count() + n > len && nick.chars().count() > 1 {
+                nick.pop();
+            }
+        }
+        Some(nick + &"_".repeat(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(nicks: &mut Nicks, isupport: &ISupport, line: &str) -> Result<Vec<String>, NoNickLeft> {
+        let (_, msg) = crate::parse(format!("{}\r\n", line).as_bytes()).unwrap();
+        // As it is used, everything else is not looked at
+        if !Nicks::tracks(&msg.command) {
+            return Ok(Vec::new());
+        }
+        nicks
+            .update(&msg, isupport)
+            .map(|r| r.iter().map(|m| m.encode().unwrap().trim_end().to_string()).collect())
+    }
+
+    #[test]
+    fn collision() {
+        let i = ISupport::default();
+        let mut nicks = Nicks::new("ZeBot", &["ZeBeaut"]);
+        assert_eq!(nicks.start().encode().unwrap(), "NICK ZeBot\r\n");
+
+        assert_eq!(feed(&mut nicks, &i, ":irc.example.com 433 * ZeBot :Nickname is already in use").unwrap(), ["NICK ZeBeaut"]);
+        assert_eq!(feed(&mut nicks, &i, ":irc.example.com 433 * ZeBeaut :Nickname is already in use").unwrap(), ["NICK ZeBot_"]);
+        // Not about the nick we are trying
+        assert!(feed(&mut nicks, &i, ":irc.example.com 433 * ZeBeaut :Nickname is already in use").unwrap().is_empty());
+        assert_eq!(feed(&mut nicks, &i, ":irc.example.com 436 * ZeBot_ :Nickname collision KILL").unwrap(), ["NICK ZeBot__"]);
+
+        assert!(feed(&mut nicks, &i, ":irc.example.com 001 ZeBot__ :Welcome").unwrap().is_empty());
+        assert_eq!(nicks.current(), "ZeBot__");
+        assert!(!nicks.has_primary(&i));
+
+        assert_eq!(feed(&mut nicks, &i, ":irc.example.com 005 ZeBot__ MONITOR=100 :are supported").unwrap(), ["MONITOR + ZeBot"]);
+        assert_eq!(feed(&mut nicks, &i, ":irc.example.com 731 ZeBot__ :zebot").unwrap(), ["NICK ZeBot"]);
+        assert!(feed(&mut nicks, &i, ":irc.example.com 433 ZeBot__ ZeBot :Nickname is already in use").unwrap().is_empty());
+        assert_eq!(feed(&mut nicks, &i, ":ZeBot!~zebot@localhost QUIT :bye").unwrap(), ["NICK ZeBot"]);
+
+        assert_eq!(feed(&mut nicks, &i, ":ZeBot__!~zebot@localhost NICK :ZeBot").unwrap(), ["MONITOR - ZeBot"]);
+        assert!(nicks.has_primary(&i));
+        assert!(feed(&mut nicks, &i, ":ZeBot!~zebot@localhost NICK :ZeBot|away").unwrap().is_empty());
+        assert_eq!(nicks.current(), "ZeBot|away");
+    }
+
+    #[test]
+    fn exhausted() {
+        let i = ISupport { nicklen: Some(6), ..Default::default() };
+        let mut nicks = Nicks::new("ZeBot", &[]);
+        nicks.start();
+
+        let mut tried = Vec::new();
+        loop {
+            let line = format!(":irc.example.com 433 * {} :Nickname is already in use", nicks.current());
+            match feed(&mut nicks, &i, &line) {
+                Ok(r) => tried.extend(r),
+                Err(e) => {
+                    assert_eq!(e, NoNickLeft("Z________".to_string()));
+                    break;
+                }
+            }
+        }
+        assert_eq!(tried.len(), MAX_GENERATED);
+        assert_eq!(&tried[..3], ["NICK ZeBot_", "NICK ZeBo__", "NICK ZeB___"]);
+    }
+}
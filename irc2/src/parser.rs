@@ -1,56 +1,197 @@
+use nom::combinator::all_consuming;
 use nom::IResult;
 use tracing::debug;
 
 use crate::*;
 
 pub fn parse(i: &[u8]) -> IResult<&[u8], Message> {
-    loop {
-        match parsers::message(i) {
-            Ok((r, msg)) => {
-                debug!("{:4}", msg);
-                return Ok((r, msg));
-            }
+    let (r, msg) = parsers::message(i)?;
+    debug!("{:4}", msg);
+    Ok((r, msg))
+}
 
-            x => {
-                return x;
-            }
-        }
-    }
+/// Parse a single line without its line ending
+pub fn parse_line(i: &[u8]) -> Result<Message, nom::Err<nom::error::Error<&[u8]>>> {
+    let (_, msg) = all_consuming(parsers::message_body)(i)?;
+    debug!("{:4}", msg);
+    Ok(msg)
 }
 
-mod parsers {
+pub(crate) mod parsers {
     use nom::{
         branch::alt,
-        bytes::complete::{take_until, take_while, take_while1, take_while_m_n},
+        bytes::complete::{take_while, take_while1, take_while_m_n},
         character::{
             complete::{char, crlf, none_of, one_of},
             is_alphabetic, is_digit,
         },
-        combinator::{map, opt, recognize},
+        combinator::{all_consuming, map, map_res, opt, recognize, verify},
         IResult,
-        multi::{many0, many_m_n},
-        number::complete::be_u8,
+        multi::{fold_many_m_n, many0, many0_count},
+        sequence::{pair, terminated},
     };
 
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
     use super::*;
 
     // rfc2812.txt:321
     pub(crate) fn message(i: &[u8]) -> IResult<&[u8], Message> {
-        let (i, prefix) = opt(parsers::prefix)(i)?;
-        let (i, command) = parsers::command(i)?;
-        let (i, p) = opt(params)(i)?;
+        let (i, msg) = message_body(i)?;
         let (i, _) = crlf(i)?;
-        let p = p.map(|x| x.iter().map(|x| String::from_utf8_lossy(*x).to_string()).collect());
+        Ok((i, msg))
+    }
+
+    /// The parts of a message as they appear on the wire
+    pub(crate) struct RawMessage<'a> {
+        /// Everything between the '@' and the SPACE, still escaped
+        pub tags: Option<&'a [u8]>,
+        pub prefix: Option<RawPrefix<'a>>,
+        pub command: &'a [u8],
+        pub params: RawParams<'a>,
+    }
+
+    pub(crate) enum RawPrefix<'a> {
+        Server(&'a [u8]),
+        Nickname {
+            nickname: &'a [u8],
+            user: Option<&'a [u8]>,
+            host: Option<&'a [u8]>,
+        },
+    }
+
+    // Up to 15 params, rfc2812.txt:324
+    #[derive(Clone, Copy, Default)]
+    pub(crate) struct RawParams<'a> {
+        pub list: [&'a [u8]; 15],
+        pub len: usize,
+    }
+
+    impl<'a> RawParams<'a> {
+        fn push(mut self, p: &'a [u8]) -> Self {
+            self.list[self.len] = p;
+            self.len += 1;
+            self
+        }
+
+        pub fn as_slice(&self) -> &[&'a [u8]] {
+            &self.list[..self.len]
+        }
+    }
+
+    // rfc2812.txt:321 without the crlf
+    pub(crate) fn message_body(i: &[u8]) -> IResult<&[u8], Message> {
+        let (i, raw) = raw_message(i)?;
+        let tags = raw.tags.map(|t| {
+            let mut tags = Tags::default();
+            for (key, value) in split_tags(t) {
+                // Duplicate keys are allowed, the last one wins
+                tags.insert(
+                    &String::from_utf8_lossy(key),
+                    &unescape_tag_value(&String::from_utf8_lossy(value)),
+                );
+            }
+            tags
+        });
+        let prefix = raw.prefix.map(|p| match p {
+            RawPrefix::Server(s) => Prefix::Server(String::from_utf8_lossy(s).to_string()),
+            RawPrefix::Nickname { nickname, user, host } => Prefix::Nickname(Nickname {
+                nickname: String::from_utf8_lossy(nickname).to_string(),
+                user: user.map(|u| String::from_utf8_lossy(u).to_string()),
+                host: host.map(classify_host),
+            }),
+        });
         Ok((
             i,
             Message {
+                tags: tags.unwrap_or_default(),
                 prefix,
-                command: command.into(),
-                params: p.unwrap_or_else(|| Vec::new()),
+                command: raw.command.into(),
+                params: raw.params.as_slice().iter().map(|x| String::from_utf8_lossy(x).to_string()).collect(),
             },
         ))
     }
 
+    // rfc2812.txt:321 without the crlf, extended by https://ircv3.net/specs/extensions/message-tags
+    pub(crate) fn raw_message(i: &[u8]) -> IResult<&[u8], RawMessage<'_>> {
+        let (i, tags) = opt(parsers::tags)(i)?;
+        let (i, prefix) = opt(parsers::prefix)(i)?;
+        let (i, command) = parsers::command(i)?;
+        let (i, params) = opt(params)(i)?;
+        Ok((
+            i,
+            RawMessage {
+                tags,
+                prefix,
+                command,
+                params: params.unwrap_or_default(),
+            },
+        ))
+    }
+
+    // <tags> ::= <tag> [';' <tag>]*
+    fn tags(i: &[u8]) -> IResult<&[u8], &[u8]> {
+        let (i, _) = char('@')(i)?;
+        let (i, list) = recognize(pair(tag, many0_count(pair(char(';'), tag))))(i)?;
+        let (i, _) = char(' ')(i)?;
+        Ok((i, list))
+    }
+
+    /// Split an already parsed tags section into keys and (escaped) values
+    pub(crate) fn split_tags(tags: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+        tags.split(|&c| c == b';').map(|t| match t.iter().position(|&c| c == b'=') {
+            Some(pos) => (&t[..pos], &t[pos + 1..]),
+            None => (t, &t[t.len()..]),
+        })
+    }
+
+    // <tag> ::= <key> ['=' <escaped_value>]
+    fn tag(i: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+        let (i, key) = tag_key(i)?;
+        let (i, value) = opt(|i| {
+            let (i, _) = char('=')(i)?;
+            take_while(|c| !b"\0\r\n; ".contains(&c))(i)
+        })(i)?;
+        Ok((i, (key, value.unwrap_or_default())))
+    }
+
+    // <key> ::= [ <client_prefix> ] [ <vendor> '/' ] <key_name>
+    fn tag_key(i: &[u8]) -> IResult<&[u8], &[u8]> {
+        fn is_key_name(c: u8) -> bool {
+            c.is_ascii_alphanumeric() || c == b'-'
+        }
+        fn tag_key_(i: &[u8]) -> IResult<&[u8], &[u8]> {
+            let (i, _client) = opt(char('+'))(i)?;
+            let (i, _vendor) = opt(terminated(
+                take_while1(|c| is_key_name(c) || c == b'.'),
+                char('/'),
+            ))(i)?;
+            take_while1(is_key_name)(i)
+        }
+        recognize(tag_key_)(i)
+    }
+
+    pub(crate) fn unescape_tag_value(v: &str) -> String {
+        let mut r = String::with_capacity(v.len());
+        let mut chars = v.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                r.push(c);
+                continue;
+            }
+            // A trailing lone backslash is dropped, unknown escapes just drop the backslash
+            match chars.next() {
+                Some(':') => r.push(';'),
+                Some('s') => r.push(' '),
+                Some('r') => r.push('\r'),
+                Some('n') => r.push('\n'),
+                Some(c) => r.push(c),
+                None => (),
+            }
+        }
+        r
+    }
+
     // rfc2812.txt:329
     fn middle(i: &[u8]) -> IResult<&[u8], &[u8]> {
         pub fn middle_(i: &[u8]) -> IResult<&[u8], &[u8]> {
@@ -61,29 +202,24 @@ mod parsers {
     }
 
     // rfc2812.txt:324
-    fn params(i: &[u8]) -> IResult<&[u8], Vec<&[u8]>> {
-        // rfc2812.txt:324
-        pub fn params_(i: &[u8]) -> IResult<&[u8], (Vec<&[u8]>, &[u8])> {
-            fn part_1(i: &[u8]) -> IResult<&[u8], &[u8]> {
-                let (i, _) = char(' ')(i)?;
-                let (i, m) = middle(i)?;
-                Ok((i, m))
-            }
-            fn part_2(i: &[u8]) -> IResult<&[u8], &[u8]> {
-                let (i, _) = char(' ')(i)?;
-                let (i, _) = opt(char(':'))(i)?;
-                let (i, trail) = trailing(i)?;
-                Ok((i, trail))
-            }
-            let (i, p1) = many_m_n(0, 14, part_1)(i)?;
-            let (i, rest) = opt(part_2)(i)?;
-            Ok((i, (p1, rest.unwrap_or_else(|| &[]))))
+    fn params(i: &[u8]) -> IResult<&[u8], RawParams<'_>> {
+        fn part_1(i: &[u8]) -> IResult<&[u8], &[u8]> {
+            let (i, _) = char(' ')(i)?;
+            let (i, m) = middle(i)?;
+            Ok((i, m))
         }
-
-        map(params_, |(mut v, x)| {
-            v.push(x);
-            v
-        })(i)
+        fn part_2(i: &[u8]) -> IResult<&[u8], &[u8]> {
+            let (i, _) = char(' ')(i)?;
+            let (i, _) = opt(char(':'))(i)?;
+            let (i, trail) = trailing(i)?;
+            Ok((i, trail))
+        }
+        let (i, p1) = fold_many_m_n(0, 14, part_1, RawParams::default, RawParams::push)(i)?;
+        let (i, rest) = opt(part_2)(i)?;
+        Ok((i, match rest {
+            Some(rest) => p1.push(rest),
+            None => p1,
+        }))
     }
 
     // rfc2812.txt:330
@@ -101,23 +237,25 @@ mod parsers {
     }
 
     // rfc2812.txt:322
-    fn prefix(i: &[u8]) -> IResult<&[u8], Prefix> {
+    fn prefix(i: &[u8]) -> IResult<&[u8], RawPrefix<'_>> {
         let (i, _) = char(':')(i)?;
-        let (i, servnick) = alt((prefix_servername, prefix_nickname))(i)?;
+        let (i, servnick) = alt((prefix_nickname, prefix_servername))(i)?;
         // Note: the trailing SPACE needed to be pulled into the subparsers in order to
-        //       differentiate the parts.
+        //       differentiate the parts. A bare name without any dots is a nickname, as nicks
+        //       cannot contain dots, e.g. ':ZeBot MODE ZeBot :+i'.
         Ok((i, servnick))
     }
 
     // rfc2812.txt:322
-    fn prefix_nickname(i: &[u8]) -> IResult<&[u8], Prefix> {
+    fn prefix_nickname(i: &[u8]) -> IResult<&[u8], RawPrefix<'_>> {
         let (i, nick) = nickname(i)?;
         fn excl_user(i: &[u8]) -> IResult<&[u8], &[u8]> {
             let (i, _excl) = char('!')(i)?;
             let (i, user) = user(i)?;
             Ok((i, user))
         }
-        fn at_host(i: &[u8]) -> IResult<&[u8], (Option<&[u8]>, &[u8])> {
+        type UserHost<'a> = (Option<&'a [u8]>, &'a [u8]);
+        fn at_host(i: &[u8]) -> IResult<&[u8], UserHost<'_>> {
             let (i, u) = opt(excl_user)(i)?;
             let (i, _at) = char('@')(i)?;
             let (i, h) = host(i)?;
@@ -127,55 +265,69 @@ mod parsers {
         let (i, _) = char(' ')(i)?;
         Ok((
             i,
-            Prefix::Nickname(Nickname {
-                nickname: String::from_utf8_lossy(nick).to_string(),
-                host: if let Some((_, u)) = &rest {
-                    Some(String::from_utf8_lossy(u).to_string())
-                } else {
-                    None
-                },
-                user: if let Some((u, _)) = rest { u.map(|u| String::from_utf8_lossy(u).to_string()) } else { None },
-            }),
+            RawPrefix::Nickname {
+                nickname: nick,
+                user: rest.and_then(|(u, _)| u),
+                host: rest.map(|(_, h)| h),
+            },
         ))
     }
 
     // rfc2812.txt:366
-    fn prefix_servername(i: &[u8]) -> IResult<&[u8], Prefix> {
-        let (i, s) = map(hostname, |x| Prefix::Server(String::from_utf8_lossy(x).to_string()))(i)?;
+    fn prefix_servername(i: &[u8]) -> IResult<&[u8], RawPrefix<'_>> {
+        let (i, s) = map(hostname, RawPrefix::Server)(i)?;
         let (i, _) = char(' ')(i)?;
         Ok((i, s))
     }
 
     // rfc2812.txt:373
-    fn ip4addr(i: &[u8]) -> IResult<&[u8], &[u8]> {
-        pub fn ip(i: &[u8]) -> IResult<&[u8], u8> {
+    fn ip4addr(i: &[u8]) -> IResult<&[u8], Ipv4Addr> {
+        fn ip(i: &[u8]) -> IResult<&[u8], &[u8]> {
             let (i, _) = take_while_m_n(1, 3, is_digit)(i)?;
             let (i, _) = char('.')(i)?;
             let (i, _) = take_while_m_n(1, 3, is_digit)(i)?;
             let (i, _) = char('.')(i)?;
             let (i, _) = take_while_m_n(1, 3, is_digit)(i)?;
             let (i, _) = char('.')(i)?;
-            let (i, _) = take_while_m_n(1, 3, is_digit)(i)?;
-            be_u8(i)
+            take_while_m_n(1, 3, is_digit)(i)
         }
-        recognize(ip)(i)
+        // Leave the range checks of the octets to std
+        map_res(recognize(ip), |x| std::str::from_utf8(x).map_err(|_| ()).and_then(|x| x.parse().map_err(|_| ())))(i)
     }
 
     // rfc2812.txt:374
-    fn ip6addr(i: &[u8]) -> IResult<&[u8], &[u8]> {
-        // FIXME
-        let (i, x) = take_until(" ")(i)?;
-        Ok((i, x))
+    fn ip6addr(i: &[u8]) -> IResult<&[u8], Ipv6Addr> {
+        // The RFC only knows the full 8 group form and the IPv4-mapped form, real servers
+        // use the compressed '::' notation of RFC 4291 as well. The std parser handles all of
+        // these, we only need to find out where the address ends.
+        map_res(
+            verify(take_while1(|c: u8| c.is_ascii_hexdigit() || c == b':' || c == b'.'), |x: &[u8]| x.contains(&b':')),
+            |x| std::str::from_utf8(x).map_err(|_| ()).and_then(|x| x.parse().map_err(|_| ())),
+        )(i)
     }
 
     // rfc2812.txt:367
     fn host(i: &[u8]) -> IResult<&[u8], &[u8]> {
-        alt((hostname, hostaddr))(i)
+        // Read the whole host first, hostnames, addresses and cloaks share prefixes and need
+        // to be tried against all of it, see classify_host().
+        take_while1(|c: u8| !b"\0\r\n ".contains(&c))(i)
+    }
+
+    pub(crate) fn classify_host(h: &[u8]) -> Host {
+        let r: IResult<&[u8], Host> = alt((
+            all_consuming(hostaddr),
+            map(all_consuming(hostname), |x| Host::Hostname(String::from_utf8_lossy(x).to_string())),
+        ))(h);
+        match r {
+            Ok((_, host)) => host,
+            // Anything else is treated as a cloak, e.g. 'user/foo' or 'freenode/utility-bot/frigg'
+            Err(_) => Host::Cloak(String::from_utf8_lossy(h).to_string()),
+        }
     }
 
     // rfc2812.txt:372
-    fn hostaddr(i: &[u8]) -> IResult<&[u8], &[u8]> {
-        alt((ip4addr, ip6addr))(i)
+    fn hostaddr(i: &[u8]) -> IResult<&[u8], Host> {
+        alt((map(ip4addr, Host::Ip4), map(ip6addr, Host::Ip6)))(i)
     }
 
     // rfc2812.txt:368
@@ -195,8 +347,7 @@ mod parsers {
     ) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], &'a [u8]> {
         move |i: &'a [u8]| {
             recognize(|i: &'a [u8]| {
-                // XXX we need to match / too, as these are used by freenode bots/services
-                let (i, _dot) = alt((char('.'), char('/')))(i)?;
+                let (i, _dot) = char('.')(i)?;
                 let (i, _rest) = p(i)?;
                 Ok((i, i))
             })(i)
@@ -216,7 +367,7 @@ mod parsers {
 
     // rfc2812.txt:323
     fn command(i: &[u8]) -> IResult<&[u8], &[u8]> {
-        let (i, cmd) = alt((take_while_m_n(3, 3, is_digit), take_until(" ")))(i)?;
+        let (i, cmd) = alt((take_while_m_n(3, 3, is_digit), take_while1(is_alphabetic)))(i)?;
         Ok((i, cmd))
     }
 
@@ -260,6 +411,7 @@ mod parsers {
 }
 
 #[cfg(test)]
+#[allow(clippy::redundant_slicing)]
 mod tests {
     #[test]
     fn privmsg() {
@@ -315,6 +467,150 @@ mod tests {
         assert!(msg.params == [&"ZeBot"[..], &"\x01VERSION\x01"[..]]);
     }
 
+    #[test]
+    fn tags() {
+        let i = &b"@time=2021-01-01T12:00:00.000Z;account=fritschy;+example.com/draft-typing=active;msgid=a\\sb\\:c\\\\d\\ne;empty=;novalue :fritschy!~fritschy@localhost PRIVMSG #zebot-test :moep\r\n"[..];
+
+        let (rest, msg) = super::parsers::message(i).unwrap();
+        assert!(rest.is_empty());
+
+        assert_eq!(msg.tags.get("time"), Some("2021-01-01T12:00:00.000Z"));
+        assert_eq!(msg.tags.get("account"), Some("fritschy"));
+        assert_eq!(msg.tags.get("msgid"), Some("a b;c\\d\ne"));
+        assert_eq!(msg.tags.get("empty"), Some(""));
+        assert_eq!(msg.tags.get("novalue"), Some(""));
+        assert_eq!(msg.tags.get("+example.com/draft-typing"), Some("active"));
+        assert_eq!(msg.tags.client.get("example.com/draft-typing").map(String::as_str), Some("active"));
+        assert!(msg.tags.get("example.com/draft-typing").is_none());
+        assert_eq!(msg.tags.server.len(), 5);
+        assert_eq!(msg.tags.client.len(), 1);
+
+        assert_eq!(format!("{}", msg.prefix.unwrap()), "fritschy!~fritschy@localhost");
+        assert!(msg.params == [&"#zebot-test"[..], &"moep"[..]]);
+    }
+
+    #[test]
+    fn tags_escapes_and_duplicates() {
+        let i = &b"@a=1;a=2;b=trailing\\;c=\\x :irc.example.com NOTICE * :hi\r\n"[..];
+
+        let (_, msg) = super::parsers::message(i).unwrap();
+        assert_eq!(msg.tags.get("a"), Some("2"));
+        assert_eq!(msg.tags.get("b"), Some("trailing"));
+        assert_eq!(msg.tags.get("c"), Some("x"));
+    }
+
+    #[test]
+    fn no_tags() {
+        let i = &b":fritschy!~fritschy@localhost PRIVMSG #zebot-test :moep\r\n"[..];
+        let (_, msg) = super::parsers::message(i).unwrap();
+        assert!(msg.tags.is_empty());
+    }
+
+    #[test]
+    fn command_without_params() {
+        // The command used to run up to the next space, i.e. into the following line
+        let (r, msg) = super::parsers::message(b"QUIT\r\nPING :moep\r\n").unwrap();
+        assert!(msg.command == (&b"QUIT"[..]).into());
+        assert!(msg.params.is_empty());
+        assert!(r == b"PING :moep\r\n");
+    }
+
+    #[test]
+    fn command_grammar() {
+        // Unchanged: three digits or letters, followed by the params
+        let (_, msg) = super::parsers::message(b":irc.example.com 001 ZeBot :Welcome\r\n").unwrap();
+        assert!(msg.command == (&b"001"[..]).into());
+        let (_, msg) = super::parsers::message(b"privmsg #zebot-test :moep\r\n").unwrap();
+        assert!(msg.params == [&"#zebot-test"[..], &"moep"[..]]);
+        assert!(super::parsers::message(b"1234 ZeBot\r\n").is_err());
+
+        // Anything else up to the space used to be a command, rfc2812.txt:323 only has letters
+        assert!(super::parsers::message(b"PRIV-MSG #zebot-test :moep\r\n").is_err());
+        assert!(super::parsers::message(b"PRIVMSG\x01 #zebot-test :moep\r\n").is_err());
+    }
+
+    #[test]
+    fn nickname_prefix() {
+        // A bare nick used to be taken for a servername
+        let (_, msg) = super::parsers::message(b":ZeBot MODE ZeBot :+i\r\n").unwrap();
+        assert_eq!(msg.prefix.unwrap(), crate::Prefix::Nickname(crate::Nickname {
+            nickname: "ZeBot".to_string(),
+            user: None,
+            host: None,
+        }));
+
+        // Unchanged for names with dots, nicks cannot have any
+        let (_, msg) = super::parsers::message(b":weber.freenode.net 376 ZeBot :End of /MOTD command.\r\n").unwrap();
+        assert!(matches!(msg.prefix, Some(crate::Prefix::Server(_))));
+        let (_, msg) = super::parsers::message(b":fritschy!~fritschy@localhost PRIVMSG ZeBot :moep\r\n").unwrap();
+        assert!(matches!(msg.prefix, Some(crate::Prefix::Nickname(_))));
+
+        // A server without dots is indistinguishable from a nick now
+        let (_, msg) = super::parsers::message(b":localhost 001 ZeBot :Welcome\r\n").unwrap();
+        assert_eq!(msg.prefix.unwrap(), crate::Prefix::Nickname(crate::Nickname {
+            nickname: "localhost".to_string(),
+            user: None,
+            host: None,
+        }));
+    }
+
+    #[test]
+    fn no_trailing() {
+        // Without a trailing parameter an empty one used to be appended
+        let (_, msg) = super::parsers::message(b":ZeBot MODE ZeBot +i\r\n").unwrap();
+        assert!(msg.params == [&"ZeBot"[..], &"+i"[..]]);
+
+        let (_, msg) = super::parsers::message(b"QUIT\r\n").unwrap();
+        assert!(msg.params.is_empty());
+
+        // An empty trailing parameter is still one
+        let (_, msg) = super::parsers::message(b"TOPIC #zebot-test :\r\n").unwrap();
+        assert!(msg.params == [&"#zebot-test"[..], &""[..]]);
+    }
+
+    fn host_of(prefix: &str) -> crate::Host {
+        let line = format!(":{} PRIVMSG ZeBot :moep\r\n", prefix);
+        let (_, msg) = super::parsers::message(line.as_bytes()).unwrap();
+        match msg.prefix {
+            Some(crate::Prefix::Nickname(n)) => n.host().unwrap().clone(),
+            p => panic!("Unexpected prefix {:?}", p),
+        }
+    }
+
+    #[test]
+    fn hosts() {
+        use crate::Host;
+
+        assert_eq!(host_of("foo!~foo@user/foo"), Host::Cloak("user/foo".to_string()));
+        assert_eq!(
+            host_of("freenode-connect!frigg@freenode/utility-bot/frigg"),
+            Host::Cloak("freenode/utility-bot/frigg".to_string())
+        );
+        assert_eq!(
+            host_of("foo!~foo@gateway/web/irccloud.com/x-abcdef"),
+            Host::Cloak("gateway/web/irccloud.com/x-abcdef".to_string())
+        );
+        assert_eq!(host_of("foo!~foo@2001:db8::1"), Host::Ip6("2001:db8::1".parse().unwrap()));
+        assert_eq!(host_of("foo!~foo@::1"), Host::Ip6("::1".parse().unwrap()));
+        assert_eq!(
+            host_of("foo!~foo@2001:db8:0:0:0:0:0:1").to_string(),
+            "2001:db8::1"
+        );
+        assert_eq!(
+            host_of("foo!~foo@0:0:0:0:0:ffff:192.168.0.1"),
+            Host::Ip6("::ffff:192.168.0.1".parse().unwrap())
+        );
+        assert_eq!(host_of("foo!~foo@192.168.0.1"), Host::Ip4("192.168.0.1".parse().unwrap()));
+        assert_eq!(host_of("foo!~foo@localhost"), Host::Hostname("localhost".to_string()));
+        assert_eq!(host_of("foo!~foo@ip-1-2-3-4.example.org"), Host::Hostname("ip-1-2-3-4.example.org".to_string()));
+        assert_eq!(host_of("NickServ!NickServ@services."), Host::Hostname("services.".to_string()));
+        assert_eq!(host_of("foo@localhost"), Host::Hostname("localhost".to_string()));
+
+        // Not quite addresses
+        assert_eq!(host_of("foo!~foo@300.1.1.1"), Host::Hostname("300.1.1.1".to_string()));
+        assert_eq!(host_of("foo!~foo@2001:db8:::1"), Host::Cloak("2001:db8:::1".to_string()));
+    }
+
     #[test]
     fn freenode_motd_and_stuff() {
         let mut i = &b":weber.freenode.net 372 ZeBot :- #freenode and using the \'/who freenode/staff/*\' command. You may message\r\n:weber.freenode.net 372 ZeBot :- any of us at any time. Please note that freenode predominantly provides \r\n:weber.freenode.net 372 ZeBot :- assistance via private message, and while we have a network channel the \r\n:weber.freenode.net 372 ZeBot :- primary venue for support requests is via private message to a member \r\n:weber.freenode.net 372 ZeBot :- of the volunteer staff team.\r\n:weber.freenode.net 372 ZeBot :-  \r\n:weber.freenode.net 372 ZeBot :- From time to time, volunteer staff may send server-wide notices relating to\r\n:weber.freenode.net 372 ZeBot :- the project, or the communities that we host. The majority of such notices\r\n:weber.freenode.net 372 ZeBot :- will be sent as wallops, and you can \'/mode <yournick> +w\' to ensure that you\r\n:weber.freenode.net 372 ZeBot :- do not miss them. Important messages relating to the freenode project, including\r\n:weber.freenode.net 372 ZeBot :- notices of upcoming maintenance and other scheduled downtime will be issued as\r\n:weber.freenode.net 372 ZeBot :- global notices.\r\n:weber.freenode.net 372 ZeBot :-  \r\n:weber.freenode.net 372 ZeBot :- Representing an on-topic project? Don\'t forget to register, more information\r\n:weber.freenode.net 372 ZeBot :- can be found on the https://freenode.net website under \"Group Registration\".\r\n:weber.freenode.net 372 ZeBot :-  \r\n:weber.freenode.net 372 ZeBot :- Thank you also to our server sponsors for the sustained support in keeping the\r\n:weber.freenode.net 372 ZeBot :- network going for close to two decades.\r\n:weber.freenode.net 372 ZeBot :-  \r\n:weber.freenode.net 372 ZeBot :- Thank you for using freenode!\r\n:weber.freenode.net 376 ZeBot :End of /MOTD command.\r\n:ZeBot MODE ZeBot :+i\r\n:NickServ!NickServ@services. NOTICE ZeBot :This nickname is registered. Please choose a different nickname, or identify via \x02/msg NickServ identify <password>\x02.\r\n"[..];
@@ -0,0 +1,200 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use tracing::{info, warn};
+
+use crate::command::{CommandCode, Numeric};
+use crate::Message;
+
+// AUTHENTICATE payloads are sent in chunks of at most 400 bytes
+const CHUNK_LEN: usize = 400;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mechanism {
+    /// Account name and password, https://tools.ietf.org/html/rfc4616
+    Plain { account: String, password: String },
+    /// The TLS client certificate, i.e. CertFP
+    External,
+}
+
+impl Mechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mechanism::Plain { .. } => "PLAIN",
+            Mechanism::External => "EXTERNAL",
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        match self {
+            // authzid \0 authcid \0 passwd
+            Mechanism::Plain { account, password } => format!("{}\0{}\0{}", account, account, password).into_bytes(),
+            Mechanism::External => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaslStatus {
+    /// Still going, send these messages
+    InProgress(Vec<Message>),
+    Success,
+    Failure(String),
+}
+
+/// SASL authentication, see https://ircv3.net/specs/extensions/sasl-3.1
+///
+/// Start it with `start()` once the "sasl" capability was ACKed, feed it every message until
+/// it reports success or failure. CAP END should only be sent after that.
+#[derive(Debug, Clone)]
+pub struct Sasl {
+    mechanism: Mechanism,
+    account: Option<String>,
+}
+
+fn authenticate(param: &str) -> Message {
+    Message::new(CommandCode::Authenticate, vec![param.to_string()])
+}
+
+impl Sasl {
+    pub fn new(mechanism: Mechanism) -> Self {
+        Sasl { mechanism, account: None }
+    }
+
+    pub fn mechanism(&self) -> &Mechanism {
+        &self.mechanism
+    }
+
+    /// The account we are logged in as, after RPL_LOGGEDIN
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// Whether the server offers our mechanism, the value of the "sasl" capability lists
+    /// them since CAP 302. Without a list we can only try.
+    pub fn is_offered(&self, cap_value: Option<&str>) -> bool {
+        match cap_value {
+            Some(mechs) if !mechs.is_empty() => mechs.split(',').any(|m| m.eq_ignore_ascii_case(self.mechanism.name())),
+            _ => true,
+        }
+    }
+
+    pub fn start(&self) -> Message {
+        authenticate(self.mechanism.name())
+    }
+
+    /// Abort an authentication in progress
+    pub fn abort(&self) -> Message {
+        authenticate("*")
+    }
+
+    pub fn update(&mut self, msg: &Message) -> SaslStatus {
+        let text = || msg.params.last().cloned().unwrap_or_default();
+
+        match &msg.command {
+            // The server is ready for our payload
+            CommandCode::Authenticate if msg.params.first().map(String::as_str) == Some("+") => {
+                SaslStatus::InProgress(self.payload())
+            }
+            CommandCode::Authenticate => {
+                warn!("Unexpected AUTHENTICATE challenge for {}: {}", self.mechanism.name(), msg);
+                SaslStatus::Failure("Unexpected challenge".to_string())
+            }
+
+            CommandCode::Numeric(n) => match n {
+                // <nick> <nick>!<ident>@<host> <account> :You are now logged in as <user>
+                Numeric::RplLoggedIn => {
+                    self.account = msg.params.get(2).cloned();
+                    info!("{}", text());
+                    SaslStatus::InProgress(Vec::new())
+                }
+                Numeric::RplSaslSuccess | Numeric::ErrSaslAlready => SaslStatus::Success,
+                Numeric::ErrNickLocked
+                | Numeric::ErrSaslFail
+                | Numeric::ErrSaslTooLong
+                | Numeric::ErrSaslAborted => SaslStatus::Failure(format!("{} {}", n, text())),
+                // <nick> <mechanisms> :are available SASL mechanisms, comes before ERR_SASLFAIL
+                Numeric::RplSaslMechs => {
+                    warn!("Server only supports SASL mechanisms {}", msg.params.get(1).map(String::as_str).unwrap_or_default());
+                    SaslStatus::InProgress(Vec::new())
+                }
+                _ => SaslStatus::InProgress(Vec::new()),
+            },
+
+            _ => SaslStatus::InProgress(Vec::new()),
+        }
+    }
+
+    fn payload(&self) -> Vec<Message> {
+        let payload = BASE64.encode(self.mechanism.payload());
+
+        let mut r = payload
+            .as_bytes()
+            .chunks(CHUNK_LEN)
+            .map(|c| authenticate(std::str::from_utf8(c).unwrap()))
+            .collect::<Vec<_>>();
+
+        // An empty payload, or one that ends with a full chunk, needs to be terminated
+        if payload.len() % CHUNK_LEN == 0 {
+            r.push(authenticate("+"));
+        }
+
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(line: &str) -> Message {
+        crate::parse(format!("{}\r\n", line).as_bytes()).unwrap().1
+    }
+
+    fn lines(status: SaslStatus) -> Vec<String> {
+        match status {
+            SaslStatus::InProgress(m) => m.iter().map(|m| m.encode().unwrap().trim_end().to_string()).collect(),
+            s => panic!("Unexpected {:?}", s),
+        }
+    }
+
+    #[test]
+    fn plain() {
+        let mut sasl = Sasl::new(Mechanism::Plain { account: "jilles".to_string(), password: "sesame".to_string() });
+        assert!(sasl.is_offered(Some("PLAIN,EXTERNAL")));
+        assert!(!sasl.is_offered(Some("EXTERNAL,SCRAM-SHA-256")));
+        assert!(sasl.is_offered(None));
+
+        assert_eq!(sasl.start().encode().unwrap(), "AUTHENTICATE PLAIN\r\n");
+        assert_eq!(lines(sasl.update(&msg("AUTHENTICATE +"))), ["AUTHENTICATE amlsbGVzAGppbGxlcwBzZXNhbWU="]);
+        assert!(lines(sasl.update(&msg(":irc.example.com 900 jilles jilles!jilles@localhost.stack.nl jilles :You are now logged in as jilles"))).is_empty());
+        assert_eq!(sasl.account(), Some("jilles"));
+        assert_eq!(sasl.update(&msg(":irc.example.com 903 jilles :SASL authentication successful")), SaslStatus::Success);
+    }
+
+    #[test]
+    fn external() {
+        let mut sasl = Sasl::new(Mechanism::External);
+        assert_eq!(sasl.start().encode().unwrap(), "AUTHENTICATE EXTERNAL\r\n");
+        assert_eq!(lines(sasl.update(&msg("AUTHENTICATE +"))), ["AUTHENTICATE +"]);
+        assert_eq!(
+            sasl.update(&msg(":irc.example.com 904 ZeBot :SASL authentication failed")),
+            SaslStatus::Failure("ERR_SASLFAIL SASL authentication failed".to_string())
+        );
+    }
+
+    #[test]
+    fn chunks() {
+        // 300 bytes of payload are 400 bytes of base64
+        let password = "x".repeat(300 - 2 - 2 * "zebot".len());
+        let mut sasl = Sasl::new(Mechanism::Plain { account: "zebot".to_string(), password });
+        let r = lines(sasl.update(&msg("AUTHENTICATE +")));
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[0].len(), "AUTHENTICATE ".len() + 400);
+        assert_eq!(r[1], "AUTHENTICATE +");
+
+        let mut sasl = Sasl::new(Mechanism::Plain { account: "zebot".to_string(), password: "x".repeat(400) });
+        let r = lines(sasl.update(&msg("AUTHENTICATE +")));
+        assert_eq!(r.len(), 2);
+        assert_ne!(r[1], "AUTHENTICATE +");
+    }
+}
@@ -1,44 +1,58 @@
-use crate::irc::{MessageHandler, Context, HandlerResult};
+use crate::irc::{AsyncMessageHandler, Context, HandlerResult, Role};
 use crate::{is_json_flag_set, text_box};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
+use async_trait::async_trait;
 use tracing::error as log_error;
 use tracing::info;
-use irc2::Message;
-use futures::executor::block_on;
+use irc2::{Message, MessageRef};
 
 pub struct Callouthandler;
 
-impl MessageHandler for Callouthandler {
-    fn handle(
-        &self,
-        ctx: &Context,
-        msg: &Message,
-    ) -> Result<HandlerResult, std::io::Error> {
-        if msg.params.len() < 2 || !msg.params[1].starts_with('!') {
-            return Ok(HandlerResult::NotInterested);
-        }
+/// The command and the script answering it, for texts like "!command args"
+fn script(text: &str) -> Option<(String, PathBuf)> {
+    let command = text.strip_prefix('!')?.split_ascii_whitespace().next().unwrap_or_default();
+    if !command.chars().all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_') {
+        return None;
+    }
 
-        let command = msg.params[1][1..]
-            .split_ascii_whitespace()
-            .next()
-            .unwrap_or_default();
-        if !command
-            .chars()
-            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
-        {
-            return Ok(HandlerResult::NotInterested);
-        }
+    let command = command.to_lowercase();
+    let path = Path::new("./handlers").join(&command);
+    if !path.exists() {
+        return None;
+    }
+    Some((command, path))
+}
+
+#[async_trait]
+impl AsyncMessageHandler for Callouthandler {
+    // Commands with a script are answered by it, nobody else
+    fn accepts(&self, msg: &MessageRef<'_>) -> bool {
+        msg.param(1).and_then(script).is_some()
+    }
 
-        let command = command.to_lowercase();
+    fn role(&self) -> Role {
+        Role::Consume
+    }
 
-        let path = format!("./handlers/{}", command);
-        let path = Path::new(&path);
+    fn name(&self) -> &str {
+        "callout"
+    }
 
-        if !path.exists() {
-            return Ok(HandlerResult::NotInterested);
-        }
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    async fn handle(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+    ) -> Result<HandlerResult, std::io::Error> {
+        let (command, path) = match msg.params.get(1).and_then(|text| script(text)) {
+            Some(script) => script,
+            None => return Ok(HandlerResult::NotInterested),
+        };
 
         let nick = msg.get_nick();
         let mut args = msg.params.iter().map(|x| x.to_string()).collect::<Vec<_>>();
@@ -60,7 +74,8 @@ impl MessageHandler for Callouthandler {
         dbg!(&args);
 
         let s = Instant::now();
-        let cmd = std::process::Command::new(path).args(&args).output();
+        // Killed if it takes too long or gets cancelled
+        let cmd = tokio::process::Command::new(path).args(&args).kill_on_drop(true).output().await;
         let s = s.elapsed();
 
         info!("Handler {} completed in {:?}", command, s);
@@ -68,7 +83,7 @@ impl MessageHandler for Callouthandler {
         match cmd {
             Ok(p) => {
                 if !p.status.success() {
-                    let dst = msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }));
+                    let dst = ctx.response_destination(msg);
                     log_error!("Handler failed with code {}", p.status.code().unwrap());
                     dbg!(&p);
                     ctx.message(&dst, "Somehow, that did not work...");
@@ -82,7 +97,7 @@ impl MessageHandler for Callouthandler {
                             let dst = if response.contains("dst") {
                                 response["dst"].to_string()
                             } else {
-                                msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }))
+                                ctx.response_destination(msg)
                             };
 
                             if response.contains("error") {
@@ -90,9 +105,7 @@ impl MessageHandler for Callouthandler {
                                 ctx.message(&dst, "Somehow, that did not work...");
                                 return Ok(HandlerResult::Handled);
                             } else if !is_json_flag_set(&response["box"]) {
-                                for l in response["lines"].members() {
-                                    ctx.message(&dst, &l.to_string());
-                                }
+                                ctx.message_bulk(&dst, response["lines"].members().map(|l| l.to_string()));
                             } else {
                                 let lines = response["lines"]
                                     .members()
@@ -116,7 +129,7 @@ impl MessageHandler for Callouthandler {
                                         textwrap::fill(&s, 80)
                                     };
 
-                                    s.split(|f| f == '\n')
+                                    s.split('\n')
                                         .map(|x| x.to_string())
                                         .collect::<Vec<_>>()
                                 } else if is_json_flag_set(&response["wrap_single_lines"]) {
@@ -145,9 +158,7 @@ impl MessageHandler for Callouthandler {
                                     lines
                                 };
 
-                                for i in text_box(lines.iter(), response["title"].as_str()) {
-                                    ctx.message(&dst, &i);
-                                }
+                                ctx.message_bulk(&dst, text_box(lines.iter(), response["title"].as_str()));
                             }
                         }
 
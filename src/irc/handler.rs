@@ -1,14 +1,242 @@
 use crate::irc::*;
-use irc2::Message;
+use irc2::{Channels, Message, MessageRef, Nicks, Prefix, SaslStatus};
+use irc2::ctcp::Ctcp;
+
+use async_trait::async_trait;
 
 pub enum HandlerResult {
+    /// The message was answered, handlers that consume messages and come later do not see it
     Handled,
     NotInterested,
+    /// The handler could not do its job for this message, e.g. a file it writes could not be
+    /// opened. This is logged and counts as a failure, the connection is not affected.
     Error(String),
 }
 
-pub trait MessageHandler {
+/// What a handler does with the messages it gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Sees every message, even if an earlier handler answered it, e.g. to collect URLs.
+    /// Returning `HandlerResult::Handled` does not stop the handlers after it.
+    Observe,
+    /// Only sees messages no earlier consumer answered, and returns `HandlerResult::Handled`
+    /// for those it answers
+    Consume,
+}
+
+/// Handlers run on the dispatcher task, one message after the other, ordered by `priority()`.
+/// Returning `Err` or panicking is logged like `HandlerResult::Error`, it does not end the
+/// connection.
+pub trait MessageHandler: Send + Sync {
     fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error>;
+
+    /// Whether to `handle()` a message at all, decided on the borrowed message. The owned
+    /// `Message` is only built for messages some handler accepts.
+    fn accepts(&self, _msg: &MessageRef<'_>) -> bool {
+        true
+    }
+
+    /// Used in the log, the type name by default
+    fn name(&self) -> &str {
+        type_name::<Self>()
+    }
+
+    fn role(&self) -> Role {
+        Role::Consume
+    }
+
+    /// Higher priorities run first, equal ones in the order they were registered
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Called when we connected or lost a connection. Handlers live across reconnects, this
+    /// is where to forget what only made sense on the previous connection.
+    fn connection_event(&self, _ctx: &Context, _event: &ConnectionEvent) {}
+
+    /// IRCv3 capabilities this handler wants enabled, if the server offers them
+    fn capabilities(&self) -> &'static [&'static str] {
+        &[]
+    }
+}
+
+/// How long an async handler may take by default
+pub const HANDLER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// For handlers that wait, e.g. for a child process. Each message gets its own task, so
+/// these do not hold up other messages, and they are cancelled when they take longer than
+/// `timeout()`, on `Context::cancel_bulk()` for their channel or when the connection ends.
+/// What `handle()` returns comes too late to stop the handlers after them, a consuming async
+/// handler claims every message its `accepts()` lets through.
+#[async_trait]
+pub trait AsyncMessageHandler: Send + Sync {
+    async fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error>;
+
+    /// Whether to start `handle()` for a message, see `MessageHandler::accepts()`. Consumers
+    /// decide here whether they answer, later consumers do not see the messages they accept.
+    fn accepts(&self, _msg: &MessageRef<'_>) -> bool {
+        true
+    }
+
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+
+    /// Used in the log, the type name by default
+    fn name(&self) -> &str {
+        type_name::<Self>()
+    }
+
+    /// Higher priorities are started first
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Called when we connected or lost a connection, see `MessageHandler::connection_event()`
+    fn connection_event(&self, _ctx: &Context, _event: &ConnectionEvent) {}
+
+    fn timeout(&self) -> Duration {
+        HANDLER_TIMEOUT
+    }
+
+    /// IRCv3 capabilities this handler wants enabled, if the server offers them
+    fn capabilities(&self) -> &'static [&'static str] {
+        &[]
+    }
+}
+
+// Without the module path
+fn type_name<T: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+pub(crate) enum HandlerKind {
+    Sync(Box<dyn MessageHandler>),
+    Async(Arc<dyn AsyncMessageHandler>),
+}
+
+/// A registered handler, with how often it failed in a row
+pub(crate) struct Handler {
+    pub kind: HandlerKind,
+    /// The connection depends on the built-in handlers, they are never disabled
+    builtin: bool,
+    failures: AtomicU32,
+    disabled: AtomicBool,
+}
+
+impl Handler {
+    pub fn builtin(h: impl MessageHandler + 'static) -> Self {
+        Handler {
+            builtin: true,
+            ..Handler::new(HandlerKind::Sync(Box::new(h)))
+        }
+    }
+
+    pub fn new(kind: HandlerKind) -> Self {
+        Handler {
+            kind,
+            builtin: false,
+            failures: AtomicU32::new(0),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match &self.kind {
+            HandlerKind::Sync(h) => h.name(),
+            HandlerKind::Async(h) => h.name(),
+        }
+    }
+
+    pub fn accepts(&self, msg: &MessageRef<'_>) -> bool {
+        match &self.kind {
+            HandlerKind::Sync(h) => h.accepts(msg),
+            HandlerKind::Async(h) => h.accepts(msg),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        match &self.kind {
+            HandlerKind::Sync(h) => h.role(),
+            HandlerKind::Async(h) => h.role(),
+        }
+    }
+
+    pub fn priority(&self) -> i32 {
+        match &self.kind {
+            HandlerKind::Sync(h) => h.priority(),
+            HandlerKind::Async(h) => h.priority(),
+        }
+    }
+
+    pub fn capabilities(&self) -> &'static [&'static str] {
+        match &self.kind {
+            HandlerKind::Sync(h) => h.capabilities(),
+            HandlerKind::Async(h) => h.capabilities(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled.load(Ordering::SeqCst)
+    }
+
+    /// E.g. 'JoinHandler (observe)' or 'Callouthandler (async, priority 10)'
+    pub fn describe(&self) -> String {
+        let role = match (&self.kind, self.role()) {
+            (HandlerKind::Async(_), Role::Observe) => "async, observe",
+            (HandlerKind::Async(_), Role::Consume) => "async, consume",
+            (_, Role::Observe) => "observe",
+            (_, Role::Consume) => "consume",
+        };
+        match self.priority() {
+            0 => format!("{} ({})", self.name(), role),
+            p => format!("{} ({}, priority {})", self.name(), role, p),
+        }
+    }
+
+    /// Log what went wrong handling msg and count failures in a row, after `disable_after` of
+    /// them the handler is disabled. Returns whether the handler claimed msg.
+    pub fn check(
+        &self,
+        r: std::thread::Result<Result<HandlerResult, std::io::Error>>,
+        msg: &Message,
+        disable_after: Option<u32>,
+    ) -> bool {
+        let e = match r {
+            Ok(Ok(HandlerResult::Error(e))) => e,
+            Ok(Ok(r)) => {
+                self.failures.store(0, Ordering::SeqCst);
+                return matches!(r, HandlerResult::Handled);
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(panic) => match panic.downcast_ref::<&str>() {
+                Some(p) => format!("panicked: {}", p),
+                None => format!("panicked: {}", panic.downcast_ref::<String>().map(String::as_str).unwrap_or("?")),
+            },
+        };
+        self.failed(&e, msg, disable_after);
+        false
+    }
+
+    pub fn failed(&self, e: &str, msg: &Message, disable_after: Option<u32>) {
+        log_error!("Handler {} failed on '{}': {}", self.name(), msg, e);
+
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        match disable_after {
+            Some(n) if failures >= n && !self.builtin => {
+                log_error!("Handler {} failed {} times in a row, disabling it", self.name(), failures);
+                self.disabled.store(true, Ordering::SeqCst);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// After the handlers with the same or a higher priority
+pub(crate) fn insert_by_priority(handlers: &mut Vec<Arc<Handler>>, h: Arc<Handler>) {
+    let pos = handlers.iter().position(|x| x.priority() < h.priority()).unwrap_or(handlers.len());
+    handlers.insert(pos, h);
 }
 
 pub(crate) struct PingHandler;
@@ -21,13 +249,541 @@ impl MessageHandler for PingHandler {
         } else if !msg.params.is_empty() {
             msg.params[0].clone()
         } else {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Don't know how to respond to PING w/o params or prefix!"));
+            return Err(std::io::Error::other("Don't know how to respond to PING w/o params or prefix!"));
+        };
+
+        ctx.send_priority(Priority::Protocol, Message::pong(&dst, Some(&dst))?);
+
+        Ok(HandlerResult::Handled)
+    }
+}
+
+/// Sources get at most one CTCP answer within this time
+const CTCP_COOLDOWN: Duration = Duration::from_secs(2);
+
+/// Answers the standard CTCP queries
+pub(crate) struct CtcpHandler {
+    last: Mutex<HashMap<Prefix, tokio::time::Instant>>,
+}
+
+impl CtcpHandler {
+    pub(crate) fn new() -> Self {
+        Self {
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `pfx` got an answer less than `CTCP_COOLDOWN` ago. Every query restarts the
+    /// cooldown, so a flood gets no answers at all.
+    fn cooling_down(&self, pfx: &Prefix) -> bool {
+        let now = tokio::time::Instant::now();
+        let mut last = lock(&self.last);
+        last.retain(|p, t| p == pfx || now.duration_since(*t) < CTCP_COOLDOWN);
+        matches!(last.insert(pfx.clone(), now), Some(t) if now.duration_since(t) < CTCP_COOLDOWN)
+    }
+}
+
+impl MessageHandler for CtcpHandler {
+    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        let mut result = HandlerResult::NotInterested;
+        let mut replies = Vec::new();
+
+        for query in msg.ctcp() {
+            let reply = match query {
+                // ACTIONs are just messages to other handlers
+                Ctcp::Action(_) => continue,
+                Ctcp::Version(_) => Ctcp::Version(Some(format!("ZeBot {}", crate::zebot_version()))),
+                Ctcp::Ping(p) => Ctcp::Ping(p),
+                Ctcp::Time(_) => Ctcp::Time(Some(chrono::Local::now().to_rfc2822())),
+                Ctcp::ClientInfo(_) => Ctcp::ClientInfo(Some("ACTION CLIENTINFO PING TIME VERSION".to_string())),
+                q => {
+                    info!("Ignoring CTCP {} from {}", q.command(), msg.get_nick());
+                    result = HandlerResult::Handled;
+                    continue;
+                }
+            };
+            replies.push(reply);
+        }
+
+        if replies.is_empty() {
+            return Ok(result);
+        }
+
+        if msg.prefix.as_ref().is_some_and(|pfx| self.cooling_down(pfx)) {
+            info!("Not answering CTCP from {}, too many queries", msg.get_nick());
+            return Ok(HandlerResult::Handled);
+        }
+
+        let nick = msg.get_nick();
+        for reply in replies {
+            info!("Answering CTCP {} from {}", reply.command(), nick);
+            ctx.notice(&nick, &reply.encode());
+        }
+
+        Ok(HandlerResult::Handled)
+    }
+
+    fn accepts(&self, msg: &MessageRef<'_>) -> bool {
+        msg.param(1).is_some_and(|text| text.contains('\x01'))
+    }
+}
+
+/// Collects the RPL_ISUPPORT tokens
+pub(crate) struct ISupportHandler;
+
+impl MessageHandler for ISupportHandler {
+    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        write(&ctx.isupport).update(msg);
+        Ok(HandlerResult::Handled)
+    }
+
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+}
+
+/// Keeps track of channel members, their modes and the channels' ban lists
+pub(crate) struct ChannelStateHandler;
+
+impl MessageHandler for ChannelStateHandler {
+    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        let me = ctx.nick();
+        write(&ctx.channel_state).update(&me, msg, &read(&ctx.isupport));
+
+        // Ask for the channel modes and the ban list after we joined
+        if msg.command == CommandCode::Join && ctx.casemapping().eq(&msg.get_nick(), &me) {
+            if let Some(chan) = msg.params.first() {
+                ctx.send(Message::mode(chan, &[])?);
+                ctx.send(Message::list_mode(chan, 'b')?);
+            }
+        }
+
+        Ok(HandlerResult::NotInterested)
+    }
+
+    fn accepts(&self, msg: &MessageRef<'_>) -> bool {
+        Channels::tracks(&msg.command())
+    }
+
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        // All prefixes in NAMES replies, not just the highest one
+        &["multi-prefix"]
+    }
+}
+
+/// Keeps track of our nick, picks another one while ours is taken and takes it back later
+pub(crate) struct NickHandler;
+
+impl MessageHandler for NickHandler {
+    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        let r = write(&ctx.nicks).update(msg, &read(&ctx.isupport));
+
+        match r {
+            Ok(msgs) => {
+                for m in msgs {
+                    ctx.send_priority(Priority::Protocol, m);
+                }
+                Ok(HandlerResult::NotInterested)
+            }
+            Err(e) => {
+                log_error!("Can not register: {}", e);
+                ctx.quit();
+                Err(std::io::Error::other(e))
+            }
+        }
+    }
+
+    fn accepts(&self, msg: &MessageRef<'_>) -> bool {
+        Nicks::tracks(&msg.command())
+    }
+
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+}
+
+/// Negotiates the capabilities the handlers asked for, and starts SASL once that settled
+pub(crate) struct CapHandler;
+
+impl MessageHandler for CapHandler {
+    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        let msgs = write(&ctx.caps).update(msg);
+        for m in msgs {
+            ctx.send_priority(Priority::Protocol, m);
+        }
+
+        let start = {
+            let caps = read(&ctx.caps);
+            let mut sasl = write(&ctx.sasl);
+            match std::mem::replace(&mut *sasl, SaslState::Disabled) {
+                SaslState::Pending(s) if caps.is_negotiating() && caps.is_settled() => {
+                    if !caps.is_enabled("sasl") {
+                        Some(Err("the server does not offer SASL".to_string()))
+                    } else if !s.is_offered(caps.value("sasl")) {
+                        Some(Err(format!("the server does not offer {}, only {}", s.mechanism().name(), caps.value("sasl").unwrap_or_default())))
+                    } else {
+                        let start = s.start();
+                        *sasl = SaslState::InProgress(s);
+                        Some(Ok(start))
+                    }
+                }
+                state => {
+                    *sasl = state;
+                    None
+                }
+            }
         };
 
-        let resp = format!("PONG {} :{}\r\n", dst, dst);
+        match start {
+            Some(Ok(m)) => ctx.send_priority(Priority::Protocol, m),
+            Some(Err(e)) => {
+                log_error!("Can not authenticate: {}", e);
+                ctx.sasl_failed()?;
+            }
+            None => (),
+        }
+
+        if msg.command == CommandCode::Cap {
+            Ok(HandlerResult::Handled)
+        } else {
+            Ok(HandlerResult::NotInterested)
+        }
+    }
+}
+
+/// Feeds AUTHENTICATE and the SASL numerics to the authentication in progress
+pub(crate) struct SaslHandler;
+
+impl MessageHandler for SaslHandler {
+    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        let (status, abort) = match &mut *write(&ctx.sasl) {
+            SaslState::InProgress(sasl) => (sasl.update(msg), sasl.abort()),
+            _ => return Ok(HandlerResult::NotInterested),
+        };
 
-        ctx.send(resp);
+        match status {
+            SaslStatus::InProgress(msgs) => {
+                for m in msgs {
+                    ctx.send_priority(Priority::Protocol, m);
+                }
+            }
+            SaslStatus::Success => {
+                info!("SASL authentication succeeded");
+                ctx.sasl_succeeded();
+            }
+            SaslStatus::Failure(e) => {
+                log_error!("SASL authentication failed: {}", e);
+                // The server still waits for us
+                if msg.command == CommandCode::Authenticate {
+                    ctx.send_priority(Priority::Protocol, abort);
+                }
+                ctx.sasl_failed()?;
+            }
+        }
 
         Ok(HandlerResult::Handled)
     }
 }
+
+/// Identifies with NickServ after registration, unless SASL took care of that, then asks
+/// NickServ for our nick if we did not get it
+pub(crate) struct IdentifyHandler;
+
+impl MessageHandler for IdentifyHandler {
+    fn handle(&self, ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        ctx.set_registered();
+
+        let unfinished = matches!(*read(&ctx.sasl), SaslState::Pending(_) | SaslState::InProgress(_));
+        if unfinished {
+            log_error!("Registered before SASL authentication finished");
+            ctx.sasl_failed()?;
+        }
+
+        if matches!(*read(&ctx.sasl), SaslState::Disabled | SaslState::Failed) {
+            ctx.identify();
+        }
+        ctx.regain_nick();
+
+        Ok(HandlerResult::NotInterested)
+    }
+
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+}
+
+/// Remembers the account we are logged in to
+pub(crate) struct AccountHandler;
+
+impl MessageHandler for AccountHandler {
+    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        let account = match msg.command {
+            // <me> <nick>!<user>@<host> <account> :You are now logged in as <account>
+            CommandCode::Numeric(Numeric::RplLoggedIn) => msg.params.get(2).cloned(),
+            _ => None,
+        };
+
+        match &account {
+            Some(a) => info!("Logged in as {}", a),
+            None => warn!("Logged out"),
+        }
+        *write(&ctx.account) = account;
+
+        Ok(HandlerResult::NotInterested)
+    }
+
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+}
+
+/// Confirms our JOINs by their echo, notices when we leave or get kicked, and reports the
+/// channels the server did not let us into
+pub(crate) struct JoinHandler;
+
+impl MessageHandler for JoinHandler {
+    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+        let cm = ctx.casemapping();
+        let me = ctx.nick();
+        let ours = cm.eq(&msg.get_nick(), &me);
+        let param = |i: usize| msg.params.get(i).map(String::as_str).unwrap_or_default();
+
+        match &msg.command {
+            CommandCode::Join if ours => {
+                for chan in param(0).split(',') {
+                    ctx.joined(chan);
+                }
+            }
+            CommandCode::Part if ours => {
+                for chan in param(0).split(',') {
+                    info!("Left {}", chan);
+                    ctx.left(chan);
+                }
+            }
+            // <channel> <nick> [:<reason>]
+            CommandCode::Kick if cm.eq(param(1), &me) => {
+                warn!("Kicked from {} by {}: {}", param(0), msg.get_nick(), param(2));
+                ctx.left(param(0));
+            }
+            // <me> <channel> :<reason>, these are not only for JOINs
+            CommandCode::Numeric(_) if ctx.join_failed(param(1)) => {
+                log_error!("Can not join {}: {}", param(1), param(2));
+            }
+            _ => (),
+        }
+
+        Ok(HandlerResult::NotInterested)
+    }
+
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::tests::context;
+
+    struct Failing;
+
+    impl MessageHandler for Failing {
+        fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            Ok(HandlerResult::Error("no disk".to_string()))
+        }
+    }
+
+    struct Early;
+
+    impl MessageHandler for Early {
+        fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            Ok(HandlerResult::NotInterested)
+        }
+
+        fn role(&self) -> Role {
+            Role::Observe
+        }
+
+        fn priority(&self) -> i32 {
+            10
+        }
+    }
+
+    struct Erroring;
+
+    impl MessageHandler for Erroring {
+        fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            Err(std::io::Error::other("no disk"))
+        }
+    }
+
+    struct Counting(Arc<AtomicU32>);
+
+    impl MessageHandler for Counting {
+        fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(HandlerResult::Handled)
+        }
+    }
+
+    struct Picky(Arc<AtomicU32>);
+
+    impl MessageHandler for Picky {
+        fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(HandlerResult::Handled)
+        }
+
+        fn accepts(&self, msg: &MessageRef<'_>) -> bool {
+            msg.param(0) == Some("#wanted")
+        }
+    }
+
+    #[test]
+    fn accepts_filters() {
+        let seen = Arc::new(AtomicU32::new(0));
+        let mut ctx = context();
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Picky(seen.clone())));
+        let ctx = Arc::new(ctx);
+
+        ctx.handle(&MessageRef::parse(":nick!user@host PRIVMSG #other :hi").unwrap());
+        assert_eq!(seen.load(Ordering::SeqCst), 0);
+        ctx.handle(&MessageRef::parse(":nick!user@host PRIVMSG #wanted :hi").unwrap());
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+    }
+
+    struct Claiming;
+
+    #[async_trait]
+    impl AsyncMessageHandler for Claiming {
+        async fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            Ok(HandlerResult::Handled)
+        }
+
+        fn accepts(&self, msg: &MessageRef<'_>) -> bool {
+            msg.param(1).is_some_and(|text| text.starts_with('!'))
+        }
+
+        fn role(&self) -> Role {
+            Role::Consume
+        }
+    }
+
+    #[tokio::test]
+    async fn async_consumer() {
+        let seen = Arc::new(AtomicU32::new(0));
+        let mut ctx = context();
+        ctx.register_async_handler(CommandCode::PrivMsg, Box::new(Claiming));
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Counting(seen.clone())));
+        let ctx = Arc::new(ctx);
+
+        ctx.handle(&MessageRef::parse(":nick!user@host PRIVMSG #zebot-test :!callout").unwrap());
+        assert_eq!(seen.load(Ordering::SeqCst), 0);
+        ctx.handle(&MessageRef::parse(":nick!user@host PRIVMSG #zebot-test :hi").unwrap());
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+    }
+
+    struct Watching(Arc<AtomicU32>);
+
+    impl MessageHandler for Watching {
+        fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(HandlerResult::NotInterested)
+        }
+
+        fn role(&self) -> Role {
+            Role::Observe
+        }
+    }
+
+    #[test]
+    fn observers_after_consumers() {
+        let answered = Arc::new(AtomicU32::new(0));
+        let watched = Arc::new(AtomicU32::new(0));
+        let mut ctx = context();
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Counting(answered.clone())));
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Counting(answered.clone())));
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Watching(watched.clone())));
+        let ctx = Arc::new(ctx);
+
+        ctx.handle(&MessageRef::parse(":nick!user@host PRIVMSG #zebot-test :hi").unwrap());
+        // Only the first consumer answers, the observer registered last still sees it
+        assert_eq!(answered.load(Ordering::SeqCst), 1);
+        assert_eq!(watched.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn errors_do_not_stop_others() {
+        let seen = Arc::new(AtomicU32::new(0));
+        let mut ctx = context();
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Erroring));
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Counting(seen.clone())));
+        let ctx = Arc::new(ctx);
+
+        let msg = MessageRef::parse(":nick!user@host PRIVMSG #zebot-test :hi").unwrap();
+        ctx.handle(&msg);
+        ctx.handle(&msg);
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn priorities() {
+        let mut handlers = Vec::new();
+        insert_by_priority(&mut handlers, Arc::new(Handler::builtin(Failing)));
+        insert_by_priority(&mut handlers, Arc::new(Handler::builtin(PingHandler)));
+        insert_by_priority(&mut handlers, Arc::new(Handler::builtin(Early)));
+        let names = handlers.iter().map(|h| h.describe()).collect::<Vec<_>>();
+        assert_eq!(names, ["Early (observe, priority 10)", "Failing (consume)", "PingHandler (consume)"]);
+
+        insert_by_priority(&mut handlers, Arc::new(Handler::new(HandlerKind::Async(Arc::new(Claiming)))));
+        assert_eq!(handlers.last().unwrap().describe(), "Claiming (async, consume)");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ctcp_cooldown() {
+        let h = CtcpHandler::new();
+        let fritschy = Prefix::Server("fritschy".to_string());
+        let other = Prefix::Server("other".to_string());
+
+        assert!(!h.cooling_down(&fritschy));
+        assert!(!h.cooling_down(&other));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(h.cooling_down(&fritschy));
+        // Flooding keeps it quiet
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(h.cooling_down(&fritschy));
+        tokio::time::advance(CTCP_COOLDOWN).await;
+        assert!(!h.cooling_down(&fritschy));
+        assert!(!h.cooling_down(&other));
+        assert_eq!(h.last.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn disable_failing() {
+        let msg = Message::privmsg("#zebot-test", "hi").unwrap();
+        let error = || Ok(Ok(HandlerResult::Error("no disk".to_string())));
+        let panic = || std::panic::catch_unwind(|| -> Result<HandlerResult, std::io::Error> { panic!("at the disco") });
+
+        let h = Handler::new(HandlerKind::Sync(Box::new(Failing)));
+        assert_eq!(h.name(), "Failing");
+        assert!(!h.check(error(), &msg, Some(3)));
+        assert!(!h.check(panic(), &msg, Some(3)));
+        // Succeeding starts over
+        assert!(h.check(Ok(Ok(HandlerResult::Handled)), &msg, Some(3)));
+        assert!(!h.check(error(), &msg, Some(3)));
+        assert!(!h.check(panic(), &msg, Some(3)));
+        assert!(h.is_enabled());
+        h.check(error(), &msg, Some(3));
+        assert!(!h.is_enabled());
+
+        let h = Handler::builtin(Failing);
+        for _ in 0..3 {
+            h.check(error(), &msg, Some(3));
+        }
+        assert!(h.is_enabled());
+    }
+}
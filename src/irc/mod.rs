@@ -1,348 +1,1166 @@
-use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::io::{Read};
-use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::Instant;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use futures_util::{FutureExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf};
 use tokio::net::TcpStream;
+use tokio_util::codec::FramedRead;
 
 pub(crate) use irc2::command::*;
 pub use handler::*;
-use tokio::time::{Duration, timeout, sleep};
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::{Duration, timeout};
 
 use tracing::{error as log_error, info, warn};
-use tokio::sync::{RwLock, Mutex};
-use futures::executor::block_on;
-
-mod util;
+use irc2::{Capabilities, CaseMapping, Channels, Charsets, HostMask, ISupport, LineCodec, Mechanism, Message, MessageRef, Nicks, Prefix, Sasl, Target};
 
 mod handler;
 
-pub struct User {
-    pub nick: String,
-    pub user: String,
+mod tls;
+
+mod sasl;
+
+mod outbox;
+
+mod reconnect;
+
+pub use tls::{TlsConfig, Verify};
+pub use sasl::{SaslConfig, SaslFailure, SaslMechanism};
+pub(crate) use sasl::SaslState;
+use outbox::Outbox;
+pub use outbox::{FloodControl, Priority};
+pub use reconnect::{Backoff, ConnectionEvent};
+
+// A handler panicking while it holds one of our locks must not take the other handlers and the
+// connection down with it, whatever it left behind is used as it is
+fn read<T>(l: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    l.read().unwrap_or_else(PoisonError::into_inner)
 }
 
-impl User {
-    pub fn new(nick: &str, user: &str) -> Self {
-        User {
-            nick: nick.to_string(),
-            user: user.to_string(),
-        }
-    }
+fn write<T>(l: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    l.write().unwrap_or_else(PoisonError::into_inner)
 }
 
-struct ReaderBuf {
-    buf: RefCell<Vec<u8>>,
-    last: RefCell<Vec<u8>>,
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
-impl ReaderBuf {
-    fn new() -> Self {
-        ReaderBuf {
-            buf: RefCell::new(vec![0; 4096]),
-            last: RefCell::new(Vec::new()),
-        }
+/// The server to connect to
+pub struct Server {
+    pub host: String,
+    pub port: u16,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Server {
+    /// Parse 'host[:port]', IPv6 addresses need to be in brackets if there is a port. Without
+    /// a port, 6697 is used for TLS and 6667 otherwise.
+    pub fn new(addr: &str, tls: Option<TlsConfig>) -> Result<Self, std::io::Error> {
+        let default_port = if tls.is_some() { 6697 } else { 6667 };
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| std::io::Error::other(format!("Invalid port in '{}'", addr)))?;
+                (host, port)
+            }
+            _ => (addr, default_port),
+        };
+
+        Ok(Server {
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port,
+            tls,
+        })
     }
+}
 
-    fn fill_from_last(&self) -> usize {
-        let len = self.last.borrow().len();
-        if len > 0 {
-            let mut l = self.last.borrow_mut();
-            self.buf.borrow_mut()[..l.len()].copy_from_slice(l.as_slice());
-            let off = l.len();
-            l.clear();
-            off
+impl std::fmt::Display for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)?;
         } else {
-            0
+            write!(f, "{}:{}", self.host, self.port)?;
         }
+        if self.tls.is_some() {
+            write!(f, " (TLS)")?;
+        }
+        Ok(())
     }
+}
 
-    fn push_to_last(&self, i: &[u8]) {
-        let l = &mut self.last.borrow_mut();
-        let len = i.len();
-        l.resize(len, 0);
-        l[..len].copy_from_slice(i);
-    }
+/// How long to wait for RPL_LOGGEDIN before joining channels anyway
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
 
-    async fn read_from(&self, source: &mut TcpStream) -> Result<usize, std::io::Error> {
-        let off = self.fill_from_last();
+struct HandlerTask {
+    /// Casefolded channel or nick the handler answers to
+    target: Option<String>,
+    handle: AbortHandle,
+}
 
-        let bytes = source
-            .read(&mut self.buf.borrow_mut().as_mut_slice()[off..])
-            .await?;
+type Reader = FramedRead<ReadHalf<Box<dyn Connection>>, LineCodec>;
 
-        if bytes == 0 {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Read of length 0 from server",
-            ))
-        } else {
-            Ok(off + bytes)
+/// Lines read, but not handled yet
+const INCOMING_QUEUE: usize = 1024;
+
+/// The server pings us every few minutes, nothing for this long means the connection is gone
+const READ_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait for queued lines to be written before disconnecting
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why the server did not let us into a channel
+const JOIN_ERRORS: [Numeric; 7] = [
+    Numeric::ErrNoSuchChannel,
+    Numeric::ErrTooManyChannels,
+    Numeric::ErrChannelIsFull,
+    Numeric::ErrInviteOnlyChan,
+    Numeric::ErrBannedFromChan,
+    Numeric::ErrBadChannelKey,
+    // ERR_NEEDREGGEDNICK on most networks
+    Numeric::ErrNoChanModes,
+];
+
+/// Plain TCP or TLS
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// How to get our nick back from whoever is using it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NickRegain {
+    /// NickServ REGAIN, which also changes our nick
+    Regain,
+    /// NickServ GHOST, we change nick once the holder is gone
+    Ghost,
+    /// Only change nick when the holder quits or changes nick
+    Off,
+}
+
+pub struct User {
+    /// The nick we want, see `Context::nick()` for the one we have
+    pub nick: String,
+    pub user: String,
+    /// Tried in order if the nick is taken, before the nick with '_' appended
+    pub alt_nicks: Vec<String>,
+    pub regain: NickRegain,
+}
+
+impl User {
+    pub fn new(nick: &str, user: &str) -> Self {
+        User {
+            nick: nick.to_string(),
+            user: user.to_string(),
+            alt_nicks: Vec::new(),
+            regain: NickRegain::Ghost,
         }
     }
 }
 
 pub struct Context {
     pub user: User,
-    pub channels: RwLock<Vec<String>>,
+    /// Channels to join once registered, with their keys
+    pub channels: RwLock<Vec<(String, Option<String>)>>,
+    /// Channels the server confirmed we are in
     pub joined_channels: RwLock<Vec<String>>,
-    handlers: HashMap<CommandCode, Vec<Box<dyn MessageHandler>>>,
-    allmsg_handlers: Vec<Box<dyn MessageHandler>>,
-    pub connection: Mutex<TcpStream>,
-    bufs: ReaderBuf,
-    messages: Mutex<Vec<String>>,
-    shutdown: Cell<bool>,
-    last_flush: Cell<Instant>,
+    /// JOINs sent, but not yet confirmed or refused
+    pending_joins: RwLock<Vec<(String, Option<String>)>>,
+    /// Keys of the joined channels by their casefolded name, to join them again after a
+    /// reconnect
+    joined_keys: RwLock<HashMap<String, String>>,
+    /// The account we are logged in to, from RPL_LOGGEDIN
+    pub account: RwLock<Option<String>>,
+    registered_at: Mutex<Option<Instant>>,
+    wait_for_login: bool,
+    /// We gave up waiting for the login on this connection
+    login_wait_over: AtomicBool,
+    pub isupport: RwLock<ISupport>,
+    pub channel_state: RwLock<Channels>,
+    pub caps: RwLock<Capabilities>,
+    pub nicks: RwLock<Nicks>,
+    handlers: HashMap<CommandCode, Vec<Arc<Handler>>>,
+    allmsg_handlers: Vec<Arc<Handler>>,
+    /// Disable handlers that failed this many times in a row
+    disable_after: Option<u32>,
+    /// Async handlers still running
+    tasks: Mutex<Vec<HandlerTask>>,
+    /// The server we are connected to
+    server: RwLock<String>,
+    /// How many connections we made
+    connections: AtomicU32,
+    /// Taken by the reader task
+    reader: Mutex<Option<Reader>>,
+    /// Each connection gets its own
+    outbox: RwLock<Arc<Outbox>>,
+    charsets: Charsets,
+    flood: FloodControl,
+    shutdown: AtomicBool,
     password_file: String,
+    sasl_config: Option<SaslConfig>,
+    pub(crate) sasl: RwLock<SaslState>,
+    ignored: Vec<HostMask>,
 }
 
 impl Context {
-    pub async fn connect(addr: SocketAddr, user: User, password_file: Option<String>) -> Result<Self, std::io::Error> {
-        let c = TcpStream::connect(addr).await?;
-        c.set_nodelay(true)?;
-
-        let connection = Mutex::new(c);
-
-        let mut handlers: HashMap<CommandCode, Vec<Box<dyn MessageHandler>>> = HashMap::new();
-        handlers.insert(CommandCode::Ping, vec![Box::new(PingHandler)]);
+    /// Set up what stays the same across connections, see `connect()`
+    pub fn new(
+        user: User,
+        password_file: Option<String>,
+        sasl: Option<SaslConfig>,
+        charsets: Charsets,
+        flood: FloodControl,
+    ) -> Self {
+        let mut handlers: HashMap<CommandCode, Vec<Arc<Handler>>> = HashMap::new();
+        handlers.insert(CommandCode::Ping, vec![Arc::new(Handler::builtin(PingHandler))]);
+        handlers.insert(CommandCode::PrivMsg, vec![Arc::new(Handler::builtin(CtcpHandler::new()))]);
+        handlers.insert(CommandCode::Numeric(Numeric::RplISupport), vec![Arc::new(Handler::builtin(ISupportHandler))]);
+        handlers.insert(CommandCode::Cap, vec![Arc::new(Handler::builtin(CapHandler))]);
+        handlers.insert(CommandCode::Numeric(Numeric::RplWelcome), vec![Arc::new(Handler::builtin(CapHandler)), Arc::new(Handler::builtin(IdentifyHandler))]);
+        handlers.insert(CommandCode::Authenticate, vec![Arc::new(Handler::builtin(SaslHandler))]);
+        for n in 900..=908 {
+            handlers.insert(CommandCode::Numeric(Numeric::from(n)), vec![Arc::new(Handler::builtin(SaslHandler))]);
+        }
+        for n in [Numeric::RplLoggedIn, Numeric::RplLoggedOut] {
+            handlers.entry(CommandCode::Numeric(n)).or_default().insert(0, Arc::new(Handler::builtin(AccountHandler)));
+        }
+        for c in [CommandCode::Join, CommandCode::Part, CommandCode::Kick] {
+            handlers.insert(c, vec![Arc::new(Handler::builtin(JoinHandler))]);
+        }
+        for n in JOIN_ERRORS {
+            handlers.insert(CommandCode::Numeric(n), vec![Arc::new(Handler::builtin(JoinHandler))]);
+        }
 
-        let allmsg_handlers: Vec<Box<dyn MessageHandler>> = Vec::new();
+        // The channel state needs to see our own NICK changes before the nick is updated
+        let allmsg_handlers: Vec<Arc<Handler>> = vec![Arc::new(Handler::builtin(ChannelStateHandler)), Arc::new(Handler::builtin(NickHandler))];
         // XXX: disable print handler, rely on irc2::parse_ng() output.
         // allmsg_handlers.push(Box::new(PrintMessageHandler::new()));
 
-        Ok(Context {
-            bufs: ReaderBuf::new(),
+        // Closed until we connect
+        let outbox = Arc::new(Outbox::default());
+        outbox.close();
+
+        Context {
             channels: RwLock::new(Vec::new()),
             joined_channels: RwLock::new(Vec::new()),
-            messages: Mutex::new(Vec::new()),
-            shutdown: Cell::new(false),
+            pending_joins: RwLock::new(Vec::new()),
+            joined_keys: RwLock::new(HashMap::new()),
+            account: RwLock::new(None),
+            registered_at: Mutex::new(None),
+            wait_for_login: false,
+            login_wait_over: AtomicBool::new(false),
+            isupport: RwLock::new(ISupport::default()),
+            channel_state: RwLock::new(Channels::new()),
+            caps: RwLock::new(Capabilities::new()),
+            nicks: RwLock::new(Nicks::new(&user.nick, &user.alt_nicks.iter().map(String::as_str).collect::<Vec<_>>())),
+            shutdown: AtomicBool::new(false),
             allmsg_handlers,
-            connection,
+            disable_after: None,
+            tasks: Mutex::new(Vec::new()),
+            server: RwLock::new(String::new()),
+            connections: AtomicU32::new(0),
+            reader: Mutex::new(None),
+            outbox: RwLock::new(outbox),
+            charsets,
+            flood,
             handlers,
             user,
-            last_flush: Cell::new(Instant::now()),
             password_file: password_file.unwrap_or_else(|| String::from("password.txt")),
-        })
+            sasl_config: sasl,
+            sasl: RwLock::new(SaslState::Disabled),
+            ignored: Vec::new(),
+        }
+    }
+
+    /// Connect to a server, `run()` the connection afterwards. Everything we learned from the
+    /// previous server is forgotten, but the channels we were in are joined again.
+    pub async fn connect(&self, server: &Server) -> Result<(), std::io::Error> {
+        info!("Connecting to {}", server);
+        let c = TcpStream::connect((server.host.as_str(), server.port)).await?;
+        c.set_nodelay(true)?;
+
+        let c: Box<dyn Connection> = match &server.tls {
+            Some(tls) => Box::new(tls.connect(&server.host, c).await?),
+            None => Box::new(c),
+        };
+
+        self.attach(&server.to_string(), c);
+        Ok(())
+    }
+
+    // Start over on a new connection
+    fn attach(&self, server: &str, c: Box<dyn Connection>) {
+        let (r, w) = tokio::io::split(c);
+        let mut codec = LineCodec::new();
+        codec.set_charsets(self.charsets.clone());
+        *lock(&self.reader) = Some(FramedRead::new(r, codec));
+
+        // Writing happens on its own, at the pace the flood control allows
+        let flood = self.flood.clone();
+        info!("Sending at most {} lines at once, then one every {} ms", flood.burst(), flood.refill().as_millis());
+        let outbox = Arc::new(Outbox::default());
+        tokio::spawn(outbox::write_loop(outbox.clone(), w, flood, self.charsets.clone()));
+        *write(&self.outbox) = outbox;
+
+        self.rejoin();
+        *write(&self.account) = None;
+        *lock(&self.registered_at) = None;
+        self.login_wait_over.store(false, Ordering::SeqCst);
+        *write(&self.isupport) = ISupport::default();
+        *write(&self.channel_state) = Channels::new();
+        *write(&self.caps) = Capabilities::new();
+        *write(&self.sasl) = SaslState::Disabled;
+        self.shutdown.store(false, Ordering::SeqCst);
+
+        *write(&self.server) = server.to_string();
+        let reconnect = self.connections.fetch_add(1, Ordering::SeqCst) > 0;
+        self.connection_event(&ConnectionEvent::Connected {
+            server: server.to_string(),
+            reconnect,
+        });
+    }
+
+    // What we were in or about to join is what we want to be in on the next server
+    fn rejoin(&self) {
+        let cm = self.casemapping();
+        let pending = std::mem::take(&mut *write(&self.pending_joins));
+        let joined = std::mem::take(&mut *write(&self.joined_channels));
+        let mut keys = std::mem::take(&mut *write(&self.joined_keys));
+
+        let mut channels = write(&self.channels);
+        channels.extend(pending);
+        for chan in joined {
+            let key = keys.remove(&cm.fold(&chan));
+            channels.push((chan, key));
+        }
     }
 
-    pub fn nick(&self) -> &String {
-        &self.user.nick
+    fn outbox(&self) -> Arc<Outbox> {
+        read(&self.outbox).clone()
     }
 
-    pub async fn join(&self, chan: &str) {
-        self.channels.write().await.push(chan.to_string());
+    /// Whether the server accepted our registration on this connection
+    pub fn is_registered(&self) -> bool {
+        lock(&self.registered_at).is_some()
     }
 
-    pub async fn leave(&self, chan: &str) {
-        if let Some(c) = self.channels.read().await.iter().position(|x| x == chan) {
-            self.channels.write().await.remove(c);
+    // Tell every handler, a handler panicking here does not keep the others from knowing
+    fn connection_event(&self, event: &ConnectionEvent) {
+        info!("{}", event);
+        for h in self.allmsg_handlers.iter().chain(self.handlers.values().flatten()) {
+            let r = std::panic::catch_unwind(AssertUnwindSafe(|| match &h.kind {
+                HandlerKind::Sync(s) => s.connection_event(self, event),
+                HandlerKind::Async(a) => a.connection_event(self, event),
+            }));
+            if r.is_err() {
+                log_error!("Handler {} panicked on '{}'", h.name(), event);
+            }
+        }
+    }
+
+    /// Our current nick, which may not be the one we want
+    pub fn nick(&self) -> String {
+        read(&self.nicks).current().to_string()
+    }
+
+    /// Whether the server enabled an IRCv3 capability for us
+    #[allow(unused)]
+    pub fn has_cap(&self, cap: &str) -> bool {
+        read(&self.caps).is_enabled(cap)
+    }
+
+    pub fn casemapping(&self) -> CaseMapping {
+        read(&self.isupport).casemapping
+    }
+
+    pub fn target(&self, name: &str) -> Target {
+        read(&self.isupport).target(name)
+    }
+
+    /// Where to send an answer to msg, i.e. the channel it was sent to or the sender
+    pub fn response_destination(&self, msg: &Message) -> String {
+        msg.get_reponse_destination(&read(&self.joined_channels), &read(&self.isupport))
+    }
+
+    /// Join a channel, with its key if it has one, once we are registered
+    pub fn join(&self, chan: &str, key: Option<&str>) {
+        write(&self.channels).push((chan.to_string(), key.map(String::from)));
+        self.join_channels();
+    }
+
+    /// Leave a channel, the server's PART echo removes it from the joined channels
+    pub fn leave(&self, chan: &str) {
+        let cm = self.casemapping();
+        let p = read(&self.channels).iter().position(|(x, _)| cm.eq(x, chan));
+        if let Some(c) = p {
+            write(&self.channels).remove(c);
+        } else if read(&self.joined_channels).iter().any(|x| cm.eq(x, chan)) {
+            match Message::part(chan, None) {
+                Ok(m) => self.send(m),
+                Err(e) => log_error!("Can not leave {}: {}", chan, e),
+            }
+        }
+    }
+
+    /// Wait with joining channels until we are logged in to our account, for channels that
+    /// only let in identified users. Joins anyway after a while.
+    pub fn wait_for_login(&mut self) {
+        self.wait_for_login = true;
+    }
+
+    fn may_join(&self) -> bool {
+        let registered_at = match *lock(&self.registered_at) {
+            Some(t) => t,
+            None => return false,
+        };
+
+        if !self.wait_for_login || self.login_wait_over.load(Ordering::SeqCst) || read(&self.account).is_some() {
+            true
+        } else if registered_at.elapsed() > LOGIN_TIMEOUT {
+            warn!("Not logged in after {} seconds, joining channels anyway", LOGIN_TIMEOUT.as_secs());
+            self.login_wait_over.store(true, Ordering::SeqCst);
+            true
         } else {
-            let p = self.joined_channels.read().await.iter().position(|x| x == chan);
-            if let Some(c) = p {
-                self.joined_channels.write().await.remove(c);
-                let cmd = format!("PART {}\r\n", chan);
-                self.send(cmd);
+            false
+        }
+    }
+
+    /// Join the channels we want to be in, once registered. They count as joined when the
+    /// server echoes the JOIN.
+    fn join_channels(&self) {
+        if read(&self.channels).is_empty() || !self.may_join() {
+            return;
+        }
+
+        let channels = std::mem::take(&mut *write(&self.channels));
+        for (chan, key) in channels {
+            match Message::join(&chan, key.as_deref()) {
+                Ok(m) => {
+                    self.send(m);
+                    write(&self.pending_joins).push((chan, key));
+                }
+                Err(e) => log_error!("Can not join {}: {}", chan, e),
             }
         }
     }
 
-    pub fn logon(&self) {
-        let msg = format!(
-            "USER {} none none :The Bot\r\nNICK :{}\r\n",
-            self.user.nick, self.user.nick,
-        );
+    pub(crate) fn set_registered(&self) {
+        *lock(&self.registered_at) = Some(Instant::now());
+    }
+
+    /// The server confirmed a JOIN
+    pub(crate) fn joined(&self, chan: &str) {
+        let cm = self.casemapping();
+        let mut pending = write(&self.pending_joins);
+        if let Some((_, Some(key))) = pending.iter().find(|(x, _)| cm.eq(x, chan)) {
+            write(&self.joined_keys).insert(cm.fold(chan), key.clone());
+        }
+        pending.retain(|(x, _)| !cm.eq(x, chan));
+        drop(pending);
+
+        let mut joined = write(&self.joined_channels);
+        if !joined.iter().any(|x| cm.eq(x, chan)) {
+            info!("Joined {}", chan);
+            joined.push(chan.to_string());
+        }
+    }
 
+    /// The server refused a JOIN, returns whether we actually tried to join the channel
+    pub(crate) fn join_failed(&self, chan: &str) -> bool {
+        let cm = self.casemapping();
+        let mut pending = write(&self.pending_joins);
+        let n = pending.len();
+        pending.retain(|(x, _)| !cm.eq(x, chan));
+        pending.len() != n
+    }
+
+    /// We left a channel or were kicked
+    pub(crate) fn left(&self, chan: &str) {
+        let cm = self.casemapping();
+        write(&self.pending_joins).retain(|(x, _)| !cm.eq(x, chan));
+        write(&self.joined_channels).retain(|x| !cm.eq(x, chan));
+        write(&self.joined_keys).remove(&cm.fold(chan));
+    }
+
+    fn logon(&self) {
         info!("Logging on with {} as {}", self.user.user, self.user.nick);
 
-        self.send(msg);
+        let sasl = match self.sasl_config.map(|c| self.sasl_mechanism(c.mechanism)) {
+            Some(Ok(m)) => Some(Sasl::new(m)),
+            Some(Err(e)) => {
+                log_error!("Can not use SASL: {}", e);
+                if self.sasl_failed().is_err() {
+                    return;
+                }
+                None
+            }
+            None => None,
+        };
+
+        // Negotiate capabilities first, the server waits for CAP END before registering us
+        let start = {
+            let mut caps = write(&self.caps);
+            for h in self.allmsg_handlers.iter().chain(self.handlers.values().flatten()) {
+                for cap in h.capabilities() {
+                    caps.want(cap);
+                }
+            }
 
-        if let Err(e) = std::fs::File::open(&self.password_file).and_then(|mut f| {
-            let mut pw = String::new();
-            f.read_to_string(&mut pw)?;
-            self.message("NickServ", &format!("identify {}", pw.trim()));
-            Ok(())
-        }) {
-            warn!("Could not open password file {}: {:?}", &self.password_file, e);
+            // SASL happens between CAP ACK and CAP END
+            if let Some(sasl) = sasl {
+                info!("Authenticating with SASL {}", sasl.mechanism().name());
+                caps.want("sasl");
+                caps.hold();
+                *write(&self.sasl) = SaslState::Pending(sasl);
+            }
+
+            caps.start()
+        };
+        self.send_priority(Priority::Protocol, start);
+
+        match Message::user(&self.user.nick, &self.user.user) {
+            Ok(user) => {
+                self.send_priority(Priority::Protocol, user);
+                let nick = write(&self.nicks).start();
+                self.send_priority(Priority::Protocol, nick);
+            }
+            Err(e) => log_error!("Can not log on: {}", e),
         }
     }
 
-    pub fn is_shutdown(&self) -> bool {
-        self.shutdown.get()
+    fn read_password(&self) -> Result<String, std::io::Error> {
+        let mut pw = String::new();
+        std::fs::File::open(&self.password_file)?.read_to_string(&mut pw)?;
+        Ok(pw.trim().to_string())
     }
 
-    pub fn quit(&self) {
-        self.shutdown.replace(true);
-        block_on(async {
-            loop {
-                match self.messages.try_lock() {
-                    Ok(mut msgs) => {
-                        msgs.clear();
-                        break;
-                    }
-                    Err(_) => {
-                        sleep(Duration::from_millis(100)).await
-                    }
-                }
+    /// The password file has either the password, or the account name and the password
+    fn sasl_mechanism(&self, mechanism: SaslMechanism) -> Result<Mechanism, std::io::Error> {
+        match mechanism {
+            SaslMechanism::External => Ok(Mechanism::External),
+            SaslMechanism::Plain => {
+                let pw = self.read_password().map_err(|e| {
+                    std::io::Error::other(format!("Could not read password file {}: {}", self.password_file, e))
+                })?;
+                let (account, password) = match pw.split_once(char::is_whitespace) {
+                    Some((account, password)) => (account.to_string(), password.trim().to_string()),
+                    None => (self.user.nick.clone(), pw),
+                };
+                Ok(Mechanism::Plain { account, password })
             }
-        });
-        self.send("QUIT :Need to restart the Kubernetes VM\r\n".to_string());
-    }
-
-    pub fn send(&self, msg: String) {
-        block_on(async {
-            let mut max = 10;
-            loop {
-                match self.messages.try_lock() {
-                    Ok(mut msgs) => {
-                        msgs.push(msg);
-                        break;
-                    }
-                    Err(_) => {
-                        if max > 0 {
-                            max -= 1;
-                        } else {
-                            log_error!("Can not send message '{}'", msg);
-                            break;
-                        }
-                        sleep(Duration::from_millis(100)).await
-                    }
+        }
+    }
+
+    fn has_primary_nick(&self) -> bool {
+        read(&self.nicks).has_primary(&read(&self.isupport))
+    }
+
+    /// Identify with NickServ, after registration. With another nick, name the account.
+    pub(crate) fn identify(&self) {
+        match self.read_password() {
+            Ok(pw) if !self.has_primary_nick() && !pw.contains(char::is_whitespace) => {
+                self.message("NickServ", &format!("identify {} {}", self.user.nick, pw))
+            }
+            Ok(pw) => self.message("NickServ", &format!("identify {}", pw)),
+            Err(e) => warn!("Could not open password file {}: {:?}", &self.password_file, e),
+        }
+    }
+
+    /// Ask NickServ to free our nick if someone else has it, after identifying
+    pub(crate) fn regain_nick(&self) {
+        if self.has_primary_nick() {
+            return;
+        }
+
+        match self.user.regain {
+            NickRegain::Regain => self.message("NickServ", &format!("REGAIN {}", self.user.nick)),
+            NickRegain::Ghost => self.message("NickServ", &format!("GHOST {}", self.user.nick)),
+            NickRegain::Off => (),
+        }
+    }
+
+    /// SASL worked out, registration can go on
+    pub(crate) fn sasl_succeeded(&self) {
+        *write(&self.sasl) = SaslState::Succeeded;
+        let end = write(&self.caps).release();
+        if let Some(m) = end {
+            self.send_priority(Priority::Protocol, m);
+        }
+    }
+
+    /// SASL did not work out, either quit or go on without it, depending on the configuration
+    pub(crate) fn sasl_failed(&self) -> Result<(), std::io::Error> {
+        *write(&self.sasl) = SaslState::Failed;
+        let end = write(&self.caps).release();
+
+        match self.sasl_config.map(|c| c.on_failure) {
+            Some(SaslFailure::Abort) => {
+                log_error!("SASL authentication failed, giving up");
+                self.quit();
+                Err(std::io::Error::other("SASL authentication failed"))
+            }
+            _ => {
+                warn!("SASL authentication failed, identifying with NickServ instead");
+                if let Some(m) = end {
+                    self.send_priority(Priority::Protocol, m);
                 }
+                Ok(())
             }
-        });
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Quit, after the answers that are still queued, but without the bulk output
+    pub fn quit(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.outbox().clear(Priority::Bulk);
+        self.send(Message::quit(Some("Need to restart the Kubernetes VM")).unwrap());
+    }
+
+    /// Queue a message for sending, see the typed constructors of `Message`
+    pub fn send(&self, msg: Message) {
+        self.send_priority(Priority::Interactive, msg);
+    }
+
+    /// Queue a message, tagged with its channel or nick so bulk output can be cancelled
+    pub fn send_priority(&self, priority: Priority, msg: Message) {
+        let target = match msg.command {
+            CommandCode::PrivMsg | CommandCode::Notice => msg.params.first().map(|t| self.casemapping().fold(t)),
+            _ => None,
+        };
+
+        match msg.encode() {
+            Ok(line) => self.outbox().push(priority, target, line),
+            Err(e) => log_error!("Not sending message {}: {}", msg, e),
+        }
+    }
+
+    /// Send long output, after everything else that is waiting
+    pub fn message_bulk<S: AsRef<str>>(&self, dst: &str, lines: impl IntoIterator<Item = S>) {
+        for l in lines {
+            match Message::privmsg(dst, l.as_ref()) {
+                Ok(m) => self.send_priority(Priority::Bulk, m),
+                Err(e) => log_error!("Not sending message to {}: {}", dst, e),
+            }
+        }
+    }
+
+    /// Drop the bulk output for a channel or nick that was not sent yet, and cancel the async
+    /// handlers still working on an answer for it
+    pub fn cancel_bulk(&self, dst: &str) -> usize {
+        let dst = self.casemapping().fold(dst);
+        self.cancel_tasks(Some(&dst));
+        self.outbox().cancel(&dst)
     }
 
     pub fn message(&self, dst: &str, msg: &str) {
-        let msg = format!("PRIVMSG {} :{}\r\n", dst, msg);
-        self.send(msg);
+        match Message::privmsg(dst, msg) {
+            Ok(m) => self.send(m),
+            Err(e) => log_error!("Not sending message to {}: {}", dst, e),
+        }
+    }
+
+    pub fn notice(&self, dst: &str, msg: &str) {
+        match Message::notice(dst, msg) {
+            Ok(m) => self.send(m),
+            Err(e) => log_error!("Not sending notice to {}: {}", dst, e),
+        }
+    }
+
+    /// Ignore all messages from users matching the given mask
+    pub fn ignore(&mut self, mask: HostMask) {
+        info!("Ignoring {}", mask);
+        self.ignored.push(mask);
+    }
+
+    fn is_ignored(&self, msg: &MessageRef<'_>) -> bool {
+        if self.ignored.is_empty() {
+            return false;
+        }
+        match msg.prefix().map(|p| p.to_prefix()) {
+            Some(Prefix::Nickname(n)) => self.ignored.iter().any(|m| m.matches(&n)),
+            _ => false,
+        }
     }
 
     #[allow(unused)]
     pub fn register_handler(&mut self, code: CommandCode, h: Box<dyn MessageHandler>) {
-        if let CommandCode::Unknown = code {
-            self.allmsg_handlers.push(h);
+        self.add_handler(code, Handler::new(HandlerKind::Sync(h)));
+    }
+
+    pub fn register_async_handler(&mut self, code: CommandCode, h: Box<dyn AsyncMessageHandler>) {
+        self.add_handler(code, Handler::new(HandlerKind::Async(h.into())));
+    }
+
+    fn add_handler(&mut self, code: CommandCode, h: Handler) {
+        let h = Arc::new(h);
+        let handlers = if let CommandCode::Unknown = code {
+            &mut self.allmsg_handlers
         } else {
             self.handlers
                 .entry(code)
                 .or_insert_with(|| Vec::with_capacity(1))
-                .push(h);
+        };
+        insert_by_priority(handlers, h);
+    }
+
+    /// The handlers each command goes through, in order
+    pub fn pipeline(&self) -> Vec<String> {
+        let describe = |handlers: &[Arc<Handler>]| handlers.iter().map(|h| h.describe()).collect::<Vec<_>>().join(" -> ");
+
+        let mut commands = self
+            .handlers
+            .iter()
+            .map(|(code, handlers)| format!("{}: {}", code, describe(handlers)))
+            .collect::<Vec<_>>();
+        commands.sort();
+        commands.insert(0, format!("All messages: {}", describe(&self.allmsg_handlers)));
+        commands
+    }
+
+    /// Disable handlers, except the built-in ones, once they failed `n` times in a row
+    pub fn disable_failing_handlers(&mut self, n: u32) {
+        self.disable_after = Some(n.max(1));
+    }
+
+    /// Start reading and dispatching on their own tasks after `connect()`, and log on. The
+    /// returned task ends when the connection does, with `Ok` if we `quit()` and with an error
+    /// if the connection broke.
+    pub fn run(self: &Arc<Self>) -> JoinHandle<Result<(), std::io::Error>> {
+        let reader = lock(&self.reader).take().expect("Context::run() without connect()");
+        let (tx, rx) = mpsc::channel(INCOMING_QUEUE);
+        let reader = tokio::spawn(read_loop(reader, tx));
+
+        if self.connections.load(Ordering::SeqCst) <= 1 {
+            for line in self.pipeline() {
+                info!("Handlers for {}", line);
+            }
         }
+
+        let ctx = self.clone();
+        tokio::spawn(async move {
+            ctx.logon();
+            let r = ctx.dispatch(rx).await;
+            reader.abort();
+            ctx.cancel_tasks(None);
+            ctx.outbox().close();
+
+            let reason = match &r {
+                Ok(()) => "Quit".to_string(),
+                Err(e) => e.to_string(),
+            };
+            let server = read(&ctx.server).clone();
+            ctx.connection_event(&ConnectionEvent::Disconnected { server, reason });
+            r
+        })
     }
 
-    async fn send_pending_messages(&self, connection: &mut TcpStream) -> Result<(), std::io::Error> {
-        let mut messages = self.messages.lock().await;
+    async fn dispatch(self: &Arc<Self>, mut rx: mpsc::Receiver<Result<String, std::io::Error>>) -> Result<(), std::io::Error> {
+        // For what does not wait for a message, e.g. joining after the login timeout
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            if self.is_shutdown() {
+                self.outbox().flush(FLUSH_TIMEOUT).await;
+                return Ok(());
+            }
+
+            self.join_channels();
 
-        if messages.is_empty() {
-            return Ok(());
+            let line = tokio::select! {
+                line = rx.recv() => match line {
+                    Some(Ok(line)) => line,
+                    // The server closes the connection once we quit
+                    _ if self.is_shutdown() => return Ok(()),
+                    Some(Err(e)) => return Err(e),
+                    None => return Err(std::io::Error::other("Reader stopped")),
+                },
+                _ = tick.tick() => continue,
+            };
+
+            let msg = match MessageRef::parse(&line) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log_error!("Skipping malformed line '{}': {:?}", line, e);
+                    continue;
+                }
+            };
+
+            // Take special care for error messages, the server is about to close the connection
+            if msg.command() == CommandCode::Error {
+                if self.is_shutdown() {
+                    return Ok(());
+                }
+                log_error!("Got ERROR message: {}, closing down", line);
+                return Err(std::io::Error::other("Got irc command ERROR"));
+            }
+
+            self.handle(&msg);
+        }
+    }
+
+    fn handle(self: &Arc<Self>, msg: &MessageRef<'_>) {
+        // Only built once a handler accepts the message
+        let mut owned = None;
+
+        // These see everything, ignored users still join, part and change modes
+        self.run_handlers(&self.allmsg_handlers, msg, &mut owned);
+
+        if self.is_ignored(msg) {
+            info!("Ignoring message {}", msg.as_str());
+            return;
+        }
+
+        if let Some(handlers) = self.handlers.get(&msg.command()) {
+            self.run_handlers(handlers, msg, &mut owned);
         }
+    }
 
-        fn more_time(count: usize) -> u64 {
-            if count > 8 {
-                (count as u64 - 9) * 100
-            } else {
-                0
+    // Once a consumer answered, only the observers get to see the message
+    fn run_handlers(self: &Arc<Self>, handlers: &[Arc<Handler>], msg: &MessageRef<'_>, owned: &mut Option<Message>) {
+        let mut handled = false;
+        for h in handlers.iter() {
+            if handled && h.role() == Role::Consume {
+                continue;
+            }
+            if !h.is_enabled() || !h.accepts(msg) {
+                continue;
+            }
+            let msg = owned.get_or_insert_with(|| msg.to_message());
+            if self.run_handler(h, msg) && h.role() == Role::Consume {
+                handled = true;
             }
         }
+    }
 
-        let offset = if (Instant::now() - self.last_flush.get()).as_millis() < 2000 {
-            400
-        } else {
-            0
+    // Errors and panics stay with the handler, returns whether it claimed the message. Async
+    // handlers claim every message they are started for.
+    fn run_handler(self: &Arc<Self>, h: &Arc<Handler>, msg: &Message) -> bool {
+        match &h.kind {
+            HandlerKind::Sync(s) => {
+                let r = std::panic::catch_unwind(AssertUnwindSafe(|| s.handle(self, msg)));
+                h.check(r, msg, self.disable_after)
+            }
+            HandlerKind::Async(a) => {
+                self.spawn_handler(h, a, msg);
+                true
+            }
+        }
+    }
+
+    // Run an async handler on its own task, it can be cancelled with the channel or nick
+    // answered to
+    fn spawn_handler(self: &Arc<Self>, h: &Arc<Handler>, a: &Arc<dyn AsyncMessageHandler>, msg: &Message) {
+        let target = match msg.command {
+            CommandCode::PrivMsg | CommandCode::Notice => Some(self.casemapping().fold(&self.response_destination(msg))),
+            _ => None,
         };
 
-        for (count, m) in messages.drain(..).enumerate() {
-            connection.write_all(m.as_bytes()).await?;
-            // This does not take into account messages sent with the previous commits...
-            sleep(Duration::from_millis(400 + offset + more_time(count))).await;
+        let ctx = self.clone();
+        let h = h.clone();
+        let a = a.clone();
+        let msg = msg.clone();
+        let task = tokio::spawn(async move {
+            let max = a.timeout();
+            match timeout(max, AssertUnwindSafe(a.handle(&ctx, &msg)).catch_unwind()).await {
+                Ok(r) => {
+                    h.check(r, &msg, ctx.disable_after);
+                }
+                Err(_) => h.failed(&format!("took longer than {} seconds, cancelled", max.as_secs()), &msg, ctx.disable_after),
+            }
+        });
+
+        let mut tasks = lock(&self.tasks);
+        tasks.retain(|t| !t.handle.is_finished());
+        tasks.push(HandlerTask { target, handle: task.abort_handle() });
+    }
+
+    /// Cancel the async handlers answering to a casefolded target, or all of them
+    fn cancel_tasks(&self, target: Option<&str>) {
+        let mut tasks = lock(&self.tasks);
+        tasks.retain(|t| {
+            let cancel = target.is_none() || t.target.as_deref() == target;
+            if cancel && !t.handle.is_finished() {
+                info!("Cancelling message handler for {}", t.target.as_deref().unwrap_or("the server"));
+                t.handle.abort();
+            }
+            !cancel
+        });
+    }
+}
+
+/// Reads messages and hands them to the dispatcher, so slow handlers do not hold up reading
+async fn read_loop(mut reader: Reader, tx: mpsc::Sender<Result<String, std::io::Error>>) {
+    loop {
+        let msg = match timeout(READ_TIMEOUT, reader.next()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => Err(std::io::Error::other("Connection closed by server")),
+            Err(e) => Err(e.into()),
+        };
+
+        let failed = msg.is_err();
+        if tx.send(msg).await.is_err() || failed {
+            break;
         }
+    }
+}
 
-        self.last_flush.set(Instant::now());
+impl Drop for Context {
+    fn drop(&mut self) {
+        // Let the writer finish what is queued and stop
+        self.outbox().close();
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf};
+
+    pub(crate) fn context() -> Context {
+        Context::new(User::new("ZeBot", "zebot"), None, None, Charsets::new(), FloodControl::new(10, Duration::from_secs(1)))
+    }
+
+    // Connect to a fake server, returns its end of the connection
+    fn connect(ctx: &Context, max_buf_size: usize) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(max_buf_size);
+        ctx.attach("fake", Box::new(client));
+        server
+    }
+
+    // Throw away what we send to the fake server
+    async fn drain(mut r: ReadHalf<DuplexStream>) {
+        let mut buf = [0; 1024];
+        while matches!(r.read(&mut buf).await, Ok(n) if n > 0) {}
     }
 
-    pub async fn update(&self) -> Result<(), std::io::Error> {
-        if self.shutdown.get() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Connection shutdown requested"
-            ));
+    struct Blocking {
+        release: std::sync::Mutex<std::sync::mpsc::Receiver<()>>,
+        seen: Arc<AtomicU32>,
+    }
+
+    impl MessageHandler for Blocking {
+        fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            self.release.lock().unwrap().recv().unwrap();
+            self.seen.fetch_add(1, Ordering::SeqCst);
+            Ok(HandlerResult::Handled)
         }
+    }
+
+    // Answers after a minute, in the channel it was asked in
+    struct Sleepy {
+        timeout: Duration,
+        answered: Arc<Mutex<Vec<String>>>,
+    }
 
-        // Join channels we want to join...
-        if !self.channels.read().await.is_empty() {
-            let joins = self
-                .channels
-                .read().await
-                .iter()
-                .fold(String::new(), |acc, x| format!("{}JOIN :{}\r\n", acc, x));
-            self.joined_channels
-                .write().await
-                .append(&mut *self.channels.write().await);
-            self.send(joins);
+    #[async_trait::async_trait]
+    impl AsyncMessageHandler for Sleepy {
+        async fn handle(&self, _ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            self.answered.lock().unwrap().push(msg.params[0].clone());
+            Ok(HandlerResult::Handled)
         }
 
-        let bytes = {
-            let conn = &mut self.connection.lock().await;
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+    }
 
-            self.send_pending_messages(conn).await?;
+    fn sleepy(timeout: Duration) -> (Arc<Context>, Arc<Mutex<Vec<String>>>) {
+        let answered = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = context();
+        ctx.disable_failing_handlers(1);
+        ctx.register_async_handler(CommandCode::PrivMsg, Box::new(Sleepy { timeout, answered: answered.clone() }));
+        ctx.joined_channels.write().unwrap().extend(["#a".to_string(), "#b".to_string()]);
+        (Arc::new(ctx), answered)
+    }
 
-            // try to timeout ...
-            timeout(Duration::from_secs(5 * 60),
-                    self.bufs.read_from(conn)
-            ).await??
-        };
+    #[tokio::test(start_paused = true)]
+    async fn async_handler_timeout() {
+        let (ctx, answered) = sleepy(Duration::from_secs(10));
+        ctx.handle(&MessageRef::parse("PRIVMSG #a :hi").unwrap());
 
-        let mut i = &self.bufs.buf.borrow()[..bytes];
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        assert!(answered.lock().unwrap().is_empty());
+        // Failed once, which is enough to be disabled here
+        assert!(!ctx.handlers[&CommandCode::PrivMsg].last().unwrap().is_enabled());
+    }
 
-        loop {
-            match irc2::parse(i) {
-                Ok((r, msg)) => {
-                    i = r;
-
-                    // Take special care for error messages
-                    if msg.command == CommandCode::Error {
-                        log_error!("Got ERROR message: {}, closing down", msg);
-                        self.quit();
-                        block_on(async { self.update().await })?;
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Got irc command ERROR"));
-                    }
-
-                    for h in self.allmsg_handlers.iter() {
-                        h.handle(self, &msg)?;
-                    }
-
-                    self.handlers
-                        .get(&msg.command)
-                        .map(|x| -> Result<(), std::io::Error> {
-                            for h in x.iter() {
-                                match h.handle(self, &msg)? {
-                                    HandlerResult::Error(x) => {
-                                        log_error!("Message handler errored: {}", x)
-                                    }
-                                    HandlerResult::Handled => break, // Really?
-                                    _ => (),
-                                }
-                            }
-                            Ok(())
-                        });
-                }
+    #[tokio::test(start_paused = true)]
+    async fn cancel_bulk_cancels_handlers() {
+        let (ctx, answered) = sleepy(Duration::from_secs(120));
+        ctx.handle(&MessageRef::parse("PRIVMSG #a :hi").unwrap());
+        ctx.handle(&MessageRef::parse("PRIVMSG #b :hi").unwrap());
 
-                // Input ended, no remaining bytes, just continue as normal
-                Err(e) if e.is_incomplete() => {
-                    info!("Need to read more, irc2::parse: {:?}", e);
-                    self.bufs.push_to_last(i);
-                    break;
-                }
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        ctx.cancel_bulk("#A");
 
-                _ => {
-                    self.bufs.push_to_last(i);
-                    break;
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(*answered.lock().unwrap(), ["#b"]);
+        // Being cancelled is no failure
+        assert!(ctx.handlers[&CommandCode::PrivMsg].last().unwrap().is_enabled());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn slow_handler_does_not_stop_reading() {
+        let (release, blocked) = std::sync::mpsc::channel();
+        let seen = Arc::new(AtomicU32::new(0));
+        let mut ctx = context();
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Blocking {
+            release: std::sync::Mutex::new(blocked),
+            seen: seen.clone(),
+        }));
+        let ctx = Arc::new(ctx);
+        let (r, mut w) = tokio::io::split(connect(&ctx, 256));
+        tokio::spawn(drain(r));
+        let session = ctx.run();
+
+        // Far more than fits into the connection, while the handler is stuck with the first line
+        let lines = b":fritschy!~fritschy@localhost PRIVMSG #zebot-test :moep\r\n".repeat(100);
+        timeout(Duration::from_secs(5), w.write_all(&lines)).await.expect("Reading stopped").unwrap();
+        assert_eq!(seen.load(Ordering::SeqCst), 0);
+
+        for _ in 0..100 {
+            release.send(()).unwrap();
+        }
+        w.shutdown().await.unwrap();
+
+        // What was read before the server closed the connection is still handled
+        let e = timeout(Duration::from_secs(5), session).await.unwrap().unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "Connection closed by server");
+        assert_eq!(seen.load(Ordering::SeqCst), 100);
+    }
+
+    // Panics while holding locks the dispatcher needs itself, counts the other messages
+    struct Poisoner(Arc<AtomicU32>);
+
+    impl MessageHandler for Poisoner {
+        fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            if msg.params[1] == "poison" {
+                let _channels = ctx.channels.write().unwrap();
+                let _isupport = ctx.isupport.write().unwrap();
+                panic!("with the locks held");
+            }
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(HandlerResult::Handled)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handler_panic_holding_lock() {
+        let seen = Arc::new(AtomicU32::new(0));
+        let mut ctx = context();
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Poisoner(seen.clone())));
+        let ctx = Arc::new(ctx);
+        let (r, mut w) = tokio::io::split(connect(&ctx, 1024));
+        tokio::spawn(drain(r));
+        let session = ctx.run();
+
+        w.write_all(b":fritschy!~fritschy@localhost PRIVMSG #zebot-test :poison\r\n").await.unwrap();
+        w.write_all(b":fritschy!~fritschy@localhost PRIVMSG #zebot-test :moep\r\n").await.unwrap();
+        while seen.load(Ordering::SeqCst) == 0 {
+            assert!(!session.is_finished());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(ctx.channels.is_poisoned() && ctx.isupport.is_poisoned());
+
+        ctx.join("#zebot-test", None);
+        assert_eq!(ctx.casemapping().fold("#ZeBot-Test"), "#zebot-test");
+
+        w.shutdown().await.unwrap();
+        let e = session.await.unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "Connection closed by server");
+    }
+
+    // Remembers the connection events, the same handler has to see all of them
+    struct Events(Arc<Mutex<Vec<String>>>);
+
+    impl MessageHandler for Events {
+        fn handle(&self, _ctx: &Context, _msg: &Message) -> Result<HandlerResult, std::io::Error> {
+            Ok(HandlerResult::NotInterested)
+        }
+
+        fn connection_event(&self, _ctx: &Context, event: &ConnectionEvent) {
+            self.0.lock().unwrap().push(event.to_string());
+        }
+    }
+
+    // Connect to a fake server that lets us in, returns the JOINs we send it and its writing end
+    async fn welcome(ctx: &Arc<Context>) -> (JoinHandle<Result<(), std::io::Error>>, mpsc::UnboundedReceiver<String>, WriteHalf<DuplexStream>) {
+        let (r, mut w) = tokio::io::split(connect(ctx, 1024));
+        let (tx, joins) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut lines = tokio::io::BufReader::new(r).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.starts_with("JOIN ") {
+                    let _ = tx.send(line);
                 }
             }
+        });
+
+        let session = ctx.run();
+        w.write_all(b":fake 001 ZeBot :Welcome\r\n").await.unwrap();
+        (session, joins, w)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_keeps_state() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = context();
+        ctx.register_handler(CommandCode::PrivMsg, Box::new(Events(events.clone())));
+        let ctx = Arc::new(ctx);
+
+        let (session, mut joins, mut w) = welcome(&ctx).await;
+        // As with /join on stdin
+        ctx.join("#a", Some("sekrit"));
+        ctx.join("#b", None);
+        assert_eq!(joins.recv().await.unwrap(), "JOIN #a sekrit");
+        assert_eq!(joins.recv().await.unwrap(), "JOIN #b");
+        w.write_all(b":ZeBot!zebot@localhost JOIN #a\r\n").await.unwrap();
+        while read(&ctx.joined_channels).is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
 
-        Ok(())
+        w.shutdown().await.unwrap();
+        assert!(session.await.unwrap().is_err());
+
+        // The channel we were in with its key, and the one the server did not answer for yet
+        let (session, mut joins, mut w) = welcome(&ctx).await;
+        let mut rejoined = vec![joins.recv().await.unwrap(), joins.recv().await.unwrap()];
+        rejoined.sort();
+        assert_eq!(rejoined, ["JOIN #a sekrit", "JOIN #b"]);
+
+        ctx.quit();
+        w.shutdown().await.unwrap();
+        assert!(session.await.unwrap().is_ok());
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "Connected to fake",
+                "Disconnected from fake: Connection closed by server",
+                "Reconnected to fake",
+                "Disconnected from fake: Quit",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_timeout_ends_session() {
+        let ctx = Arc::new(context());
+        let (r, _w) = tokio::io::split(connect(&ctx, 1024));
+        tokio::spawn(drain(r));
+
+        let start = tokio::time::Instant::now();
+        let e = ctx.run().await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= READ_TIMEOUT);
     }
 }
@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+use irc2::Charsets;
+use tracing::{error as log_error, warn};
+
+/// Which lines go first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// What the server waits for, e.g. PONG or CAP. Goes ahead of everything else and does
+    /// not wait for the flood control.
+    Protocol,
+    /// Answers to users
+    Interactive,
+    /// Long output, e.g. a fortune box, goes out when nothing else is waiting
+    Bulk,
+}
+
+const PRIORITIES: usize = 3;
+
+// Long lines cost extra, like ircu's 2 s plus 1 s per 120 bytes of fake lag
+const BYTES_PER_HALF_TOKEN: usize = 120;
+
+/// Token bucket flood control for outgoing lines.
+///
+/// Up to `burst` lines can go out at once, after that one more every `refill`. A line costs
+/// one token, plus half a token for every 120 bytes, which follows the penalties most ircds
+/// give. The defaults, 5 lines and 2 seconds, keep well below what servers tolerate.
+#[derive(Debug, Clone)]
+pub struct FloodControl {
+    burst: f64,
+    refill: Duration,
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl Default for FloodControl {
+    fn default() -> Self {
+        FloodControl::new(5, Duration::from_secs(2))
+    }
+}
+
+impl FloodControl {
+    pub fn new(burst: u32, refill: Duration) -> Self {
+        let burst = burst.max(1) as f64;
+        FloodControl {
+            burst,
+            refill,
+            tokens: burst,
+            last: None,
+        }
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst as u32
+    }
+
+    pub fn refill(&self) -> Duration {
+        self.refill
+    }
+
+    /// What a line of `len` bytes costs, in lines
+    pub fn cost(len: usize) -> f64 {
+        1.0 + (len / BYTES_PER_HALF_TOKEN) as f64 / 2.0
+    }
+
+    fn fill(&mut self, now: Instant) {
+        if let Some(last) = self.last {
+            let earned = if self.refill.is_zero() {
+                self.burst
+            } else {
+                now.saturating_duration_since(last).as_secs_f64() / self.refill.as_secs_f64()
+            };
+            self.tokens = (self.tokens + earned).min(self.burst);
+        }
+        self.last = Some(now);
+    }
+
+    /// How long to wait before a line of `len` bytes may be sent
+    pub fn delay(&mut self, now: Instant, len: usize) -> Duration {
+        self.fill(now);
+        // A line that costs more than the whole bucket only waits for a full one
+        let missing = Self::cost(len).min(self.burst) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            self.refill.mul_f64(missing)
+        }
+    }
+
+    /// Account for a line that was sent. This may go below zero for lines that could not
+    /// wait, e.g. a PONG, later lines wait longer then.
+    pub fn sent(&mut self, now: Instant, len: usize) {
+        self.fill(now);
+        self.tokens -= Self::cost(len).min(self.burst);
+    }
+}
+
+struct Item {
+    line: String,
+    /// Casefolded channel or nick the line is for, if any
+    target: Option<String>,
+}
+
+#[derive(Default)]
+struct Queues {
+    queues: [VecDeque<Item>; PRIORITIES],
+    closed: bool,
+}
+
+/// Lines waiting to be written, shared between the `Context` and the writer task
+#[derive(Default)]
+pub(crate) struct Outbox {
+    queues: Mutex<Queues>,
+    notify: Notify,
+}
+
+impl Outbox {
+    pub fn push(&self, priority: Priority, target: Option<String>, line: String) {
+        self.queues.lock().unwrap().queues[priority as usize].push_back(Item { line, target });
+        self.notify.notify_one();
+    }
+
+    /// Drop the bulk output for a target that was not written yet, returns how many lines
+    pub fn cancel(&self, target: &str) -> usize {
+        let mut q = self.queues.lock().unwrap();
+        let bulk = &mut q.queues[Priority::Bulk as usize];
+        let n = bulk.len();
+        bulk.retain(|i| i.target.as_deref() != Some(target));
+        n - bulk.len()
+    }
+
+    /// Drop everything of a priority that was not written yet
+    pub fn clear(&self, priority: Priority) {
+        self.queues.lock().unwrap().queues[priority as usize].clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.lock().unwrap().queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// The writer stops once everything queued was written
+    pub fn close(&self) {
+        self.queues.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    /// Wait until everything queued was written, for at most `max`
+    pub async fn flush(&self, max: Duration) {
+        let wait = async {
+            while !self.is_empty() {
+                sleep(Duration::from_millis(50)).await;
+            }
+        };
+        if timeout(max, wait).await.is_err() {
+            warn!("Could not write all queued lines in {} seconds", max.as_secs());
+        }
+    }
+}
+
+enum Next {
+    Line(String, bool),
+    Wait(Duration),
+    Idle,
+    Done,
+}
+
+impl Outbox {
+    fn next(&self, flood: &mut FloodControl) -> Next {
+        let mut q = self.queues.lock().unwrap();
+        if let Some(item) = q.queues[Priority::Protocol as usize].pop_front() {
+            return Next::Line(item.line, true);
+        }
+
+        let closed = q.closed;
+        match q.queues.iter_mut().find(|q| !q.is_empty()) {
+            Some(queue) => match flood.delay(Instant::now(), queue[0].line.len()) {
+                d if d.is_zero() => Next::Line(queue.pop_front().unwrap().line, false),
+                d => Next::Wait(d),
+            },
+            None if closed => Next::Done,
+            None => Next::Idle,
+        }
+    }
+}
+
+/// Writes queued lines, as fast as the flood control allows. This runs as its own task, so
+/// reading never waits for writing.
+pub(crate) async fn write_loop<W: AsyncWrite + Unpin>(
+    outbox: Arc<Outbox>,
+    mut w: W,
+    mut flood: FloodControl,
+    charsets: Charsets,
+) -> Result<(), std::io::Error> {
+    loop {
+        match outbox.next(&mut flood) {
+            Next::Line(line, urgent) => {
+                flood.sent(Instant::now(), line.len());
+                if let Err(e) = w.write_all(&charsets.encode(&line)).await {
+                    log_error!("Could not write {}: {}", line.trim_end(), e);
+                    return Err(e);
+                }
+                if urgent || outbox.is_empty() {
+                    w.flush().await?;
+                }
+            }
+            // Lines of a higher priority do not wait for the ones waiting now
+            Next::Wait(d) => {
+                let _ = timeout(d, outbox.notify.notified()).await;
+            }
+            Next::Idle => outbox.notify.notified().await,
+            Next::Done => return w.flush().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncBufReadExt;
+
+    fn privmsg(outbox: &Outbox, priority: Priority, target: &str, text: &str) {
+        outbox.push(priority, Some(target.to_string()), format!("PRIVMSG {} :{}\r\n", target, text));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn protocol_first() {
+        let outbox = Arc::new(Outbox::default());
+        let (w, r) = tokio::io::duplex(4096);
+        let writer = tokio::spawn(write_loop(outbox.clone(), w, FloodControl::new(2, Duration::from_secs(2)), Charsets::new()));
+
+        for i in 0..5 {
+            privmsg(&outbox, Priority::Interactive, "#zebot-test", &i.to_string());
+        }
+        let mut lines = tokio::io::BufReader::new(r).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PRIVMSG #zebot-test :0");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PRIVMSG #zebot-test :1");
+
+        // Waits for the flood control, but not for the PONG
+        let start = Instant::now();
+        outbox.push(Priority::Protocol, None, "PONG :irc.example.com\r\n".to_string());
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PONG :irc.example.com");
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PRIVMSG #zebot-test :2");
+        assert!(start.elapsed() >= Duration::from_secs(4));
+
+        outbox.close();
+        for i in 3..5 {
+            assert_eq!(lines.next_line().await.unwrap().unwrap(), format!("PRIVMSG #zebot-test :{}", i));
+        }
+        writer.await.unwrap().unwrap();
+        assert!(lines.next_line().await.unwrap().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bulk_last() {
+        let outbox = Arc::new(Outbox::default());
+        for i in 0..3 {
+            privmsg(&outbox, Priority::Bulk, "#a", &format!("fortune {}", i));
+            privmsg(&outbox, Priority::Bulk, "#b", &format!("fortune {}", i));
+        }
+        privmsg(&outbox, Priority::Interactive, "#b", "hello");
+        assert_eq!(outbox.cancel("#a"), 3);
+        assert_eq!(outbox.cancel("#a"), 0);
+        outbox.close();
+
+        let (w, r) = tokio::io::duplex(4096);
+        write_loop(outbox.clone(), w, FloodControl::new(1, Duration::from_secs(1)), Charsets::new()).await.unwrap();
+
+        let mut lines = tokio::io::BufReader::new(r).lines();
+        let mut written = Vec::new();
+        while let Some(l) = lines.next_line().await.unwrap() {
+            written.push(l);
+        }
+        assert_eq!(written, ["PRIVMSG #b :hello", "PRIVMSG #b :fortune 0", "PRIVMSG #b :fortune 1", "PRIVMSG #b :fortune 2"]);
+    }
+
+    #[test]
+    fn burst_and_refill() {
+        let mut f = FloodControl::new(3, Duration::from_secs(2));
+        let t = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(f.delay(t, 50), Duration::ZERO);
+            f.sent(t, 50);
+        }
+        assert_eq!(f.delay(t, 50), Duration::from_secs(2));
+        assert_eq!(f.delay(t + Duration::from_secs(1), 50), Duration::from_secs(1));
+        assert_eq!(f.delay(t + Duration::from_secs(2), 50), Duration::ZERO);
+
+        // Refilling stops at the burst size
+        let later = t + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(f.delay(later, 50), Duration::ZERO);
+            f.sent(later, 50);
+        }
+        assert!(f.delay(later, 50) > Duration::ZERO);
+    }
+
+    #[test]
+    fn penalties() {
+        assert_eq!(FloodControl::cost(0), 1.0);
+        assert_eq!(FloodControl::cost(119), 1.0);
+        assert_eq!(FloodControl::cost(240), 2.0);
+
+        let mut f = FloodControl::new(2, Duration::from_secs(2));
+        let t = Instant::now();
+        f.sent(t, 360);
+        assert_eq!(f.delay(t, 10), Duration::from_secs(2));
+
+        // Overdrawn by a line that could not wait
+        f.sent(t, 10);
+        assert_eq!(f.delay(t, 10), Duration::from_secs(4));
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+
+use rand::Rng;
+use tokio::time::Duration;
+
+/// The connection to a server came up or went down, see `MessageHandler::connection_event()`
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    /// Connected, but not registered yet. `reconnect` is false only for the first connection.
+    Connected { server: String, reconnect: bool },
+    /// The connection broke, or we quit
+    Disconnected { server: String, reason: String },
+}
+
+impl fmt::Display for ConnectionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionEvent::Connected { server, reconnect: false } => write!(f, "Connected to {}", server),
+            ConnectionEvent::Connected { server, reconnect: true } => write!(f, "Reconnected to {}", server),
+            ConnectionEvent::Disconnected { server, reason } => write!(f, "Disconnected from {}: {}", server, reason),
+        }
+    }
+}
+
+/// Exponential backoff between connection attempts.
+///
+/// The wait doubles with every failed attempt, from `min` up to `max`. Only a random part
+/// between half and all of it is waited, so bots that lost their server together do not all
+/// come back at the same moment.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max: max.max(min),
+            failures: 0,
+        }
+    }
+
+    /// How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let full = self.min.saturating_mul(1 << self.failures.min(16)).min(self.max);
+        self.failures += 1;
+        full / 2 + full.mul_f64(rand::thread_rng().gen_range(0.0..=0.5))
+    }
+
+    /// Start over with `min`, e.g. once we got registered
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let mut b = Backoff::new(Duration::from_secs(2), Duration::from_secs(60));
+        for full in [2, 4, 8, 16, 32, 60, 60] {
+            let d = b.next_delay();
+            assert!(d >= Duration::from_secs(full) / 2 && d <= Duration::from_secs(full), "{:?} for {}", d, full);
+        }
+
+        b.reset();
+        assert!(b.next_delay() <= Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,37 @@
+use irc2::Sasl;
+
+/// SASL mechanism to log in with during capability negotiation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SaslMechanism {
+    /// Account and password from the password file
+    Plain,
+    /// The TLS client certificate
+    External,
+}
+
+/// What to do if SASL authentication fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SaslFailure {
+    /// Quit, rather than being on the network without being logged in
+    Abort,
+    /// Register anyway and identify with NickServ
+    Fallback,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SaslConfig {
+    pub mechanism: SaslMechanism,
+    pub on_failure: SaslFailure,
+}
+
+/// Where SASL authentication stands on this connection
+#[derive(Debug)]
+pub(crate) enum SaslState {
+    /// Not configured, identify with NickServ
+    Disabled,
+    /// Waiting for capability negotiation to settle
+    Pending(Sasl),
+    InProgress(Sasl),
+    Succeeded,
+    Failed,
+}
@@ -0,0 +1,294 @@
+use std::convert::TryFrom;
+use std::io::{BufReader, Error};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use tracing::{info, warn};
+
+/// How to verify the server's certificate
+#[derive(Debug, Clone)]
+pub enum Verify {
+    /// Against the system's root certificates
+    System,
+    /// Against the CAs in a PEM file, e.g. for a self-signed certificate
+    CaFile(PathBuf),
+    /// Not at all, only meant for testing
+    Insecure,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub verify: Verify,
+    /// Client certificate and key as PEM, for CertFP. The key may be in the certificate file.
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            verify: Verify::System,
+            client_cert: None,
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut r = BufReader::new(std::fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut r).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::other(format!("No certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &PathBuf) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut r = BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut r)?.ok_or_else(|| Error::other(format!("No private key in {}", path.display())))
+}
+
+fn system_roots() -> Result<RootCertStore, Error> {
+    let native = rustls_native_certs::load_native_certs();
+    for e in native.errors {
+        warn!("Could not load system certificate: {}", e);
+    }
+
+    let mut roots = RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(native.certs);
+    if ignored > 0 {
+        warn!("Ignored {} unparsable system certificates", ignored);
+    }
+    if added == 0 {
+        return Err(Error::other("No system root certificates found"));
+    }
+    Ok(roots)
+}
+
+fn ca_file_roots(path: &PathBuf) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(cert).map_err(Error::other)?;
+    }
+    Ok(roots)
+}
+
+/// Accepts any certificate, but still checks that the server has the key for it
+#[derive(Debug)]
+struct InsecureVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl TlsConfig {
+    pub fn connector(&self) -> Result<TlsConnector, Error> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(Error::other)?;
+
+        let builder = match &self.verify {
+            Verify::System => builder.with_root_certificates(system_roots()?),
+            Verify::CaFile(path) => builder.with_root_certificates(ca_file_roots(path)?),
+            Verify::Insecure => {
+                warn!("Not verifying the server's TLS certificate");
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(InsecureVerifier(provider())))
+            }
+        };
+
+        let config = match &self.client_cert {
+            Some((cert, key)) => {
+                info!("Using client certificate {}", cert.display());
+                builder
+                    .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+                    .map_err(Error::other)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+
+    pub async fn connect(&self, host: &str, tcp: TcpStream) -> Result<TlsStream<TcpStream>, Error> {
+        let name = ServerName::try_from(host.to_string()).map_err(Error::other)?;
+        self.connector()?.connect(name, tcp).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    fn self_signed(name: &str) -> CertifiedKey {
+        generate_simple_self_signed(vec![name.to_string()]).unwrap()
+    }
+
+    fn write_pem(name: &str, pem: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("zebot-tls-test-{}-{}.pem", std::process::id(), name));
+        std::fs::write(&path, pem).unwrap();
+        path
+    }
+
+    /// A TLS-terminating stand-in for an ircd, answers the first line with a PONG.
+    /// Returns the port and whether the client presented a certificate.
+    async fn stand_in(
+        server: &CertifiedKey,
+        client_ca: Option<&CertifiedKey>,
+    ) -> (u16, tokio::task::JoinHandle<Result<bool, Error>>) {
+        let builder = ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions().unwrap();
+        let builder = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(ca.cert.der().clone()).unwrap();
+                builder.with_client_cert_verifier(
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider()).build().unwrap(),
+                )
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let key = PrivatePkcs8KeyDer::from(server.key_pair.serialize_der());
+        let config = builder.with_single_cert(vec![server.cert.der().clone()], key.into()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let task = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await?;
+            let tls = TlsAcceptor::from(Arc::new(config)).accept(tcp).await?;
+            let has_client_cert = tls.get_ref().1.peer_certificates().is_some();
+            let mut tls = tokio::io::BufReader::new(tls);
+            let mut line = String::new();
+            tls.read_line(&mut line).await?;
+            assert_eq!(line, "PING :moep\r\n");
+            tls.write_all(b"PONG :moep\r\n").await?;
+            tls.flush().await?;
+            Ok(has_client_cert)
+        });
+
+        (port, task)
+    }
+
+    async fn ping(config: &TlsConfig, host: &str, port: u16) -> Result<String, Error> {
+        let tcp = TcpStream::connect(("127.0.0.1", port)).await?;
+        let tls = config.connect(host, tcp).await?;
+        let mut tls = tokio::io::BufReader::new(tls);
+        tls.write_all(b"PING :moep\r\n").await?;
+        tls.flush().await?;
+        let mut line = String::new();
+        tls.read_line(&mut line).await?;
+        Ok(line)
+    }
+
+    #[tokio::test]
+    async fn ca_file() {
+        let server = self_signed("localhost");
+        let (port, task) = stand_in(&server, None).await;
+
+        let config = TlsConfig {
+            verify: Verify::CaFile(write_pem("ca", &server.cert.pem())),
+            client_cert: None,
+        };
+        assert_eq!(ping(&config, "localhost", port).await.unwrap(), "PONG :moep\r\n");
+        assert!(!task.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn wrong_ca_and_name() {
+        let server = self_signed("localhost");
+        let other = self_signed("localhost");
+
+        let (port, _task) = stand_in(&server, None).await;
+        let config = TlsConfig {
+            verify: Verify::CaFile(write_pem("other-ca", &other.cert.pem())),
+            client_cert: None,
+        };
+        assert!(ping(&config, "localhost", port).await.is_err());
+
+        let (port, _task) = stand_in(&server, None).await;
+        let config = TlsConfig {
+            verify: Verify::CaFile(write_pem("name-ca", &server.cert.pem())),
+            client_cert: None,
+        };
+        assert!(ping(&config, "irc.example.com", port).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn insecure() {
+        let server = self_signed("localhost");
+        let (port, task) = stand_in(&server, None).await;
+
+        let config = TlsConfig {
+            verify: Verify::Insecure,
+            client_cert: None,
+        };
+        assert_eq!(ping(&config, "irc.example.com", port).await.unwrap(), "PONG :moep\r\n");
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_cert() {
+        let server = self_signed("localhost");
+        let client = self_signed("zebot");
+        let (port, task) = stand_in(&server, Some(&client)).await;
+
+        // Certificate and key in one file
+        let pem = write_pem("client", &format!("{}{}", client.cert.pem(), client.key_pair.serialize_pem()));
+        let config = TlsConfig {
+            verify: Verify::CaFile(write_pem("cert-ca", &server.cert.pem())),
+            client_cert: Some((pem.clone(), pem)),
+        };
+        assert_eq!(ping(&config, "localhost", port).await.unwrap(), "PONG :moep\r\n");
+        assert!(task.await.unwrap().unwrap());
+    }
+}
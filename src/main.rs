@@ -1,13 +1,12 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::io::{BufRead, BufReader, Write, Error};
-use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use async_trait::async_trait;
 use chrono::prelude::*;
 
-use futures_util::future::FutureExt;
 use json::JsonValue;
 use rand::{Rng, thread_rng};
 use rand::prelude::IteratorRandom;
@@ -26,9 +25,11 @@ mod callout;
 use crate::callout::Callouthandler;
 use tracing_subscriber::FmtSubscriber;
 use tracing::{error as log_error, Level};
-use irc2::{Message, Prefix};
-use futures::executor::block_on;
-use std::borrow::Borrow;
+use irc2::{format, Charsets, HostMask, Message, Nick, Prefix, Target};
+
+// Between connection attempts, growing from the first to the second
+const RECONNECT_MIN: Duration = Duration::from_secs(5);
+const RECONNECT_MAX: Duration = Duration::from_secs(5 * 60);
 
 pub fn zebot_version() -> String {
     // See build.rs
@@ -43,12 +44,28 @@ pub fn zebot_version() -> String {
 async fn async_main(args: &clap::ArgMatches<'_>) -> std::io::Result<()> {
     info!("This is ZeBot {}", zebot_version());
 
-    let addr = args
-        .value_of("server")
+    let tls = if args.is_present("tls") {
+        let verify = if args.is_present("tls-insecure") {
+            Verify::Insecure
+        } else if let Some(ca) = args.value_of("tls-ca-file") {
+            Verify::CaFile(ca.into())
+        } else {
+            Verify::System
+        };
+        let client_cert = args.value_of("tls-cert").map(|cert| {
+            // The key may be in the certificate file
+            (cert.into(), args.value_of("tls-key").unwrap_or(cert).into())
+        });
+        Some(TlsConfig { verify, client_cert })
+    } else {
+        None
+    };
+
+    let servers = args
+        .values_of("server")
         .unwrap()
-        .to_socket_addrs()?
-        .next()
-        .expect("Could not resolve host address");
+        .map(|addr| Server::new(addr, tls.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
 
     let mut stdin = tokio::io::stdin();
     let mut stdin_buf = vec![0u8; 1024];
@@ -56,37 +73,128 @@ async fn async_main(args: &clap::ArgMatches<'_>) -> std::io::Result<()> {
     let nick = args.value_of("nick").unwrap();
     let user = args.value_of("user").unwrap();
     let pass = args.value_of("pass-file").map(String::from);
-    let mut context = Context::connect(addr, User::new(nick, user), pass).await?;
+    let mut charsets = Charsets::new();
+    if let Some(label) = args.value_of("encoding") {
+        charsets.set_network(label).map_err(std::io::Error::other)?;
+    }
+    for x in args.values_of("channel-encoding").into_iter().flatten() {
+        match x.split_once('=') {
+            Some((chan, label)) => charsets.set_channel(chan, label).map_err(std::io::Error::other)?,
+            None => return Err(std::io::Error::other(format!("Expected CHANNEL=CHARSET, got '{}'", x))),
+        }
+    }
+
+    let sasl = args.value_of("sasl").map(|mechanism| SaslConfig {
+        mechanism: match mechanism {
+            "external" => SaslMechanism::External,
+            _ => SaslMechanism::Plain,
+        },
+        on_failure: match args.value_of("sasl-failure") {
+            Some("abort") => SaslFailure::Abort,
+            _ => SaslFailure::Fallback,
+        },
+    });
+
+    let mut user = User::new(nick, user);
+    user.alt_nicks = args.values_of("alt-nick").into_iter().flatten().map(String::from).collect();
+    user.regain = match args.value_of("regain") {
+        Some("regain") => NickRegain::Regain,
+        Some("off") => NickRegain::Off,
+        _ => NickRegain::Ghost,
+    };
+
+    let number = |name: &str| {
+        let v = args.value_of(name).unwrap();
+        v.parse::<u32>().map_err(|_| std::io::Error::other(format!("Invalid --{} '{}'", name, v)))
+    };
+    let flood = FloodControl::new(number("flood-burst")?, Duration::from_millis(number("flood-refill")?.into()));
+
+    let mut context = Context::new(user, pass, sasl, charsets, flood);
+
+    for mask in args.values_of("ignore").into_iter().flatten() {
+        context.ignore(HostMask::new(mask));
+    }
+
+    if args.is_present("wait-for-login") {
+        context.wait_for_login();
+    }
 
-    for i in args.value_of("channel").unwrap().split(|x| x == ',') {
-        context.join(i).await;
+    if args.is_present("disable-failing-handlers") {
+        context.disable_failing_handlers(number("disable-failing-handlers")?);
+    }
+
+    let mut keys = HashMap::new();
+    for x in args.values_of("channel-key").into_iter().flatten() {
+        match x.split_once('=') {
+            Some((chan, key)) => keys.insert(chan.to_string(), key.to_string()),
+            None => return Err(std::io::Error::other(format!("Expected CHANNEL=KEY, got '{}'", x))),
+        };
+    }
+
+    for i in args.value_of("channel").unwrap().split(',') {
+        context.join(i, keys.get(i).map(String::as_str));
     }
 
     let current_channel = args
         .value_of("channel")
         .unwrap()
-        .split(|x| x == ',')
+        .split(',')
         .next()
         .unwrap();
 
-    context.register_handler(CommandCode::PrivMsg, Box::new(YoutubeTitleHandler));
-    context.register_handler(CommandCode::PrivMsg, Box::new(Callouthandler));
+    context.register_async_handler(CommandCode::PrivMsg, Box::new(YoutubeTitleHandler));
+    context.register_async_handler(CommandCode::PrivMsg, Box::new(Callouthandler));
     context.register_handler(CommandCode::Join, Box::new(GreetHandler));
     context.register_handler(CommandCode::PrivMsg, Box::new(ZeBotAnswerHandler::new()));
     context.register_handler(CommandCode::PrivMsg, Box::new(MiscCommandsHandler));
     context.register_handler(CommandCode::PrivMsg, Box::new(SubstituteLastHandler::new()));
     context.register_handler(CommandCode::PrivMsg, Box::new(URLCollector::new()));
 
-    context.logon();
+    // Handlers and channels stay the same, whichever server we are connected to
+    let context = Arc::new(context);
+    let mut backoff = Backoff::new(RECONNECT_MIN, RECONNECT_MAX);
+    let mut servers = servers.iter().cycle();
+    let mut server = servers.next().unwrap();
+
+    loop {
+        let registered = match context.connect(server).await {
+            Ok(()) => {
+                if session(&context, &mut stdin, &mut stdin_buf, current_channel).await {
+                    return Ok(());
+                }
+                context.is_registered()
+            }
+            Err(e) => {
+                log_error!("Can not connect to {}: {}", server, e);
+                false
+            }
+        };
+
+        // A server that let us in gets another try, otherwise the next one does
+        if registered {
+            backoff.reset();
+        } else {
+            server = servers.next().unwrap();
+        }
+
+        let delay = backoff.next_delay();
+        info!("Connecting to {} in {:.1} seconds", server, delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Run a connection, reading commands from stdin meanwhile. Returns whether to quit, i.e.
+/// stdin was closed or the connection was given up on, e.g. SASL failed.
+async fn session(context: &Arc<Context>, stdin: &mut tokio::io::Stdin, stdin_buf: &mut [u8], current_channel: &str) -> bool {
+    let mut connection = context.run();
 
-    while !context.is_shutdown() {
-        // Read from server and stdin simultaneously
+    loop {
+        // The connection runs on its own tasks, we only wait for stdin here
         let stdin_read = async {
-            let bytes = stdin.read(stdin_buf.as_mut_slice()).await?;
+            let bytes = stdin.read(stdin_buf).await?;
 
             if bytes == 0 {
                 // EOF?
-                context.quit();
                 return Err::<(), std::io::Error>(std::io::ErrorKind::BrokenPipe.into());
             }
 
@@ -110,10 +218,10 @@ async fn async_main(args: &clap::ArgMatches<'_>) -> std::io::Result<()> {
                     }
 
                     "join" => {
-                        if args.len() != 1 {
-                            log_error!("Error: /JOIN CHANNEL");
+                        if args.is_empty() || args.len() > 2 {
+                            log_error!("Error: /JOIN CHANNEL [KEY]");
                         } else {
-                            context.join(args[0]).await;
+                            context.join(args[0], args.get(1).copied());
                         }
                     }
 
@@ -121,7 +229,7 @@ async fn async_main(args: &clap::ArgMatches<'_>) -> std::io::Result<()> {
                         if args.len() != 1 {
                             log_error!("Error: /PART CHANNEL");
                         } else {
-                            context.leave(args[0]).await;
+                            context.leave(args[0]);
                         }
                     }
 
@@ -134,38 +242,21 @@ async fn async_main(args: &clap::ArgMatches<'_>) -> std::io::Result<()> {
             }
 
             Ok(())
-        }
-            .fuse();
-
-        let irc_read = context.update().fuse();
-
-        tokio::pin!(irc_read, stdin_read);
+        };
 
         tokio::select! {
-            r = irc_read => {
-                if let Err(e) = r {
-                    return Err(e);
-                }
-            }
+            r = &mut connection => return matches!(r, Ok(Ok(()))),
 
             r = stdin_read => {
                 if let Err(e) = r {
-                    return Err(e);
+                    info!("Quitting, can not read stdin: {}", e);
+                    context.quit();
+                    let _ = connection.await;
+                    return true;
                 }
             }
-
-            else => {
-                log_error!("Error ...");
-                return Err(std::io::ErrorKind::Other.into());
-            }
         }
-        ;
-
-        // context.update().or(b).await?;
     }
-
-    // One last update to send pending messages...
-    context.update().await
 }
 
 #[tokio::main]
@@ -186,9 +277,46 @@ async fn main() -> std::io::Result<()> {
         .about("An IRC Bot")
         .arg(
             clap::Arg::with_name("server")
-                .default_value("localhost:6667")
+                .help("host[:port], the port defaults to 6667, or 6697 with --tls. With more than one, the next is tried when connecting fails.")
+                .default_value("localhost")
                 .short("s")
-                .long("server"),
+                .long("server")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("tls")
+                .help("Connect using TLS, verifying the server against the system's root certificates")
+                .short("t")
+                .long("tls"),
+        )
+        .arg(
+            clap::Arg::with_name("tls-ca-file")
+                .help("Verify the server against the CA certificates in this PEM file instead")
+                .long("tls-ca-file")
+                .takes_value(true)
+                .requires("tls"),
+        )
+        .arg(
+            clap::Arg::with_name("tls-insecure")
+                .help("Do not verify the server's certificate at all, for testing only")
+                .long("tls-insecure")
+                .conflicts_with("tls-ca-file")
+                .requires("tls"),
+        )
+        .arg(
+            clap::Arg::with_name("tls-cert")
+                .help("Client certificate (PEM) to present to the server, e.g. for CertFP")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires("tls"),
+        )
+        .arg(
+            clap::Arg::with_name("tls-key")
+                .help("Private key (PEM) for --tls-cert, if it is not in the certificate file")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-cert"),
         )
         .arg(
             clap::Arg::with_name("nick")
@@ -196,6 +324,22 @@ async fn main() -> std::io::Result<()> {
                 .short("n")
                 .long("nick"),
         )
+        .arg(
+            clap::Arg::with_name("alt-nick")
+                .help("Nick to use if the nick is taken, tried in order before appending '_'")
+                .short("a")
+                .long("alt-nick")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("regain")
+                .help("How to get the nick back if it is taken: NickServ 'ghost' or 'regain', or 'off'")
+                .long("regain")
+                .takes_value(true)
+                .possible_values(&["ghost", "regain", "off"])
+                .default_value("ghost"),
+        )
         .arg(
             clap::Arg::with_name("user")
                 .default_value("The Bot")
@@ -203,28 +347,94 @@ async fn main() -> std::io::Result<()> {
                 .long("user"),
         )
         .arg(clap::Arg::with_name("pass-file")
+            .help("Password for NickServ or SASL PLAIN, either 'password' or 'account password'")
             .default_value("password.txt")
             .short("p")
             .long("pass"))
+        .arg(
+            clap::Arg::with_name("sasl")
+                .help("Log in with SASL: 'plain' with the password file, 'external' with --tls-cert")
+                .long("sasl")
+                .takes_value(true)
+                .possible_values(&["plain", "external"]),
+        )
+        .arg(
+            clap::Arg::with_name("sasl-failure")
+                .help("If SASL fails, 'abort' or 'fallback' to identifying with NickServ")
+                .long("sasl-failure")
+                .takes_value(true)
+                .possible_values(&["abort", "fallback"])
+                .default_value("fallback"),
+        )
         .arg(
             clap::Arg::with_name("channel")
                 .default_value("#zebot-test")
                 .short("c")
                 .long("channel"),
         )
+        .arg(
+            clap::Arg::with_name("channel-key")
+                .help("Key for a channel with mode +k, e.g. '#zebot-test=secret'")
+                .short("k")
+                .long("channel-key")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("wait-for-login")
+                .help("Join channels only after logging in with SASL or NickServ, e.g. for +r channels")
+                .short("w")
+                .long("wait-for-login"),
+        )
+        .arg(
+            clap::Arg::with_name("ignore")
+                .help("Ignore users matching this hostmask, e.g. '*!*@*.example.org'")
+                .short("i")
+                .long("ignore")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("flood-burst")
+                .help("Lines to send at once before the flood control kicks in")
+                .long("flood-burst")
+                .default_value("5"),
+        )
+        .arg(
+            clap::Arg::with_name("flood-refill")
+                .help("Milliseconds until the flood control allows one more line")
+                .long("flood-refill")
+                .default_value("2000"),
+        )
+        .arg(
+            clap::Arg::with_name("disable-failing-handlers")
+                .help("Disable a handler after it failed this many times in a row")
+                .long("disable-failing-handlers")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("encoding")
+                .help("Charset to fall back to for text that is not UTF-8, e.g. 'latin1'")
+                .short("e")
+                .long("encoding")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("channel-encoding")
+                .help("Charset to use for a channel, e.g. '#zebot-test=latin1', overrides --encoding")
+                .long("channel-encoding")
+                .multiple(true)
+                .number_of_values(1),
+        )
         .get_matches();
 
-    loop {
-        if let Err(x) = async_main(&m).await {
-            log_error!("Encountered an error, will retry...: {:?}", x);
-        } else {
-            log_error!("Exiting as requested, cya.");
-            break;
-        }
-
-        tokio::time::sleep(Duration::from_secs(5)).await;
+    // Connection problems are dealt with in there, this is only for bad arguments
+    if let Err(x) = async_main(&m).await {
+        log_error!("Encountered an error, giving up: {:?}", x);
+        return Err(x);
     }
 
+    log_error!("Exiting as requested, cya.");
     Ok(())
 }
 
@@ -232,9 +442,8 @@ fn nag_user(nick: &str) -> String {
     fn doit(nick: &str) -> Result<String, std::io::Error> {
         let nick = nick.replace(|x: char| !x.is_alphanumeric(), "_");
         let nag_file = format!("nag-{}.txt", nick);
-        let f = std::fs::File::open(&nag_file).map_err(|e| {
+        let f = std::fs::File::open(&nag_file).inspect_err(|_| {
             log_error!("Could not open nag-file '{}'", &nag_file);
-            e
         })?;
         let br = BufReader::new(f);
         let l = br.lines();
@@ -283,29 +492,32 @@ fn is_json_flag_set(jv: &JsonValue) -> bool {
 
 struct YoutubeTitleHandler;
 
-impl MessageHandler for YoutubeTitleHandler {
-    fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, Error> {
+#[async_trait]
+impl AsyncMessageHandler for YoutubeTitleHandler {
+    async fn handle(&self, ctx: &Context, msg: &Message) -> Result<HandlerResult, Error> {
         if msg.params.len() > 1 {
             let yt_re = regex::Regex::new(r"https?://((www.)?youtube\.com/watch|youtu.be/)").unwrap();
-            for url in msg.params[1]
+            for url in format::strip(&msg.params[1])
                 .split_ascii_whitespace()
                 .filter(|x| x.starts_with("https://") || x.starts_with("http://")) {
                 if yt_re.is_match(url) {
-                    if let Ok(output) = std::process::Command::new("python3")
+                    if let Ok(output) = tokio::process::Command::new("python3")
                         .current_dir("youtube-dl")
-                        .args(&[
+                        .args([
                             "-m", "youtube_dl", "--quiet", "--get-title", "--socket-timeout", "5", url,
                         ])
-                        .output() {
+                        .kill_on_drop(true)
+                        .output()
+                        .await {
                         let err = String::from_utf8_lossy(output.stderr.as_ref());
                         if !err.is_empty() {
                             log_error!("Got error from youtube-dl: {}", err);
-                            let dst = msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }));
+                            let dst = ctx.response_destination(msg);
                             ctx.message(&dst, &format!("Got an error for URL {}, is this a valid video URL?", &url));
                         } else {
                             let title = String::from_utf8_lossy(output.stdout.as_ref());
                             if !title.is_empty() {
-                                let dst = msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }));
+                                let dst = ctx.response_destination(msg);
                                 ctx.message(&dst, &format!("{} has title '{}'", &url, title.trim()));
                             }
                         }
@@ -332,6 +544,14 @@ impl MessageHandler for YoutubeTitleHandler {
 
         Ok(HandlerResult::NotInterested)
     }
+
+    fn name(&self) -> &str {
+        "youtube titles"
+    }
+
+    fn priority(&self) -> i32 {
+        10
+    }
 }
 
 struct URLCollector {
@@ -349,7 +569,6 @@ impl URLCollector {
         let mut f = std::fs::OpenOptions::new()
             .truncate(false)
             .create(true)
-            .write(true)
             .read(false)
             .append(true)
             .open(&self.filename)?;
@@ -366,16 +585,19 @@ impl MessageHandler for URLCollector {
         ctx: &Context,
         msg: &Message,
     ) -> Result<HandlerResult, std::io::Error> {
-        let text = &msg.params[1];
+        // Formatting codes would otherwise end up in the URLs
+        let text = format::strip(&msg.params[1]);
 
         for word in text.split_ascii_whitespace() {
             if let Ok(url) = Url::parse(word) {
                 match url.scheme() {
                     "http" | "https" | "ftp" => {
                         let nick = msg.get_nick();
-                        let chan = msg.get_reponse_destination(&block_on( async { ctx.joined_channels.read().await }));
+                        let chan = ctx.response_destination(msg);
                         log_error!("Got an url from {} {}: {}", &chan, &nick, url.as_ref());
-                        self.add_url(&nick, &chan, url.as_ref())?;
+                        if let Err(e) = self.add_url(&nick, &chan, url.as_ref()) {
+                            return Ok(HandlerResult::Error(format!("Could not save the URL to {}: {}", self.filename, e)));
+                        }
                     }
                     _ => (),
                 }
@@ -384,16 +606,30 @@ impl MessageHandler for URLCollector {
 
         Ok(HandlerResult::NotInterested)
     }
+
+    // Collects every URL, answered or not
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+
+    fn name(&self) -> &str {
+        "url collector"
+    }
+
+    // Before any command could fail
+    fn priority(&self) -> i32 {
+        20
+    }
 }
 
 struct SubstituteLastHandler {
-    last_msg: RefCell<HashMap<(String, String), String>>,
+    last_msg: Mutex<HashMap<(Target, Nick), String>>,
 }
 
 impl SubstituteLastHandler {
     fn new() -> Self {
         SubstituteLastHandler {
-            last_msg: RefCell::new(HashMap::new()),
+            last_msg: Mutex::new(HashMap::new()),
         }
     }
 }
@@ -463,16 +699,18 @@ impl MessageHandler for SubstituteLastHandler {
         msg: &Message,
     ) -> Result<HandlerResult, std::io::Error> {
         let nick = msg.get_nick();
-        let dst = msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }));
+        let dst = ctx.response_destination(msg);
+        let key = (ctx.target(&dst), Nick::new(&nick, ctx.casemapping()));
 
         if !msg.params[1].starts_with("!s") && !msg.params[1].starts_with("!S") {
-            if msg.params[1].starts_with("\x01ACTION") {
+            if msg.is_action() {
                 log_error!("Ignoring ACTION message");
                 return Ok(HandlerResult::NotInterested);
             }
             self.last_msg
-                .borrow_mut()
-                .insert((dst, nick), msg.params[1].clone());
+                .lock()
+                .unwrap()
+                .insert(key, format::strip(&msg.params[1]));
             return Ok(HandlerResult::NotInterested);
         }
 
@@ -494,7 +732,7 @@ impl MessageHandler for SubstituteLastHandler {
 
         match regex::Regex::new(&pat) {
             Ok(re) => {
-                if let Some(last) = self.last_msg.borrow().get(&(dst.clone(), nick.clone())) {
+                if let Some(last) = self.last_msg.lock().unwrap().get(&key) {
                     let new_msg = if flags.contains('g') {
                         re.replace_all(last, subst.as_str())
                     } else if let Ok(n) = flags.parse::<usize>() {
@@ -528,16 +766,30 @@ impl MessageHandler for SubstituteLastHandler {
 
         Ok(HandlerResult::Handled)
     }
+
+    // Needs every message to substitute in
+    fn role(&self) -> Role {
+        Role::Observe
+    }
+
+    fn name(&self) -> &str {
+        "substitute"
+    }
+
+    // Remembers the last message before commands see it
+    fn priority(&self) -> i32 {
+        10
+    }
 }
 
 struct ZeBotAnswerHandler {
-    last: RefCell<HashMap<Prefix, Instant>>,
+    last: Mutex<HashMap<Prefix, Instant>>,
 }
 
 impl ZeBotAnswerHandler {
     fn new() -> Self {
         Self {
-            last: RefCell::new(HashMap::new()),
+            last: Mutex::new(HashMap::new()),
         }
     }
 }
@@ -548,9 +800,11 @@ impl MessageHandler for ZeBotAnswerHandler {
         ctx: &Context,
         msg: &Message,
     ) -> Result<HandlerResult, std::io::Error> {
-        if msg.params.len() > 1 && msg.params[1..].iter().any(|x| x.contains(ctx.nick())) {
+        let cm = ctx.casemapping();
+        let nick = cm.fold(&ctx.nick());
+        if msg.params.len() > 1 && msg.params[1..].iter().any(|x| cm.fold(x).contains(&nick)) {
             let now = Instant::now();
-            let mut last = self.last.borrow_mut();
+            let mut last = self.last.lock().unwrap();
             let pfx = msg.prefix.as_ref().unwrap();
             if last.contains_key(pfx) {
                 let last_ts = *last.get(pfx).unwrap();
@@ -569,13 +823,22 @@ impl MessageHandler for ZeBotAnswerHandler {
                 format!("Hey {}", &msg.get_nick())
             };
 
-            let dst = msg.get_reponse_destination(&block_on(async {ctx.joined_channels.read().await}));
+            let dst = ctx.response_destination(msg);
             ctx.message(&dst, &m);
         }
 
         // Pretend we're not interested
         Ok(HandlerResult::NotInterested)
     }
+
+    fn name(&self) -> &str {
+        "answer"
+    }
+
+    // Only when nobody answered a command
+    fn priority(&self) -> i32 {
+        -10
+    }
 }
 
 struct MiscCommandsHandler;
@@ -596,15 +859,15 @@ fn handle(
             .unwrap_or_else(|| msg.params[1].as_ref())
         {
             "!version" | "!ver" => {
-                let dst = msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }));
+                let dst = ctx.response_destination(msg);
                 ctx.message(&dst, &format!("I am version {}, let's not talk about it!", zebot_version()));
             }
             "!help" | "!commands" => {
-                let dst = msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }));
-                ctx.message(&dst, "I am ZeBot, I can say Hello and answer to !fortune, !bash, !echo and !errno <int>");
+                let dst = ctx.response_destination(msg);
+                ctx.message(&dst, "I am ZeBot, I can say Hello and answer to !fortune, !bash, !echo and !errno <int>, !stop ends long output and running commands");
             }
             "!echo" => {
-                let dst = msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }));
+                let dst = ctx.response_destination(msg);
                 let m = &msg.params[1];
                 if m.len() > 6 {
                     let m = &m[6..];
@@ -613,10 +876,17 @@ fn handle(
                     }
                 }
             }
+            "!stop" | "!hush" => {
+                let dst = ctx.response_destination(msg);
+                let n = ctx.cancel_bulk(&dst);
+                if n > 0 {
+                    info!("Dropped {} lines of output for {}", n, dst);
+                }
+            }
             "!exec" | "!sh" | "!shell" | "!powershell" | "!power-shell" => {
                 let m = format!("Na aber wer wird denn gleich, {}", msg.get_nick());
                 ctx.message(
-                    msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await }))
+                    ctx.response_destination(msg)
                         .as_str(),
                     &m,
                 );
@@ -626,6 +896,15 @@ fn handle(
 
         Ok(HandlerResult::Handled)
     }
+
+    fn name(&self) -> &str {
+        "commands"
+    }
+
+    // The built-in commands win over scripts with the same name
+    fn priority(&self) -> i32 {
+        5
+    }
 }
 
 struct GreetHandler;
@@ -657,9 +936,9 @@ impl MessageHandler for GreetHandler {
         ctx: &Context,
         msg: &Message,
     ) -> Result<HandlerResult, std::io::Error> {
-        if *ctx.nick() != msg.get_nick() {
+        if !ctx.casemapping().eq(&ctx.nick(), &msg.get_nick()) {
             if let CommandCode::Join = msg.command {
-                ctx.message(&msg.get_reponse_destination(&block_on(async { ctx.joined_channels.read().await })),
+                ctx.message(&ctx.response_destination(msg),
                             &greet(&msg.get_nick()),
                 );
             }
@@ -667,4 +946,12 @@ impl MessageHandler for GreetHandler {
 
         Ok(HandlerResult::NotInterested)
     }
+
+    fn name(&self) -> &str {
+        "greet"
+    }
+
+    fn priority(&self) -> i32 {
+        0
+    }
 }